@@ -1,17 +1,25 @@
 use std::fs::File;
 use std::io;
-use std::io::{Error, ErrorKind};
+use std::io::{BufWriter, Error, ErrorKind};
 use std::io::prelude::*;
+use std::path::Path;
 use std::thread;
 use std::time;
 
 use clap::{Arg, App};
 
+use chip8::config::Config;
 use chip8::core::cpu::Cpu;
+use chip8::core::disasm;
+use chip8::core::pacer::Pacer;
+use chip8::core::quirks::Quirks;
+use chip8::debugger;
 use chip8::sdl::controller::Controller as UIController;
+#[cfg(feature = "watch")]
+use chip8::watch::RomWatcher;
 
 fn main() -> io::Result<()> {
-    let matches = App::new("Chip-8 Emulator")
+    let app = App::new("Chip-8 Emulator")
         .version("0.1")
         .author("Shawn M. Chapla <shwnchpl@gmail.com>")
         .about("A Rust/SDL2 CHIP-8 emulator.")
@@ -19,7 +27,76 @@ fn main() -> io::Result<()> {
              .help("Chip-8 ROM file to load.")
              .required(true)
              .index(1))
-        .get_matches();
+        .arg(Arg::with_name("config")
+             .long("config")
+             .value_name("FILE")
+             .help("Configuration file to load.")
+             .takes_value(true))
+        .arg(Arg::with_name("clock")
+             .long("clock")
+             .value_name("HZ")
+             .help("Instructions executed per second.")
+             .takes_value(true))
+        .arg(Arg::with_name("debug")
+             .long("debug")
+             .help("Step through the ROM from a prompt on stdin instead of running it."))
+        .arg(Arg::with_name("disasm")
+             .long("disasm")
+             .value_name("OUT")
+             .help("Write a disassembly of the ROM to OUT, or - for stdout, and exit.")
+             .takes_value(true))
+        .arg(Arg::with_name("dump-state-on-crash")
+             .long("dump-state-on-crash")
+             .value_name("FILE")
+             .help("On a fatal CPU error, write the registers, code around PC and RAM to FILE.")
+             .takes_value(true))
+        .arg(Arg::with_name("profile")
+             .long("profile")
+             .value_name("NAME")
+             .help("Variant and quirk preset to start from; the config file still applies.")
+             .possible_values(&Quirks::PROFILES)
+             .takes_value(true))
+        .arg(Arg::with_name("trace")
+             .long("trace")
+             .value_name("FILE")
+             .help("Write every instruction executed to FILE as JSON lines.")
+             .takes_value(true))
+        .arg(Arg::with_name("scale")
+             .long("scale")
+             .value_name("N")
+             .help("Window pixels per CHIP-8 pixel.")
+             .takes_value(true));
+    #[cfg(feature = "watch")]
+    let app = app.arg(Arg::with_name("watch")
+                      .long("watch")
+                      .help("Reload the ROM whenever the file changes."));
+    let matches = app.get_matches();
+
+    let invalid = |e: String| Error::new(ErrorKind::InvalidInput, e);
+
+    let mut config = Config::default();
+    if let Some(profile) = matches.value_of("profile") {
+        config.set_profile(profile).map_err(invalid)?;
+    }
+    if let Some(path) = matches.value_of("config") {
+        config = config.update_from_path(path).map_err(invalid)?;
+    }
+
+    if let Some(clock) = matches.value_of("clock") {
+        config.clock_hz = clock
+            .parse()
+            .ok()
+            .filter(|&hz| hz > 0)
+            .ok_or_else(|| invalid(format!("bad clock rate: {}", clock)))?;
+    }
+
+    if let Some(scale) = matches.value_of("scale") {
+        config.scale = scale
+            .parse()
+            .ok()
+            .filter(|&n| n > 0)
+            .ok_or_else(|| invalid(format!("bad scale: {}", scale)))?;
+    }
 
     let rom_path = matches.value_of("ROM").unwrap();
     let mut f = File::open(rom_path)?;
@@ -27,26 +104,167 @@ fn main() -> io::Result<()> {
 
     f.read_to_end(&mut prog)?;
 
-    let ui_controller = UIController::new();
-    let mut cpu = Cpu::new();
+    if let Some(out) = matches.value_of("disasm") {
+        let text = disasm::listing(&prog);
+        return if out == "-" {
+            io::stdout().write_all(text.as_bytes())
+        } else {
+            File::create(out)?.write_all(text.as_bytes())
+        };
+    }
+
+    let rom_name = Path::new(rom_path)
+        .file_stem()
+        .map(|s| s.to_string_lossy().into_owned());
+    let ui_controller = UIController::try_with_settings(
+        rom_name.as_deref(), config.controller_settings())
+        .map_err(|e| Error::other(format!("could not start the UI: {}", e)))?;
+    let mut cpu = config.build_cpu();
 
     cpu.set_sound_driver(Some(ui_controller.get_sound_driver()));
     cpu.set_input_driver(Some(ui_controller.get_input_driver()));
     cpu.set_display_driver(Some(ui_controller.get_display_driver()));
+    cpu.set_stop_on_nonfatal(false);
+
+    if let Some(path) = matches.value_of("trace") {
+        /* Write errors are dropped, the trace being a debugging aid;
+           the file is flushed when the Cpu goes away on exit. */
+        let mut out = BufWriter::new(File::create(path)?);
+        cpu.set_trace_hook(Some(Box::new(move |entry| {
+            let _ = writeln!(out, "{}", debugger::trace_json(entry));
+        })));
+    }
 
-    cpu.load(&prog)
+    cpu.load_maybe_compressed(&prog)
         .map_err(
             |e| Error::new(ErrorKind::InvalidData, e.to_string())
         )?;
 
-    while ui_controller.alive() {
-        if let Err(e) = cpu.tick() {
-            if e.fatal() {
+    let frame_time = time::Duration::from_nanos(
+        1_000_000_000 * config.cycles_per_frame as u64 / config.clock_hz as u64);
+
+    if matches.is_present("debug") {
+        debug_repl(&mut cpu, &ui_controller, config.cycles_per_frame, frame_time)?;
+        return Ok(());
+    }
+
+    let mut pacer = Pacer::new(frame_time, time::Instant::now());
+    #[cfg(feature = "watch")]
+    let mut watcher = if matches.is_present("watch") {
+        Some(RomWatcher::new(rom_path, time::Instant::now()))
+    } else {
+        None
+    };
+
+    let mut cap_reported = false;
+    'running: while ui_controller.alive() {
+        #[cfg(feature = "watch")]
+        if watcher.as_mut().is_some_and(|w| w.changed(time::Instant::now())) {
+            /* A half written file can fail to load; the next save retries. */
+            match std::fs::read(rom_path) {
+                Ok(rom) => match cpu.reset_and_load(&rom) {
+                    Ok(()) => prog = rom,
+                    Err(e) => println!("could not reload {}: {}", rom_path, e),
+                },
+                Err(e) => println!("could not reload {}: {}", rom_path, e),
+            }
+        }
+        if ui_controller.take_reset() {
+            cpu.reset_and_load(&prog)
+                .map_err(|e| Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        }
+        if ui_controller.paused() {
+            thread::sleep(frame_time);
+            pacer.resync(time::Instant::now());
+            continue;
+        }
+
+        let turbo = ui_controller.turbo();
+        let frames = if turbo { 1 } else { pacer.frames_due(time::Instant::now()) };
+        for _ in 0..frames {
+            if let Err(e) = cpu.run_frame(config.cycles_per_frame) {
                 println!("fatal CPU error: {:?}", e);
-                break;
+                if let Some(path) = matches.value_of("dump-state-on-crash") {
+                    File::create(path)?.write_all(debugger::crash_report(&cpu, &e).as_bytes())?;
+                    println!("state written to {}", path);
+                }
+                break 'running;
             }
         }
-        thread::sleep(time::Duration::from_millis(2));
+        if cpu.frame_capped() && !cap_reported {
+            eprintln!("warning: {} cycles per frame is over the cap, running {}",
+                      config.cycles_per_frame, cpu.max_frame_instructions());
+        }
+        cap_reported = cpu.frame_capped();
+        ui_controller.update_hud(&cpu);
+
+        if turbo {
+            pacer.resync(time::Instant::now());
+        } else {
+            thread::sleep(pacer.until_next(time::Instant::now()));
+        }
+    }
+
+    Ok(())
+}
+
+fn debug_repl(cpu: &mut Cpu, ui_controller: &UIController, cycles_per_frame: usize,
+              frame_time: time::Duration) -> io::Result<()> {
+    let mut breakpoints = debugger::Breakpoints::default();
+    println!("{}", debugger::Command::HELP);
+
+    for line in io::stdin().lock().lines() {
+        if !ui_controller.alive() {
+            break;
+        }
+        let cmd = match debugger::Command::parse(&line?) {
+            Ok(cmd) => cmd,
+            Err(e) => {
+                println!("{}\n{}", e, debugger::Command::HELP);
+                continue;
+            },
+        };
+
+        match cmd {
+            debugger::Command::Step => {
+                if let Err(e) = cpu.tick() {
+                    println!("CPU error: {}", e);
+                }
+                print!("{}", debugger::disassemble_around(cpu));
+            },
+            debugger::Command::Continue => {
+                /* Runs at the normal pace until a breakpoint, a fatal error
+                   or the window closing. */
+                'running: while ui_controller.alive() {
+                    for _ in 0..cycles_per_frame {
+                        match cpu.tick() {
+                            Err(e) if e.fatal() => {
+                                println!("fatal CPU error: {}", e);
+                                break 'running;
+                            },
+                            _ => (),
+                        }
+                        if breakpoints.hit(cpu) {
+                            println!("breakpoint at {:04x}", cpu.program_counter());
+                            break 'running;
+                        }
+                    }
+                    ui_controller.update_hud(cpu);
+                    thread::sleep(frame_time);
+                }
+            },
+            debugger::Command::Break(addr) => {
+                let set = breakpoints.toggle(addr);
+                println!("breakpoint {} at {:04x}", if set { "set" } else { "cleared" }, addr);
+            },
+            debugger::Command::Registers => print!("{}", debugger::registers(cpu)),
+            debugger::Command::Memory(addr, len) => match debugger::hexdump(cpu, addr, len as usize) {
+                Ok(dump) => print!("{}", dump),
+                Err(e) => println!("{}", e),
+            },
+            debugger::Command::Disassemble => print!("{}", debugger::disassemble_around(cpu)),
+        }
+        ui_controller.update_hud(cpu);
     }
 
     Ok(())
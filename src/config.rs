@@ -0,0 +1,446 @@
+use std::fs;
+use std::path::Path;
+
+use sdl2::pixels::Color;
+
+use crate::core::cpu::Cpu;
+use crate::core::quirks::Quirks;
+use crate::core::variant::Variant;
+use crate::sdl::controller::{ControlBindings, KeyMappingMode, Settings};
+
+type Result<T> = std::result::Result<T, String>;
+
+/* A hand-written parser for just the TOML this file needs. The crate builds
+   offline against a small, fixed set of dependencies, and pulling in serde
+   and a toml crate for a dozen flat keys isn't worth it. Anything outside
+   the subset described on Config is an error rather than being guessed at:
+   inline tables, arrays of tables, dotted keys, multi-line and literal
+   strings, escape sequences, floats and dates. */
+
+/* Emulator-wide settings, read from a small TOML subset: top level keys,
+   [section] headers, and integer, boolean, string and flat array values.
+
+       clock_hz = 500
+       fg_color = [255, 176, 0]
+       variant = "xochip"
+
+       [quirks]
+       strict_address_space = true
+
+       [keymap]
+       "1" = 0x1
+       Q = 0x4
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub variant: Variant,
+    pub quirks: Quirks,
+    pub clock_hz: u32,
+    pub cycles_per_frame: usize,
+    pub fg_color: (u8, u8, u8),
+    pub bg_color: (u8, u8, u8),
+    pub scale: u32,
+    pub integer_scale: bool,
+    pub fps_in_title: bool,
+    pub anti_click: bool,
+    pub latch_input_per_frame: bool,
+    pub keymap: Vec<(String, u8)>,
+    pub keymap2: Vec<(String, u8)>,
+    pub key_mapping: KeyMappingMode,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Bool(bool),
+    Int(i64),
+    Str(String),
+    Array(Vec<Value>),
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            variant: Variant::Schip,
+            quirks: Quirks::default(),
+            clock_hz: 500,
+            cycles_per_frame: 8,
+            fg_color: (255, 255, 255),
+            bg_color: (0, 0, 0),
+            scale: 20,
+            integer_scale: false,
+            fps_in_title: false,
+            anti_click: false,
+            latch_input_per_frame: false,
+            keymap: Vec::new(),
+            keymap2: Vec::new(),
+            key_mapping: KeyMappingMode::Physical,
+        }
+    }
+}
+
+impl Config {
+    pub fn from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Config::default().update_from_path(path)
+    }
+
+    /* Like from_path, but starting from self instead of the defaults, so only
+       the keys present in the file change. */
+    pub fn update_from_path<P: AsRef<Path>>(self, path: P) -> Result<Self> {
+        let text = fs::read_to_string(&path)
+            .map_err(|e| format!("{}: {}", path.as_ref().display(), e))?;
+        self.update(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<Self> {
+        Config::default().update(text)
+    }
+
+    pub fn update(self, text: &str) -> Result<Self> {
+        let mut config = self;
+        let mut section = String::new();
+
+        for (n, line) in text.lines().enumerate() {
+            let err = |e: String| format!("line {}: {}", n + 1, e);
+            let line = strip_comment(line).trim();
+
+            if line.is_empty() {
+                continue;
+            } else if line.starts_with("[[") {
+                return Err(err("arrays of tables are not supported".to_owned()));
+            } else if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(err("unterminated section header".to_owned()));
+                }
+                section = line[1..line.len() - 1].trim().to_owned();
+            } else {
+                let (key, value) = parse_pair(line).map_err(err)?;
+                config.set(&section, &key, value).map_err(err)?;
+            }
+        }
+
+        Ok(config)
+    }
+
+    /* Start from one of the Quirks::PROFILES presets, along with the
+       variant it was taken from. */
+    pub fn set_profile(&mut self, name: &str) -> Result<()> {
+        let unknown = || format!("unknown profile: {}", name);
+        self.quirks = Quirks::profile(name).ok_or_else(unknown)?;
+        self.variant = Variant::for_profile(name).ok_or_else(unknown)?;
+        Ok(())
+    }
+
+    /* The variant sets up RAM and the op set. Its quirks are then replaced
+       wholesale by the configured ones. */
+    pub fn build_cpu(&self) -> Cpu {
+        let mut cpu = Cpu::new(self.variant);
+        cpu.set_quirks(self.quirks.clone());
+        cpu.set_buzz_threshold(self.anti_click as u8);
+        cpu.set_latch_input_per_frame(self.latch_input_per_frame);
+        cpu
+    }
+
+    pub fn controller_settings(&self) -> Settings {
+        let (r, g, b) = self.fg_color;
+        let fg_color = Color::RGB(r, g, b);
+        let (r, g, b) = self.bg_color;
+        let bg_color = Color::RGB(r, g, b);
+
+        Settings {
+            scale: self.scale,
+            fg_color,
+            bg_color,
+            keymap: self.keymap.clone(),
+            extra_keymaps: vec![self.keymap2.clone()],
+            key_mapping: self.key_mapping,
+            controls: ControlBindings::default(),
+            integer_scale: self.integer_scale,
+            fps_in_title: self.fps_in_title,
+        }
+    }
+
+    fn set(&mut self, section: &str, key: &str, value: Value) -> Result<()> {
+        match (section, key) {
+            ("", "clock_hz") => self.clock_hz = as_int(value, 1, u32::MAX as i64)? as u32,
+            ("", "cycles_per_frame") => {
+                self.cycles_per_frame = as_int(value, 1, u32::MAX as i64)? as usize
+            },
+            ("", "fg_color") => self.fg_color = as_color(value)?,
+            ("", "bg_color") => self.bg_color = as_color(value)?,
+            ("", "scale") => self.scale = as_int(value, 1, 0xff)? as u32,
+            ("", "integer_scale") => self.integer_scale = as_bool(value)?,
+            ("", "fps_in_title") => self.fps_in_title = as_bool(value)?,
+            ("", "anti_click") => self.anti_click = as_bool(value)?,
+            ("", "latch_input_per_frame") => self.latch_input_per_frame = as_bool(value)?,
+            ("", "variant") => {
+                let name = as_str(value)?;
+                self.variant = Variant::from_name(&name)
+                    .ok_or_else(|| format!("unknown variant: {}", name))?
+            },
+            ("", "key_mapping") => {
+                let name = as_str(value)?;
+                self.key_mapping = KeyMappingMode::from_name(&name)
+                    .ok_or_else(|| format!("unknown key mapping: {}", name))?
+            },
+            ("quirks", "strict_address_space") => self.quirks.strict_address_space = as_bool(value)?,
+            ("quirks", "lores_scroll_rounds_up") => {
+                self.quirks.lores_scroll_rounds_up = as_bool(value)?
+            },
+            ("quirks", "shift_uses_vy") => self.quirks.shift_uses_vy = as_bool(value)?,
+            ("quirks", "add_imm_sets_vf") => self.quirks.add_imm_sets_vf = as_bool(value)?,
+            ("quirks", "wrap_jumps") => self.quirks.wrap_jumps = as_bool(value)?,
+            ("quirks", "clip_sprites") => self.quirks.clip_sprites = as_bool(value)?,
+            ("quirks", "memory_increments_i") => {
+                self.quirks.memory_increments_i = as_bool(value)?
+            },
+            ("quirks", "strict_index") => self.quirks.strict_index = as_bool(value)?,
+            ("keymap", name) => {
+                let k = as_int(value, 0, Cpu::MAX_REG as i64)? as u8;
+                self.keymap.push((name.to_owned(), k));
+            },
+            ("keymap2", name) => {
+                let k = as_int(value, 0, Cpu::MAX_REG as i64)? as u8;
+                self.keymap2.push((name.to_owned(), k));
+            },
+            _ => return Err(format!("unknown key: {}", key)),
+        }
+        Ok(())
+    }
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_str = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_str = !in_str,
+            '#' if !in_str => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_pair(line: &str) -> Result<(String, Value)> {
+    let (key, rest) = if let Some(stripped) = line.strip_prefix('"') {
+        let end = stripped.find('"').ok_or("unterminated key")?;
+        (stripped[..end].to_owned(), &stripped[end + 1..])
+    } else {
+        let end = line.find('=').ok_or("expected key = value")?;
+        (line[..end].trim().to_owned(), &line[end..])
+    };
+
+    let rest = rest.trim_start();
+    if !rest.starts_with('=') || key.is_empty() {
+        return Err("expected key = value".to_owned());
+    }
+
+    Ok((key, parse_value(rest[1..].trim())?))
+}
+
+fn parse_value(s: &str) -> Result<Value> {
+    if s == "true" {
+        Ok(Value::Bool(true))
+    } else if s == "false" {
+        Ok(Value::Bool(false))
+    } else if s.starts_with("\"\"\"") {
+        Err("multi-line strings are not supported".to_owned())
+    } else if s.starts_with('"') {
+        if s.len() < 2 || !s.ends_with('"') {
+            return Err("unterminated string".to_owned());
+        }
+        let body = &s[1..s.len() - 1];
+        if body.contains('\\') {
+            return Err("escape sequences are not supported".to_owned());
+        } else if body.contains('"') {
+            return Err(format!("bad value: {}", s));
+        }
+        Ok(Value::Str(body.to_owned()))
+    } else if s.starts_with('{') {
+        Err("inline tables are not supported".to_owned())
+    } else if s.starts_with('[') {
+        if !s.ends_with(']') {
+            return Err("unterminated array".to_owned());
+        }
+        s[1..s.len() - 1]
+            .split(',')
+            .map(str::trim)
+            .filter(|e| !e.is_empty())
+            .map(parse_value)
+            .collect::<Result<Vec<Value>>>()
+            .map(Value::Array)
+    } else if let Some(hex) = s.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+            .map(Value::Int)
+            .map_err(|_| format!("bad value: {}", s))
+    } else {
+        s.parse::<i64>()
+            .map(Value::Int)
+            .map_err(|_| format!("bad value: {}", s))
+    }
+}
+
+fn as_bool(value: Value) -> Result<bool> {
+    match value {
+        Value::Bool(b) => Ok(b),
+        v => Err(format!("expected a boolean, got {:?}", v)),
+    }
+}
+
+fn as_str(value: Value) -> Result<String> {
+    match value {
+        Value::Str(s) => Ok(s),
+        v => Err(format!("expected a string, got {:?}", v)),
+    }
+}
+
+fn as_int(value: Value, min: i64, max: i64) -> Result<i64> {
+    match value {
+        Value::Int(i) if i >= min && i <= max => Ok(i),
+        Value::Int(i) => Err(format!("{} is out of range {}..={}", i, min, max)),
+        v => Err(format!("expected an integer, got {:?}", v)),
+    }
+}
+
+fn as_color(value: Value) -> Result<(u8, u8, u8)> {
+    match value {
+        Value::Array(ref a) if a.len() == 3 => {
+            let c = a.iter()
+                .map(|v| as_int(v.clone(), 0, 0xff).map(|c| c as u8))
+                .collect::<Result<Vec<u8>>>()?;
+            Ok((c[0], c[1], c[2]))
+        },
+        v => Err(format!("expected [r, g, b], got {:?}", v)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::error::Error;
+    use crate::core::op::Op;
+
+    const SAMPLE: &str = r#"
+        # Amber on black, a bit faster than usual.
+        clock_hz = 700
+        cycles_per_frame = 12
+        scale = 10
+        integer_scale = true
+        fps_in_title = true
+        anti_click = true
+        latch_input_per_frame = true
+        fg_color = [255, 176, 0]
+        bg_color = [0x10, 0x10, 0x10]
+        key_mapping = "logical"
+        variant = "xochip"
+
+        [quirks]
+        strict_address_space = true
+
+        [keymap]
+        "1" = 0x1
+        Q = 0x4   # trailing comment
+
+        [keymap2]
+        Up = 0x5
+    "#;
+
+    #[test]
+    fn parse_sample() {
+        let config = Config::parse(SAMPLE).unwrap();
+
+        assert_eq!(config, Config {
+            variant: Variant::XoChip,
+            quirks: Quirks { strict_address_space: true, ..Quirks::default() },
+            clock_hz: 700,
+            cycles_per_frame: 12,
+            fg_color: (255, 176, 0),
+            bg_color: (0x10, 0x10, 0x10),
+            scale: 10,
+            integer_scale: true,
+            fps_in_title: true,
+            anti_click: true,
+            latch_input_per_frame: true,
+            keymap: vec![("1".to_owned(), 0x1), ("Q".to_owned(), 0x4)],
+            keymap2: vec![("Up".to_owned(), 0x5)],
+            key_mapping: KeyMappingMode::Logical,
+        });
+
+        let cpu = config.build_cpu();
+        assert_eq!(cpu.variant(), Variant::XoChip);
+        assert_eq!(*cpu.quirks(), config.quirks);
+        assert_eq!(cpu.buzz_threshold(), 1);
+
+        let settings = config.controller_settings();
+        assert_eq!(settings.scale, 10);
+        assert!(settings.integer_scale);
+        assert!(settings.fps_in_title);
+        assert_eq!(settings.fg_color, Color::RGB(255, 176, 0));
+        assert_eq!(settings.bg_color, Color::RGB(0x10, 0x10, 0x10));
+        assert_eq!(settings.keymap, config.keymap);
+        assert_eq!(settings.extra_keymaps, vec![vec![("Up".to_owned(), 0x5)]]);
+        assert_eq!(settings.key_mapping, KeyMappingMode::Logical);
+    }
+
+    #[test]
+    fn update_profile() {
+        let base = Config { quirks: Quirks::profile("cosmac").unwrap(), ..Config::default() };
+        let config = base.update("[quirks]\nshift_uses_vy = false").unwrap();
+
+        assert!(config.quirks.strict_address_space);
+        assert!(!config.quirks.shift_uses_vy);
+    }
+
+    #[test]
+    fn profile_variant() {
+        let mut config = Config::default();
+
+        config.set_profile("xochip").unwrap();
+        let cpu = config.build_cpu();
+        assert_eq!(cpu.variant(), Variant::XoChip);
+        assert_eq!(cpu.ram_bytes(), 0x10000);
+        assert!(cpu.quirks().memory_increments_i);
+
+        config.set_profile("cosmac").unwrap();
+        let mut cpu = config.build_cpu();
+        assert_eq!(cpu.variant(), Variant::Chip8);
+        assert_eq!(cpu.exec(Op::High), Err(Error::UnimplementedOp(Op::High)));
+
+        config.set_profile("modern").unwrap();
+        assert_eq!(config.variant, Variant::Schip);
+        assert_eq!(config.quirks, Quirks::default());
+
+        assert!(config.set_profile("vip").is_err());
+    }
+
+    #[test]
+    fn parse_errors() {
+        assert_eq!(Config::parse(""), Ok(Config::default()));
+        assert!(Config::parse("clock_hz = 0").is_err());
+        assert!(Config::parse("fg_color = [1, 2]").is_err());
+        assert!(Config::parse("[quirks]\nstrict_address_space = 1").is_err());
+        assert!(Config::parse("[keymap]\nQ = 0x10").is_err());
+        assert!(Config::parse("bogus = true").is_err());
+        assert!(Config::parse("key_mapping = \"dvorak\"").is_err());
+        assert!(Config::parse("variant = \"megachip\"").is_err());
+        assert!(Config::parse("[quirks").is_err());
+    }
+
+    #[test]
+    fn unsupported_toml() {
+        let rejected = |text: &str| Config::parse(text).unwrap_err();
+
+        assert_eq!(rejected("[keymap]\nQ = { key = 4 }"),
+                   "line 2: inline tables are not supported");
+        assert_eq!(rejected("key_mapping = \"\"\"logical\"\"\""),
+                   "line 1: multi-line strings are not supported");
+        assert_eq!(rejected("key_mapping = \"\"\"\nlogical\n\"\"\""),
+                   "line 1: multi-line strings are not supported");
+        assert_eq!(rejected("key_mapping = \"log\\ical\""),
+                   "line 1: escape sequences are not supported");
+        assert_eq!(rejected("key_mapping = \"log\\\"ical\""),
+                   "line 1: escape sequences are not supported");
+        assert_eq!(rejected("[[keymap]]"), "line 1: arrays of tables are not supported");
+        assert!(Config::parse("key_mapping = 'logical'").is_err());
+        assert!(Config::parse("quirks.wrap_jumps = true").is_err());
+    }
+}
@@ -1,40 +1,188 @@
 
-use std::sync::Arc;
-use std::sync::atomic::Ordering;
+use std::collections::HashMap;
+#[cfg(feature = "std")]
+use std::fs;
+#[cfg(feature = "std")]
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
 
 use super::driver;
 use super::error::{Result, Error};
-use super::op::{Reg, Op};
-use super::timer::Timer;
+use super::memory::Memory;
+use super::op::{LintIssue, Reg, Op, OpCategory};
+use super::quirks::Quirks;
+use super::timer::{ManualSource, ThreadSource, Timer, TimerSource, ORDERING};
+use super::variant::Variant;
+use super::vram::{Edge, VramView};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunState {
+    Running,
+    WaitingForKey,
+    Halted,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DriverStatus {
+    pub display: bool,
+    pub sound: bool,
+    pub input: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemWrite {
+    pub pc: u16,
+    pub addr: u16,
+    pub old: u8,
+    pub new: u8,
+}
+
+/* One executed instruction, as handed to a trace hook: where it was, the
+   raw word and its decoding, the V registers once it had run, and the
+   error it ended with, if any. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceEntry {
+    pub pc: u16,
+    pub opcode: u16,
+    pub op: Op,
+    pub regs: [u8; Cpu::REG_COUNT],
+    pub error: Option<Error>,
+}
+
+type TraceHook = Box<dyn FnMut(&TraceEntry)>;
+type SelfModHook = Box<dyn FnMut(&MemWrite)>;
+
+/* What one instruction did, for a step debugger. changed_regs holds the
+   new value of every V register that changed, and drew is set for any
+   display op, whether or not a pixel actually flipped. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct StepResult {
+    pub op: Op,
+    pub pc_before: u16,
+    pub pc_after: u16,
+    pub changed_regs: Vec<(usize, u8)>,
+    pub drew: bool,
+}
+
+/* A copy of the state a debugger or renderer on another thread might want
+   to show. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snapshot {
+    pub pc: u16,
+    pub i: u16,
+    pub v: [u8; Cpu::REG_COUNT],
+    pub hires: bool,
+    pub vram: Vec<bool>,
+}
+
+/* The most recently published Snapshot. Publishing swaps in a new Arc, so
+   readers only ever hold the lock long enough to clone a pointer and never
+   hold up the CPU, and every snapshot they see is complete. */
+pub struct SharedState {
+    latest: Mutex<Arc<Snapshot>>,
+}
+
+impl SharedState {
+    pub fn latest(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.latest.lock().unwrap())
+    }
+}
+
+/* Everything needed to take back one instruction. RAM and vram only keep
+   the cells that changed, with their old values. */
+struct Undo {
+    pc: u16,
+    sp: u8,
+    i: u16,
+    v: [u8; Cpu::REG_COUNT],
+    stack: Vec<u16>,
+    dt: u8,
+    st: u8,
+    hires: bool,
+    state: RunState,
+    ram: Vec<(usize, u8)>,
+    vram: Vec<(usize, bool)>,
+}
 
 pub struct Cpu {
     pc: u16,
     sp: u8,
     i: u16,
     v: [u8; Self::REG_COUNT],
-    ram: [u8; Self::RAM_BYTES],
-    vram: [bool; Self::VRAM_BYTES],
-    stack: [u16; Self::MAX_STACK_DEPTH],
+    ram: Memory,
+    vram: [bool; Self::HIRES_VRAM_BYTES],
+    hires: bool,
+    stack: Vec<u16>,
     display_driver: Option<Box<dyn driver::Display>>,
     input_driver: Option<Box<dyn driver::Input>>,
     timer: Timer,
+    timer_source: Box<dyn TimerSource>,
+    state: RunState,
+    skip_unknown_opcodes: bool,
+    stop_on_nonfatal: bool,
+    protect_font: bool,
+    font_addr: u16,
+    variant: Variant,
+    quirks: Quirks,
+    timings: Option<HashMap<OpCategory, Duration>>,
+    mem_writes: Option<Vec<MemWrite>>,
+    highest_pc: u16,
+    self_mod_hook: Option<SelfModHook>,
+    trace_hook: Option<TraceHook>,
+    shared: Option<Arc<SharedState>>,
+    double_buffer: bool,
+    frame_pending: bool,
+    max_frame_instructions: usize,
+    frame_capped: bool,
+    undo_journal: bool,
+    last_undo: Option<Undo>,
+    detect_halt_loops: bool,
+    latched_keys: Option<[Option<bool>; Self::KEY_COUNT]>,
+    latched_at: u32,
+    rng_seed: Option<u64>,
+    rng: Option<StdRng>,
+    rng_calls: u64,
 }
 
 impl Cpu {
     pub const LOAD_OFFSET: usize = 0x200;
     pub const REG_COUNT: usize = 0x10;
-    pub const RAM_BYTES: usize = 0x1000;
+    /* The standard 4K. ram_bytes gives the current variant's size. */
+    pub const RAM_BYTES: usize = Memory::BYTES;
+    pub const ADDRESS_SPACE_END: usize = 0xfff;
+    /* The default stack depth. The VIP only had room for 12 entries and
+       SCHIP for 16, but 32 has always been the default here and deeply
+       recursive ROMs rely on it; see set_stack_depth. */
     pub const MAX_STACK_DEPTH: usize = 0x20;
 
     pub const MAX_REG: usize = 0x0f;
+    pub const KEY_COUNT: usize = 0x10;
     pub const INDEX_REG: usize = 0x00;
     pub const FLAG_REG: usize = 0x0f;
 
     pub const DISPLAY_WIDTH: usize = 0x40;
     pub const DISPLAY_HEIGHT: usize = 0x20;
 
+    /* The size of the lo-res screen. The vram array itself is allocated at
+       HIRES_VRAM_BYTES, and only this much of it is in use in lo-res. */
     pub const VRAM_BYTES: usize = Self::DISPLAY_WIDTH * Self::DISPLAY_HEIGHT;
 
+    pub const HIRES_DISPLAY_WIDTH: usize = 0x80;
+    pub const HIRES_DISPLAY_HEIGHT: usize = 0x40;
+
+    pub const HIRES_VRAM_BYTES: usize = Self::HIRES_DISPLAY_WIDTH * Self::HIRES_DISPLAY_HEIGHT;
+
+    const SCROLL_SIDEWAYS_PX: u8 = 4;
+
+    const RUN_CYCLES_PER_FRAME: usize = 8;
+    pub const DEFAULT_MAX_FRAME_INSTRUCTIONS: usize = 100_000;
+    const RUN_FRAME_TIME: Duration = Duration::from_millis(16);
+
     const FONT_SPRITES_BYTES: usize = 0x50;
     const FONT_SPRITES_RAM_START: usize = 0x0;
     const FONT_SPRITES_RAM_END: usize = 0x50;
@@ -59,10 +207,28 @@ impl Cpu {
         0xf0, 0x80, 0xf0, 0x80, 0x80,   /* F */
     ];
 
-    pub fn new() -> Self {
-        let mut ram = [0xff; Self::RAM_BYTES];
+    /* A Cpu for variant, with its RAM, quirks and ops. Schip runs every op
+       this emulator knows. */
+    pub fn new(variant: Variant) -> Self {
+        let mut cpu = Self::with_timer_source(Box::new(ThreadSource));
+        cpu.set_variant(variant);
+        cpu
+    }
+
+    /* The timers only advance when the host calls tick_timers or
+       advance_timers. */
+    pub fn new_without_timer_thread() -> Self {
+        Self::with_timer_source(Box::new(ManualSource::default()))
+    }
+
+    pub fn with_timer_source(timer_source: Box<dyn TimerSource>) -> Self {
+        let timer = if timer_source.needs_thread() { Timer::new() } else { Timer::new_manual() };
 
-        ram[Self::FONT_SPRITES_RAM_START..Self::FONT_SPRITES_RAM_END]
+        let mut ram = Memory::filled(0xff);
+
+        ram.slice_mut(Self::FONT_SPRITES_RAM_START,
+                      Self::FONT_SPRITES_RAM_END - Self::FONT_SPRITES_RAM_START)
+            .unwrap()
             .copy_from_slice(&Self::FONT_SPRITES);
 
         Cpu {
@@ -70,24 +236,125 @@ impl Cpu {
             sp: 0x00,
             i: 0x0000,
             v: [0x00; Self::REG_COUNT],
-            ram: ram,
-            vram: [false; Self::VRAM_BYTES],
-            stack: [0x0000; Self::MAX_STACK_DEPTH],
+            ram,
+            vram: [false; Self::HIRES_VRAM_BYTES],
+            hires: false,
+            stack: vec![0x0000; Self::MAX_STACK_DEPTH],
             display_driver: None,
             input_driver: None,
-            timer: Timer::new(),
+            timer,
+            timer_source,
+            state: RunState::Running,
+            skip_unknown_opcodes: false,
+            stop_on_nonfatal: true,
+            protect_font: false,
+            font_addr: Self::FONT_SPRITES_RAM_START as u16,
+            variant: Variant::Schip,
+            quirks: Quirks::default(),
+            timings: None,
+            mem_writes: None,
+            highest_pc: 0,
+            self_mod_hook: None,
+            trace_hook: None,
+            shared: None,
+            double_buffer: false,
+            frame_pending: false,
+            max_frame_instructions: Self::DEFAULT_MAX_FRAME_INSTRUCTIONS,
+            frame_capped: false,
+            undo_journal: false,
+            last_undo: None,
+            detect_halt_loops: false,
+            latched_keys: None,
+            latched_at: 0,
+            rng_seed: None,
+            rng: None,
+            rng_calls: 0,
+        }
+    }
+
+    /* Puts the machine back in its power-on state. Drivers, quirks and other
+       settings are kept, as is the timer thread. A pending key wait is
+       cancelled, so the input driver isn't left waiting on a press for an
+       instruction that is gone. */
+    pub fn reset(&mut self) {
+        if self.state == RunState::WaitingForKey {
+            if let Some(input_driver) = &self.input_driver {
+                input_driver.cancel_block();
+            }
+        }
+        let fresh = Self::new_without_timer_thread();
+
+        self.pc = fresh.pc;
+        self.sp = fresh.sp;
+        self.i = fresh.i;
+        self.v = fresh.v;
+        self.ram = fresh.ram;
+        self.ram.resize(self.variant.ram_bytes(), 0xff);
+        if self.font_addr != fresh.font_addr {
+            self.move_font(fresh.font_addr as usize);
+        }
+        self.vram = fresh.vram;
+        self.hires = fresh.hires;
+        self.stack = vec![0x0000; self.stack.len()];
+        self.state = fresh.state;
+        self.highest_pc = fresh.highest_pc;
+        self.last_undo = None;
+        self.frame_capped = false;
+        self.unlatch_keys();
+        self.rewind_rng();
+        self.timer.dt.store(0, ORDERING);
+        self.timer.st.store(0, ORDERING);
+    }
+
+    /* Swaps in a new program without tearing down the CPU. The display is
+       cleared right away so the old game doesn't linger on screen. The rom
+       may be gzipped, same as for load_maybe_compressed. */
+    pub fn reset_and_load(&mut self, rom: &[u8]) -> Result<()> {
+        self.reset();
+        if self.display_driver.is_some() {
+            self.refresh_display()?;
         }
+        self.load_maybe_compressed(rom)
     }
 
     pub fn load(&mut self, data: &[u8]) -> Result<()> {
-        if data.len() > self.ram.len() - Self::LOAD_OFFSET {
-            Err(Error::LoadFailure)
-        } else {
-            let load_end = Self::LOAD_OFFSET + data.len();
-            self.ram[Self::LOAD_OFFSET..load_end].copy_from_slice(data);
-            self.pc = Self::LOAD_OFFSET as u16;
-            Ok(())
+        self.ram.slice_mut(Self::LOAD_OFFSET, data.len())
+            .map_err(|_| Error::LoadFailure)?
+            .copy_from_slice(data);
+        self.pc = Self::LOAD_OFFSET as u16;
+        self.state = RunState::Running;
+        Ok(())
+    }
+
+    /* Loads data, inflating it first if it is gzipped. Without the gzip
+       feature, compressed data fails to load. */
+    pub fn load_maybe_compressed(&mut self, data: &[u8]) -> Result<()> {
+        #[cfg(feature = "gzip")]
+        if super::gzip::is_gzip(data) {
+            let max_len = self.ram_bytes() - Self::LOAD_OFFSET;
+            return self.load(&super::gzip::decompress(data, max_len)?);
+        }
+        #[cfg(not(feature = "gzip"))]
+        if data.starts_with(&[0x1f, 0x8b]) {
+            return Err(Error::LoadFailure);
         }
+        self.load(data)
+    }
+
+    /* Reads a ROM from disk and loads it, gzipped or not. */
+    #[cfg(feature = "std")]
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        self.load_maybe_compressed(&fs::read(path)?)
+    }
+
+    /* Returns the load addresses of every word in rom that doesn't decode. A
+       trailing odd byte is ignored. */
+    pub fn validate_rom(rom: &[u8]) -> Vec<u16> {
+        rom.chunks_exact(2)
+            .enumerate()
+            .filter(|(_, w)| !Op::is_valid(((w[0] as u16) << 8) | w[1] as u16))
+            .map(|(n, _)| (Self::LOAD_OFFSET + n * 2) as u16)
+            .collect()
     }
 
     pub fn set_display_driver(&mut self, driver: Option<Box<dyn driver::Display>>) {
@@ -100,416 +367,2522 @@ impl Cpu {
         *d = driver;
     }
 
-    pub fn set_input_driver(&mut self, driver: Option<Box<dyn driver::Input>>) {
-        self.input_driver = driver;
+    pub fn driver_status(&self) -> DriverStatus {
+        DriverStatus {
+            display: self.display_driver.is_some(),
+            sound: self.timer.sound_driver.lock().unwrap().is_some(),
+            input: self.input_driver.is_some(),
+        }
     }
 
-    pub fn tick(&mut self) -> Result<()> {
-        let opcode = self.fetch()?;
-        let op = Op::decode(opcode)
-            .ok_or_else(|| Error::BadInstruction)?;
-        self.exec(op)
+    pub fn buzz_threshold(&self) -> u8 {
+        self.timer.buzz_threshold.load(ORDERING)
     }
 
-    pub fn fetch(&self) -> Result<u16> {
-        if self.pc as usize > self.ram.len() - 1 {
-            Err(Error::PrefetchAbort)
-        } else {
-            let h = self.ram[self.pc as usize] as u16;
-            let l = self.ram[(self.pc + 1) as usize] as u16;
-            Ok((h << 8) | l)
-        }
+    /* See Timer::buzz_threshold. */
+    pub fn set_buzz_threshold(&mut self, threshold: u8) {
+        self.timer.buzz_threshold.store(threshold, ORDERING);
     }
 
-    pub fn exec(&mut self, op: Op) -> Result<()> {
-        self.pc += 2;
+    /* See Timer::on_delay_expire. */
+    pub fn on_delay_expire(&self, callback: Option<Box<dyn FnMut() + Send>>) {
+        self.timer.on_delay_expire(callback);
+    }
 
-        match op {
-            Op::Sys(_) => Err(Error::UnimplementedOp(op)),
-            Op::Cls => {
-                for elem in self.vram.iter_mut() {
-                    *elem = false;
-                }
-                if let Some(display_driver) = &mut self.display_driver {
-                    display_driver.refresh(&self.vram);
-                    Ok(())
-                } else {
-                    Err(Error::DriverMissing)
-                }
-            },
-            Op::Ret => {
-                if self.sp == 0 {
-                    Err(Error::StackUnderflow)
-                } else {
-                    self.sp -= 1;
-                    self.pc = self.stack[self.sp as usize];
-                    Ok(())
-                }
-            },
-            Op::Jmp(addr) => {
-                self.pc = addr;
-                Ok(())
-            },
-            Op::Call(addr) => {
-                if self.sp as usize > self.stack.len() {
-                    Err(Error::StackOverflow)
-                } else {
-                    self.stack[self.sp as usize] = self.pc;
-                    self.sp += 1;
-                    self.pc = addr;
-                    Ok(())
-                }
-            },
-            Op::Se(Reg(x @ 0..=Self::MAX_REG), kk) => {
-                if self.v[x] == kk {
-                    self.pc += 2;
-                }
-                Ok(())
-            },
-            Op::Sne(Reg(x @ 0..=Self::MAX_REG), kk) => {
-                if self.v[x] != kk {
-                    self.pc += 2;
-                }
-                Ok(())
-            },
-            Op::Sre(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                if self.v[x] == self.v[y] {
-                    self.pc += 2;
-                }
-                Ok(())
-            },
-            Op::Ld(Reg(x @ 0..=Self::MAX_REG), kk) => {
-                self.v[x] = kk;
-                Ok(())
-            },
-            Op::Add(Reg(x @ 0..=Self::MAX_REG), kk) => {
-                /* per spec, carry flag intentionally not changed */
-                let (val, _) = self.v[x].overflowing_add(kk);
-                self.v[x] = val;
-                Ok(())
-            },
-            Op::Mov(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                self.v[x] = self.v[y];
-                Ok(())
-            },
-            Op::Or(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                self.v[x] |= self.v[y];
-                Ok(())
-            },
-            Op::And(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                self.v[x] &= self.v[y];
-                Ok(())
-            },
-            Op::Xor(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                self.v[x] ^= self.v[y];
-                Ok(())
-            },
-            Op::Addr(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                let (val, carry) = self.v[x].overflowing_add(self.v[y]);
-                self.v[x] = val;
-                self.v[Self::FLAG_REG] = carry as u8;
-                Ok(())
-            },
-            Op::Subr(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                let (val, carry) = self.v[x].overflowing_sub(self.v[y]);
-                self.v[x] = val;
-                self.v[Self::FLAG_REG] = !carry as u8;
-                Ok(())
-            },
-            Op::Shr(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                self.v[Self::FLAG_REG] = self.v[y] & 0x01;
-                self.v[x] = self.v[y] >> 1;
-                Ok(())
-            },
-            Op::Subnr(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                let (val, carry) = self.v[y].overflowing_sub(self.v[x]);
-                self.v[x] = val;
-                self.v[Self::FLAG_REG] = !carry as u8;
-                Ok(())
-            },
-            Op::Shl(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                self.v[Self::FLAG_REG] = self.v[y] & 0x80;
-                self.v[x] = self.v[y] << 1;
-                Ok(())
-            },
-            Op::Srne(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG)) => {
-                if self.v[x] != self.v[y] {
-                    self.pc += 2;
-                }
-                Ok(())
-            },
-            Op::Ldi(addr) => {
-                self.i = addr;
-                Ok(())
-            },
-            Op::Jmpi(addr) => {
-                self.pc = addr + (self.v[Self::INDEX_REG] as u16);
-                Ok(())
-            },
-            Op::Rand(Reg(x @ 0..=Self::MAX_REG), kk) => {
-                self.v[x] = rand::random::<u8>() & kk;
-                Ok(())
-            },
-            Op::Draw(Reg(x @ 0..=Self::MAX_REG), Reg(y @ 0..=Self::MAX_REG), m) => {
-                if ((self.i + m as u16) as usize) < self.ram.len() {
-                    let mut did_clear = false;
-                    for n in 0..m {
-                        let offset = self.i as usize + n as usize;
-                        let spr_byte = self.ram[offset];
-                        let v = (self.v[y] as usize + n as usize) % Self::DISPLAY_HEIGHT;
-                        for h in 0..8 {
-                            let set = (spr_byte & (1 << (7 - h))) != 0;
-                            let h = (self.v[x] as usize + h) % Self::DISPLAY_WIDTH;
-                            let vram_offset = v * Self::DISPLAY_WIDTH + h;
-                            let will_clear = self.vram[vram_offset] && set;
-                            if will_clear {
-                                did_clear = true;
-                            }
-                            self.vram[vram_offset] ^= set;
-                        }
-                        self.v[Self::FLAG_REG] = did_clear as u8;
-                    }
+    pub fn on_sound_expire(&self, callback: Option<Box<dyn FnMut() + Send>>) {
+        self.timer.on_sound_expire(callback);
+    }
 
-                    if let Some(display_driver) = &mut self.display_driver {
-                        display_driver.refresh(&self.vram);
-                        Ok(())
-                    } else {
-                        Err(Error::DriverMissing)
-                    }
-                } else {
-                    Err(Error::DataAbort)
-                }
-            },
-            Op::Skp(Reg(x @ 0..=Self::MAX_REG)) => {
-                if let Some(input_driver) = &self.input_driver {
-                    if input_driver.poll(self.v[x] as u8) {
-                        self.pc += 2;
-                    }
-                    Ok(())
-                } else {
-                    Err(Error::DriverMissing)
-                }
-            },
-            Op::Sknp(Reg(x @ 0..=Self::MAX_REG)) => {
-                if let Some(input_driver) = &self.input_driver {
-                    if !input_driver.poll(self.v[x] as u8) {
-                        self.pc += 2;
-                    }
-                    Ok(())
-                } else {
-                    /* Assume that no input driver means no key press, ever. */
-                    self.pc += 2;
-                    Err(Error::DriverMissing)
-                }
-            },
-            Op::Movd(Reg(x @ 0..=Self::MAX_REG)) => {
-                self.v[x] = self.timer.dt.load(Ordering::Relaxed);
-                Ok(())
-            },
-            Op::Key(Reg(x @ 0..=Self::MAX_REG)) => {
-                if let Some(input_driver) = &self.input_driver {
-                    self.v[x] = input_driver.block();
-                    Ok(())
-                } else {
-                    Err(Error::DriverMissing)
-                }
-            },
-            Op::Ldd(Reg(x @ 0..=Self::MAX_REG)) => {
-                self.timer.dt.store(self.v[x], Ordering::Relaxed);
-                Ok(())
-            },
-            Op::Lds(Reg(x @ 0..=Self::MAX_REG)) => {
-                self.timer.st.store(self.v[x], Ordering::Relaxed);
-                Ok(())
-            },
-            Op::Addi(Reg(x @ 0..=Self::MAX_REG)) => {
-                self.i += self.v[x] as u16;
-                Ok(())
-            },
-            Op::Ldspr(Reg(x @ 0..=Self::MAX_REG)) => {
-                self.i = Self::FONT_SPRITES_RAM_START as u16 +
-                         Self::FONT_SPRITE_BYTES_PER as u16 *
-                         self.v[x] as u16;
-                Ok(())
-            },
-            Op::Bcd(Reg(x @ 0..=Self::MAX_REG)) => {
-                let i = self. i as usize;
-                if i < self.ram.len() - 2 {
-                    let vx = self.v[x];
-                    let h = vx / 100;
-                    let t = (vx - h * 100) / 10;
-                    let o = vx - (h * 100) - (t * 10);
-
-                    self.ram[i] = h;
-                    self.ram[i + 1] = t;
-                    self.ram[i + 2] = o;
-                    Ok(())
-                } else {
-                    Err(Error::DataAbort)
-                }
-            },
-            Op::Str(Reg(x @ 0..=Self::MAX_REG)) => {
-                let i = self.i as usize;
-                let j = i + x;
-                if j < self.ram.len() {
-                    self.ram[i..=j].copy_from_slice(&self.v[..=x]);
-                    Ok(())
-                } else {
-                    Err(Error::DataAbort)
-                }
-            },
-            Op::Read(Reg(x @ 0..=Self::MAX_REG)) => {
-                let i = self.i as usize;
-                let j = i + x;
-                if j < self.ram.len() {
-                    self.v[..=x].copy_from_slice(&self.ram[i..=j]);
-                    Ok(())
-                } else {
-                    Err(Error::DataAbort)
-                }
-            },
-            _ => Err(Error::MalformedOp(op)),
+    /* Reports host time to the timer source, ticking the timers for as
+       many 60hz periods as it says are due. */
+    pub fn advance_timers(&mut self, delta: Duration) {
+        for _ in 0..self.timer_source.ticks_due(delta) {
+            self.timer.tick();
         }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::thread;
-    use std::time::Duration;
+    pub fn tick_timers(&self) {
+        self.timer.tick();
+    }
 
-    use super::*;
+    pub fn set_input_driver(&mut self, driver: Option<Box<dyn driver::Input>>) {
+        self.input_driver = driver;
+    }
 
-    #[test]
-    fn atomic() {
-        let mut cpu = Cpu::new();
+    /* With latching on, SKP and SKNP see each key as it was the first time
+       it was checked in a frame, even if the UI thread catches a change
+       partway through. A ROM that checks a key twice in a frame then can't
+       get two different answers. A frame ends with each 60hz timer tick,
+       however the host drives the CPU. key_pressed always reads the live
+       state. */
+    pub fn set_latch_input_per_frame(&mut self, latch: bool) {
+        self.latched_keys = if latch { Some([None; Self::KEY_COUNT]) } else { None };
+    }
 
-        cpu.exec(Op::Ld(Reg(0), 200)).unwrap();
-        cpu.exec(Op::Ldd(Reg(0))).unwrap();
-        cpu.exec(Op::Movd(Reg(0))).unwrap();
+    fn unlatch_keys(&mut self) {
+        if let Some(keys) = &mut self.latched_keys {
+            *keys = [None; Self::KEY_COUNT];
+        }
+    }
 
-        assert_eq!(cpu.v[0], 200);
-        thread::sleep(Duration::from_millis(500));
+    /* None without an input driver. */
+    fn poll_key(&mut self, key: u8) -> Option<bool> {
+        let ticks = self.timer.ticks.load(ORDERING);
+        if ticks != self.latched_at {
+            self.latched_at = ticks;
+            self.unlatch_keys();
+        }
+        let driver = self.input_driver.as_ref()?;
+        match self.latched_keys.as_mut().and_then(|keys| keys.get_mut(key as usize)) {
+            Some(latched) => Some(*latched.get_or_insert_with(|| driver.poll(key))),
+            None => Some(driver.poll(key)),
+        }
+    }
 
-        cpu.exec(Op::Movd(Reg(0))).unwrap();
-        assert!(cpu.v[0] < 175 && cpu.v[0] > 165);
+    /* For callers outside of exec. Unlike SKP, a missing input driver
+       isn't an error here, just a key that is never down. */
+    pub fn key_pressed(&self, key: u8) -> bool {
+        self.input_driver.as_ref().is_some_and(|d| d.poll(key))
+    }
 
-        cpu.exec(Op::Ld(Reg(0), 200)).unwrap();
-        cpu.exec(Op::Lds(Reg(0))).unwrap();
-        thread::sleep(Duration::from_millis(250));
+    pub fn quirks(&self) -> &Quirks {
+        &self.quirks
+    }
 
-        let ds = cpu.timer.st.load(Ordering::Relaxed);
-        assert!(ds < 187 && ds > 183);
+    pub fn variant(&self) -> Variant {
+        self.variant
     }
 
-    #[test]
-    fn bcd() {
-        let mut cpu = Cpu::new();
+    /* Ops the variant lacks fail with UnimplementedOp from then on. Resets
+       the quirks to the variant's; set_quirks afterwards to change them.
+       RAM grows or shrinks to the variant's size, and a variant without
+       hires drops back to lo-res with a blank screen. */
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+        self.quirks = variant.quirks();
+        self.ram.resize(variant.ram_bytes(), 0xff);
+        if self.hires && !variant.has_hires() {
+            self.hires = false;
+            self.vram = [false; Self::HIRES_VRAM_BYTES];
+            if self.display_driver.is_some() {
+                let _ = self.refresh_display();
+            }
+        }
+    }
 
-        cpu.exec(Op::Ld(Reg(0), 135)).unwrap();
-        cpu.exec(Op::Ldi(0x400)).unwrap();
-        cpu.exec(Op::Bcd(Reg(0))).unwrap();
-        cpu.exec(Op::Read(Reg(2))).unwrap();
+    /* How much RAM the current variant has. */
+    pub fn ram_bytes(&self) -> usize {
+        self.ram.size()
+    }
 
-        assert_eq!(cpu.v[0], 1);
-        assert_eq!(cpu.v[1], 3);
-        assert_eq!(cpu.v[2], 5);
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
     }
 
-    #[test]
-    fn draw() {
-        let mut cpu = Cpu::new();
+    pub fn stack_depth(&self) -> usize {
+        self.stack.len()
+    }
+
+    /* Sets how many calls can be nested before Call fails with
+       StackOverflow, between 1 and 255. Meant to be called right after
+       construction: the stack is emptied. */
+    pub fn set_stack_depth(&mut self, depth: usize) {
+        self.stack = vec![0x0000; depth.clamp(1, u8::MAX as usize)];
+        self.sp = 0;
+    }
+
+    pub fn set_skip_unknown_opcodes(&mut self, skip: bool) {
+        self.skip_unknown_opcodes = skip;
+    }
+
+    pub fn program_counter(&self) -> u16 {
+        self.pc
+    }
+
+    /* The return addresses currently pushed, oldest first. */
+    pub fn stack(&self) -> &[u16] {
+        &self.stack[..self.sp as usize]
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.timer.dt.load(ORDERING)
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.timer.st.load(ORDERING)
+    }
+
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    pub fn registers(&self) -> &[u8] {
+        &self.v
+    }
+
+    pub fn set_registers(&mut self, regs: [u8; Self::REG_COUNT]) {
+        self.v = regs;
+    }
+
+    pub fn registers_mut(&mut self) -> &mut [u8; Self::REG_COUNT] {
+        &mut self.v
+    }
+
+    /* The built-in 4x5 sprite for a hex digit, one row per byte. */
+    pub fn font_glyph(digit: u8) -> &'static [u8] {
+        let start = (digit & 0xf) as usize * Self::FONT_SPRITE_BYTES_PER;
+        &Self::FONT_SPRITES[start..start + Self::FONT_SPRITE_BYTES_PER]
+    }
+
+    /* Hundreds, tens and ones digits of v, as stored by Bcd. */
+    pub fn bcd(v: u8) -> [u8; 3] {
+        [v / 100, v / 10 % 10, v % 10]
+    }
+
+    pub fn font_address(&self) -> u16 {
+        self.font_addr
+    }
+
+    /* Moves the built-in font, which Ldspr points into, to addr. Some ROMs
+       expect it at 0x050 instead of the usual 0x000. The font has to fit
+       below LOAD_OFFSET, where loading a ROM can't overwrite it. */
+    pub fn set_font_address(&mut self, addr: u16) -> Result<()> {
+        if addr as usize + Self::FONT_SPRITES_BYTES > Self::LOAD_OFFSET {
+            return Err(Error::DataAbort);
+        }
+        let old = self.font_addr as usize;
+        self.font_addr = addr;
+        self.move_font(old);
+        Ok(())
+    }
+
+    /* Zeroes the font at old and copies a fresh one to font_addr. */
+    fn move_font(&mut self, old: usize) {
+        for byte in self.ram.slice_mut(old, Self::FONT_SPRITES_BYTES).unwrap() {
+            *byte = 0x00;
+        }
+        self.ram.slice_mut(self.font_addr as usize, Self::FONT_SPRITES_BYTES)
+            .unwrap()
+            .copy_from_slice(&Self::FONT_SPRITES);
+    }
+
+    /* Debug aid: Bcd and Str writes that would land in the font sprites are
+       dropped with a ProtectedWrite error instead. */
+    pub fn set_protect_font(&mut self, protect: bool) {
+        self.protect_font = protect;
+    }
+
+    /* With double buffering, display changes are held back and handed to
+       the display driver once per run_frame, or whenever swap_buffers is
+       called, so it never sees a frame that is only partly drawn. Turning
+       it off flushes anything still held back. */
+    pub fn set_double_buffer(&mut self, enabled: bool) -> Result<()> {
+        self.double_buffer = enabled;
+        if enabled {
+            Ok(())
+        } else {
+            self.swap_buffers()
+        }
+    }
+
+    /* Shows the frame drawn since the last swap, if anything changed. */
+    pub fn swap_buffers(&mut self) -> Result<()> {
+        if self.frame_pending {
+            self.frame_pending = false;
+            self.present()
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn read_memory(&self, addr: usize, len: usize) -> Result<&[u8]> {
+        self.ram.slice(addr, len)
+    }
+
+    /* Every word from LOAD_OFFSET up, decoded as RAM holds it right now,
+       so self-modified code shows up as it will run. */
+    pub fn instructions(&self) -> impl Iterator<Item = (u16, Option<Op>)> + '_ {
+        self.ram.slice(Self::LOAD_OFFSET, self.ram_bytes() - Self::LOAD_OFFSET)
+            .unwrap()
+            .chunks_exact(2)
+            .enumerate()
+            .map(|(n, word)| {
+                let addr = (Self::LOAD_OFFSET + n * 2) as u16;
+                (addr, Op::decode((word[0] as u16) << 8 | word[1] as u16))
+            })
+    }
+
+    /* Every op in RAM from LOAD_OFFSET up that a ROM for variant shouldn't
+       contain. Data decodes as ops too, so treat these as warnings. */
+    pub fn lint_rom(&self, variant: Variant) -> Vec<(u16, Op, LintIssue)> {
+        self.instructions()
+            .filter_map(|(addr, op)| {
+                let op = op?;
+                op.lint(variant).map(|issue| (addr, op, issue))
+            })
+            .collect()
+    }
+
+    pub fn index_register(&self) -> u16 {
+        self.i
+    }
+
+    pub fn set_index(&mut self, i: u16) -> Result<()> {
+        if i as usize >= self.ram_bytes() {
+            Err(Error::DataAbort)
+        } else {
+            self.i = i;
+            Ok(())
+        }
+    }
+
+    pub fn state(&self) -> RunState {
+        self.state
+    }
+
+    pub fn tick(&mut self) -> Result<()> {
+        let result = self.fetch().and_then(|opcode| {
+            self.highest_pc = self.highest_pc.max(self.pc);
+            match Op::decode(opcode) {
+                Some(op) if self.detect_halt_loops && self.is_halt_loop(&op) => {
+                    Err(Error::HaltLoop(self.pc))
+                },
+                Some(op) if self.trace_hook.is_some() => {
+                    let pc = self.pc;
+                    let result = self.exec(op.clone());
+                    let error = result.clone().err();
+                    let entry = TraceEntry { pc, opcode, op, regs: self.v, error };
+                    if let Some(hook) = &mut self.trace_hook {
+                        hook(&entry);
+                    }
+                    result
+                },
+                Some(op) => self.exec(op),
+                None if self.skip_unknown_opcodes => {
+                    /* Treat junk words as no-ops for ROMs that contain them. */
+                    self.pc = self.pc.wrapping_add(2);
+                    Ok(())
+                },
+                None => Err(Error::BadInstruction),
+            }
+        });
+
+        if let Err(ref e) = result {
+            if e.fatal() {
+                self.state = RunState::Halted;
+            }
+        }
+
+        result
+    }
+
+    /* tick, reporting what the instruction changed. A word skipped over
+       as an unknown opcode has no op to report, so it comes back as
+       BadInstruction even though pc moved past it. */
+    pub fn step_detailed(&mut self) -> Result<StepResult> {
+        let pc_before = self.pc;
+        let v = self.v;
+        let op = self.current_op();
+
+        self.tick()?;
+        let op = op.ok_or(Error::BadInstruction)?;
+
+        let changed_regs = v.iter()
+            .zip(self.v.iter())
+            .enumerate()
+            .filter(|(_, (old, new))| old != new)
+            .map(|(n, (_, &new))| (n, new))
+            .collect();
+
+        Ok(StepResult {
+            drew: op.category() == OpCategory::Display,
+            op,
+            pc_before,
+            pc_after: self.pc,
+            changed_regs,
+        })
+    }
+
+    /* With a seed, Rand draws from a generator seeded with it instead of
+       the thread RNG, so runs can be replayed. None goes back to the
+       thread RNG. reset rewinds the generator to the seed. */
+    pub fn set_rng_seed(&mut self, seed: Option<u64>) {
+        self.rng_seed = seed;
+        self.rewind_rng();
+    }
+
+    /* Rand instructions run since the seed was set or last rewound. */
+    pub fn rng_call_count(&self) -> u64 {
+        self.rng_calls
+    }
+
+    /* Rewinds the seeded generator and skips ahead calls draws, so the next
+       Rand gets the same value the (calls + 1)th did on the first run. */
+    pub fn fast_forward_rng(&mut self, calls: u64) {
+        self.rewind_rng();
+        for _ in 0..calls {
+            self.random_byte();
+        }
+    }
+
+    fn rewind_rng(&mut self) {
+        self.rng = self.rng_seed.map(StdRng::seed_from_u64);
+        self.rng_calls = 0;
+    }
+
+    fn random_byte(&mut self) -> u8 {
+        self.rng_calls += 1;
+        match &mut self.rng {
+            Some(rng) => rng.gen(),
+            None => rand::random(),
+        }
+    }
+
+    pub fn set_stop_on_nonfatal(&mut self, stop: bool) {
+        self.stop_on_nonfatal = stop;
+    }
+
+    /* Many programs finish by jumping to themselves forever. With this set,
+       tick reports that as a non-fatal HaltLoop instead of running it, so
+       a frontend can tell the program has ended. */
+    pub fn set_halt_loop_detection(&mut self, enabled: bool) {
+        self.detect_halt_loops = enabled;
+    }
+
+    fn is_halt_loop(&self, op: &Op) -> bool {
+        match *op {
+            Op::Jmp(addr) => addr == self.pc,
+            Op::Jmpi(addr) => addr + self.v[Self::INDEX_REG] as u16 == self.pc,
+            _ => false,
+        }
+    }
+
+    /* Enabling starts a fresh report; disabling discards it. */
+    pub fn set_instrumentation(&mut self, enabled: bool) {
+        self.timings = if enabled { Some(HashMap::new()) } else { None };
+    }
+
+    pub fn timing_report(&self) -> HashMap<OpCategory, Duration> {
+        self.timings.clone().unwrap_or_default()
+    }
+
+    /* Records every RAM write made by Bcd and Str until disabled. */
+    pub fn set_mem_write_trace(&mut self, enabled: bool) {
+        self.mem_writes = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    pub fn take_mem_writes(&mut self) -> Vec<MemWrite> {
+        self.mem_writes.as_mut().map(std::mem::take).unwrap_or_default()
+    }
+
+    /* Called after every instruction tick decodes and runs, until set to
+       None. An instruction that faults is still traced, with the error in
+       the entry. */
+    pub fn set_trace_hook(&mut self, hook: Option<TraceHook>) {
+        self.trace_hook = hook;
+    }
+
+    /* Called for each Bcd and Str write that lands on code that has
+       already run, anywhere from LOAD_OFFSET up to the highest instruction
+       executed so far. None turns the check off. */
+    pub fn on_self_mod_write(&mut self, callback: Option<SelfModHook>) {
+        self.self_mod_hook = callback;
+    }
+
+    /* While enabled, each exec keeps what it changed so that undo_last can
+       step back over it. Only the most recent instruction is kept. */
+    pub fn set_undo_journal(&mut self, enabled: bool) {
+        self.undo_journal = enabled;
+        self.last_undo = None;
+    }
+
+    /* Reverts the last instruction executed with the journal on. Returns
+       whether there was anything to undo; a second call in a row has no
+       effect. */
+    pub fn undo_last(&mut self) -> Result<bool> {
+        let undo = match self.last_undo.take() {
+            Some(undo) => undo,
+            None => return Ok(false),
+        };
+
+        self.pc = undo.pc;
+        self.sp = undo.sp;
+        self.i = undo.i;
+        self.v = undo.v;
+        self.stack = undo.stack;
+        self.timer.dt.store(undo.dt, ORDERING);
+        self.timer.st.store(undo.st, ORDERING);
+        self.state = undo.state;
+        for &(addr, old) in undo.ram.iter().rev() {
+            self.ram.write(addr, old)?;
+        }
+
+        let redraw = self.hires != undo.hires || !undo.vram.is_empty();
+        self.hires = undo.hires;
+        for &(n, old) in &undo.vram {
+            self.vram[n] = old;
+        }
+        if redraw && self.display_driver.is_some() {
+            self.refresh_display()?;
+        }
+        Ok(true)
+    }
+
+    /* Ticks up to n times and returns how many instructions completed along
+       with the error that ended the run early, if any. */
+    pub fn tick_n(&mut self, n: usize) -> (usize, Result<()>) {
+        for count in 0..n {
+            match self.tick() {
+                Err(e) if e.fatal() || self.stop_on_nonfatal => return (count, Err(e)),
+                _ => (),
+            }
+        }
+        (n, Ok(()))
+    }
+
+    pub fn run_frame(&mut self, cycles: usize) -> Result<()> {
+        if cycles > self.max_frame_instructions {
+            self.frame_capped = true;
+        }
+        let result = self.run_cycles(cycles.min(self.max_frame_instructions));
+        self.swap_buffers()?;
+        self.publish_state();
+        result
+    }
+
+    /* A safety net against a misconfigured cycles per frame keeping
+       run_frame from ever returning. */
+    pub fn set_max_frame_instructions(&mut self, max: usize) {
+        self.max_frame_instructions = max;
+        self.frame_capped = false;
+    }
+
+    pub fn max_frame_instructions(&self) -> usize {
+        self.max_frame_instructions
+    }
+
+    /* Whether run_frame has been asked for more cycles than the cap, and
+       run fewer, since the last reset or change to the cap. */
+    pub fn frame_capped(&self) -> bool {
+        self.frame_capped
+    }
+
+    fn run_cycles(&mut self, cycles: usize) -> Result<()> {
+        for _ in 0..cycles {
+            match self.tick() {
+                Err(e) if e.fatal() || self.stop_on_nonfatal => return Err(e),
+                _ => (),
+            }
+            if self.state == RunState::WaitingForKey {
+                /* Hand control back to the host rather than spinning on the
+                   same Key instruction for the rest of the frame. */
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /* State other threads can poll. Cpu itself can't be shared since its
+       drivers aren't Send, so instead a Snapshot is published at the end of
+       every run_frame and whenever publish_state is called. */
+    pub fn shared_state(&mut self) -> Arc<SharedState> {
+        if self.shared.is_none() {
+            self.shared = Some(Arc::new(SharedState {
+                latest: Mutex::new(Arc::new(self.snapshot())),
+            }));
+        }
+        Arc::clone(self.shared.as_ref().unwrap())
+    }
+
+    pub fn publish_state(&self) {
+        if let Some(shared) = &self.shared {
+            let snapshot = Arc::new(self.snapshot());
+            *shared.latest.lock().unwrap() = snapshot;
+        }
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let (width, height) = self.display_dimensions();
+        Snapshot {
+            pc: self.pc,
+            i: self.i,
+            v: self.v,
+            hires: self.hires,
+            vram: self.vram[..width * height].to_vec(),
+        }
+    }
+
+    /* Runs at most max_instructions, stopping early to wait for a key, and
+       returns how many actually ran. Errors end the run as in run_frame. */
+    pub fn run_budget(&mut self, max_instructions: u32) -> Result<u32> {
+        for count in 0..max_instructions {
+            match self.tick() {
+                Err(e) if e.fatal() || self.stop_on_nonfatal => return Err(e),
+                _ => (),
+            }
+            if self.state == RunState::WaitingForKey {
+                return Ok(count);
+            }
+        }
+        Ok(max_instructions)
+    }
+
+    /* Loads rom and runs it at roughly 500Hz until should_continue returns
+       false or a fatal error occurs. Non-fatal errors are handed to
+       on_error and skipped over. */
+    pub fn load_and_run(&mut self, rom: &[u8], should_continue: impl Fn() -> bool,
+                        mut on_error: impl FnMut(&Error)) -> Result<()> {
+        self.load(rom)?;
+
+        while should_continue() {
+            match self.run_frame(Self::RUN_CYCLES_PER_FRAME) {
+                Err(e) if e.fatal() => return Err(e),
+                Err(e) => on_error(&e),
+                Ok(()) => (),
+            }
+            thread::sleep(Self::RUN_FRAME_TIME);
+        }
+        Ok(())
+    }
+
+    /* Reports the vram offsets a Draw would clear given the current I and
+       V registers, without touching vram or VF. */
+    pub fn test_draw(&self, x: Reg, y: Reg, m: u8) -> Result<Vec<usize>> {
+        let (Reg(x), Reg(y)) = (x, y);
+        let (width, height) = self.display_dimensions();
+        let sprite = self.ram.slice(self.i as usize, m as usize)?;
+        let screen = VramView::new(&self.vram[..], width, height, Edge::Wrap);
+        let mut collisions = Vec::new();
+        for (n, spr_byte) in sprite.iter().enumerate() {
+            for h in (0..8).filter(|h| spr_byte & (0x80 >> h) != 0) {
+                let (px, py) = (self.v[x] as usize + h, self.v[y] as usize + n);
+                if screen.get(px, py) {
+                    collisions.extend(screen.index(px, py));
+                }
+            }
+        }
+        Ok(collisions)
+    }
+
+    /* Width and height of the active screen, which changes with 00FE and
+       00FF. */
+    pub fn display_dimensions(&self) -> (usize, usize) {
+        if self.hires {
+            (Self::HIRES_DISPLAY_WIDTH, Self::HIRES_DISPLAY_HEIGHT)
+        } else {
+            (Self::DISPLAY_WIDTH, Self::DISPLAY_HEIGHT)
+        }
+    }
+
+    /* Direct vram access in display coordinates. Pixels off the current
+       screen read as unset and ignore writes. */
+    pub fn get_pixel(&self, x: usize, y: usize) -> bool {
+        let (width, height) = self.display_dimensions();
+        VramView::new(&self.vram[..], width, height, Edge::Clip).get(x, y)
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, on: bool) {
+        let (width, height) = self.display_dimensions();
+        VramView::new(&mut self.vram[..], width, height, Edge::Clip).set(x, y, on);
+    }
+
+    /* Where control goes after the instruction at pc, without running it.
+       Skips and Key depend on state that isn't known until they execute, so
+       for those this is always the next instruction. */
+    pub fn next_pc(&self) -> Result<u16> {
+        let op = match Op::decode(self.fetch()?) {
+            Some(op) => op,
+            None if self.skip_unknown_opcodes => return Ok(self.pc.wrapping_add(2)),
+            None => return Err(Error::BadInstruction),
+        };
+
+        match op {
+            Op::Jmp(addr) | Op::Call(addr) => self.jump_target(addr),
+            Op::Jmpi(addr) => self.jump_target(addr + self.v[Self::INDEX_REG] as u16),
+            Op::Ret if self.sp == 0 => Err(Error::StackUnderflow),
+            Op::Ret => Ok(self.stack[self.sp as usize - 1]),
+            _ => Ok(self.pc.wrapping_add(2)),
+        }
+    }
+
+    pub fn fetch(&self) -> Result<u16> {
+        self.fetch_at(self.pc)
+    }
+
+    pub fn fetch_at(&self, addr: u16) -> Result<u16> {
+        self.ram.read_u16(addr as usize).map_err(|_| Error::PrefetchAbort)
+    }
+
+    /* The instruction about to run, for display. Neither advances pc. */
+    pub fn current_opcode(&self) -> Result<u16> {
+        self.fetch()
+    }
+
+    pub fn current_op(&self) -> Option<Op> {
+        self.current_opcode().ok().and_then(Op::decode)
+    }
+
+    pub fn exec(&mut self, op: Op) -> Result<()> {
+        if !self.undo_journal {
+            return self.exec_timed(op);
+        }
+
+        let vram = self.vram;
+        self.last_undo = Some(Undo {
+            pc: self.pc,
+            sp: self.sp,
+            i: self.i,
+            v: self.v,
+            stack: self.stack.clone(),
+            dt: self.timer.dt.load(ORDERING),
+            st: self.timer.st.load(ORDERING),
+            hires: self.hires,
+            state: self.state,
+            ram: Vec::new(),
+            vram: Vec::new(),
+        });
+        let result = self.exec_timed(op);
+        if let Some(undo) = &mut self.last_undo {
+            undo.vram = vram.iter()
+                .zip(self.vram.iter())
+                .enumerate()
+                .filter(|(_, (old, new))| old != new)
+                .map(|(n, (&old, _))| (n, old))
+                .collect();
+        }
+        result
+    }
+
+    fn exec_timed(&mut self, op: Op) -> Result<()> {
+        if self.timings.is_none() {
+            return self.exec_op(op);
+        }
+
+        let category = op.category();
+        let start = Instant::now();
+        let result = self.exec_op(op);
+        if let Some(timings) = &mut self.timings {
+            *timings.entry(category).or_default() += start.elapsed();
+        }
+        result
+    }
+
+    /* Runs ops in order, stopping at the first error. */
+    pub fn exec_all(&mut self, ops: &[Op]) -> Result<()> {
+        for op in ops {
+            self.exec(op.clone())?;
+        }
+        Ok(())
+    }
+
+    fn exec_op(&mut self, op: Op) -> Result<()> {
+        /* decode only ever produces register numbers from a single nibble. */
+        debug_assert!(op.regs().iter().all(|&r| r <= Self::MAX_REG),
+                      "register out of range in {:?}", op);
+
+        /* Only XO-CHIP has RAM up at 0xfffe. Running past it wraps pc back
+           to 0, the same as a 16 bit address bus would. */
+        self.pc = self.pc.wrapping_add(2);
+
+        if !self.variant.supports(&op) {
+            return Err(Error::UnimplementedOp(op));
+        }
+
+        match op {
+            Op::Sys(_) => Err(Error::UnimplementedOp(op)),
+            Op::Cls => {
+                for elem in self.vram.iter_mut() {
+                    *elem = false;
+                }
+                self.refresh_display()
+            },
+            Op::Scd(n) => {
+                let n = self.scroll_amount(n);
+                self.scroll(0, n);
+                self.refresh_display()
+            },
+            Op::Scr => {
+                let n = self.scroll_amount(Self::SCROLL_SIDEWAYS_PX);
+                self.scroll(n, 0);
+                self.refresh_display()
+            },
+            Op::Scl => {
+                let n = self.scroll_amount(Self::SCROLL_SIDEWAYS_PX);
+                self.scroll(-n, 0);
+                self.refresh_display()
+            },
+            Op::Low | Op::High => {
+                /* The vram layout depends on the mode, so start over with a
+                   blank screen rather than showing garbage. */
+                self.hires = op == Op::High;
+                for elem in self.vram.iter_mut() {
+                    *elem = false;
+                }
+                self.refresh_display()
+            },
+            Op::Ret => {
+                if self.sp == 0 {
+                    Err(Error::StackUnderflow)
+                } else {
+                    self.sp -= 1;
+                    self.pc = self.stack[self.sp as usize];
+                    Ok(())
+                }
+            },
+            Op::Jmp(addr) => {
+                self.pc = self.jump_target(addr)?;
+                Ok(())
+            },
+            Op::Call(addr) => {
+                let target = self.jump_target(addr)?;
+                if self.sp as usize >= self.stack.len() {
+                    Err(Error::StackOverflow)
+                } else {
+                    self.stack[self.sp as usize] = self.pc;
+                    self.sp += 1;
+                    self.pc = target;
+                    Ok(())
+                }
+            },
+            Op::Se(Reg(x), kk) => {
+                if self.v[x] == kk {
+                    self.pc = self.pc.wrapping_add(2);
+                }
+                Ok(())
+            },
+            Op::Sne(Reg(x), kk) => {
+                if self.v[x] != kk {
+                    self.pc = self.pc.wrapping_add(2);
+                }
+                Ok(())
+            },
+            Op::Sre(Reg(x), Reg(y)) => {
+                if self.v[x] == self.v[y] {
+                    self.pc = self.pc.wrapping_add(2);
+                }
+                Ok(())
+            },
+            Op::Ld(Reg(x), kk) => {
+                self.v[x] = kk;
+                Ok(())
+            },
+            Op::Add(Reg(x), kk) => {
+                /* per spec, carry flag intentionally not changed unless the
+                   non-standard add_imm_sets_vf quirk asks for it */
+                let (val, carry) = self.v[x].overflowing_add(kk);
+                self.v[x] = val;
+                if self.quirks.add_imm_sets_vf {
+                    self.v[Self::FLAG_REG] = carry as u8;
+                }
+                Ok(())
+            },
+            Op::Mov(Reg(x), Reg(y)) => {
+                self.v[x] = self.v[y];
+                Ok(())
+            },
+            Op::Or(Reg(x), Reg(y)) => {
+                self.v[x] |= self.v[y];
+                Ok(())
+            },
+            Op::And(Reg(x), Reg(y)) => {
+                self.v[x] &= self.v[y];
+                Ok(())
+            },
+            Op::Xor(Reg(x), Reg(y)) => {
+                self.v[x] ^= self.v[y];
+                Ok(())
+            },
+            Op::Addr(Reg(x), Reg(y)) => {
+                let (val, carry) = self.v[x].overflowing_add(self.v[y]);
+                self.v[x] = val;
+                self.v[Self::FLAG_REG] = carry as u8;
+                Ok(())
+            },
+            /* VF is NOT borrow for both subtractions: 1 when the result
+               didn't wrap, including when the operands are equal. */
+            Op::Subr(Reg(x), Reg(y)) => {
+                let (val, carry) = self.v[x].overflowing_sub(self.v[y]);
+                self.v[x] = val;
+                self.v[Self::FLAG_REG] = !carry as u8;
+                Ok(())
+            },
+            Op::Shr(Reg(x), Reg(y)) => {
+                let src = self.v[if self.quirks.shift_uses_vy { y } else { x }];
+                self.v[x] = src >> 1;
+                self.v[Self::FLAG_REG] = src & 0x01;
+                Ok(())
+            },
+            Op::Subnr(Reg(x), Reg(y)) => {
+                let (val, carry) = self.v[y].overflowing_sub(self.v[x]);
+                self.v[x] = val;
+                self.v[Self::FLAG_REG] = !carry as u8;
+                Ok(())
+            },
+            Op::Shl(Reg(x), Reg(y)) => {
+                let src = self.v[if self.quirks.shift_uses_vy { y } else { x }];
+                self.v[x] = src << 1;
+                self.v[Self::FLAG_REG] = src >> 7;
+                Ok(())
+            },
+            Op::Srne(Reg(x), Reg(y)) => {
+                if self.v[x] != self.v[y] {
+                    self.pc = self.pc.wrapping_add(2);
+                }
+                Ok(())
+            },
+            Op::Ldi(addr) => {
+                self.set_i(addr as u32)?;
+                Ok(())
+            },
+            Op::Jmpi(addr) => {
+                self.pc = self.jump_target(addr + (self.v[Self::INDEX_REG] as u16))?;
+                Ok(())
+            },
+            Op::Rand(Reg(x), kk) => {
+                self.v[x] = self.random_byte() & kk;
+                Ok(())
+            },
+            Op::Draw(Reg(x), Reg(y), m) => {
+                let (width, height) = self.display_dimensions();
+                let sprite = self.ram.slice(self.i as usize, m as usize)?;
+                /* The origin always wraps onto the screen; the edge only
+                   decides what happens to the rest of the sprite. */
+                let (x0, y0) = (self.v[x] as usize % width, self.v[y] as usize % height);
+                let edge = if self.quirks.clip_sprites { Edge::Clip } else { Edge::Wrap };
+                let mut screen = VramView::new(&mut self.vram[..], width, height, edge);
+                let mut did_clear = false;
+                for (n, spr_byte) in sprite.iter().enumerate() {
+                    for h in (0..8).filter(|h| spr_byte & (0x80 >> h) != 0) {
+                        did_clear |= screen.flip(x0 + h, y0 + n);
+                    }
+                }
+
+                /* Written once at the end, so VF is always fresh (even for
+                   a zero row sprite) and a sprite drawn at VF still uses
+                   the coordinate it started with. */
+                self.v[Self::FLAG_REG] = did_clear as u8;
+                self.refresh_display()
+            },
+            Op::Skp(Reg(x)) => {
+                if let Some(pressed) = self.poll_key(self.v[x]) {
+                    if pressed {
+                        self.pc = self.pc.wrapping_add(2);
+                    }
+                    Ok(())
+                } else {
+                    Err(Error::DriverMissing)
+                }
+            },
+            Op::Sknp(Reg(x)) => {
+                if let Some(pressed) = self.poll_key(self.v[x]) {
+                    if !pressed {
+                        self.pc = self.pc.wrapping_add(2);
+                    }
+                    Ok(())
+                } else {
+                    /* Assume that no input driver means no key press, ever. */
+                    self.pc = self.pc.wrapping_add(2);
+                    Err(Error::DriverMissing)
+                }
+            },
+            Op::Movd(Reg(x)) => {
+                self.v[x] = self.timer.dt.load(ORDERING);
+                Ok(())
+            },
+            Op::Key(Reg(x)) => {
+                if let Some(input_driver) = &self.input_driver {
+                    /* A cancelled wait leaves VX alone and keeps waiting, so
+                       the host can decide what to do with the stopped ROM. */
+                    let key = input_driver.try_block().filter(|&k| k != driver::KEY_CANCELLED);
+                    if let Some(key) = key {
+                        self.v[x] = key;
+                        self.state = RunState::Running;
+                    } else {
+                        /* No key yet. Rewind so that this instruction is
+                           issued again on the next tick. */
+                        self.pc -= 2;
+                        self.state = RunState::WaitingForKey;
+                    }
+                    Ok(())
+                } else {
+                    Err(Error::DriverMissing)
+                }
+            },
+            Op::Ldd(Reg(x)) => {
+                self.timer.dt.store(self.v[x], ORDERING);
+                Ok(())
+            },
+            Op::Lds(Reg(x)) => {
+                self.timer.st.store(self.v[x], ORDERING);
+                Ok(())
+            },
+            Op::Addi(Reg(x)) => self.set_i(self.i as u32 + self.v[x] as u32),
+            Op::Ldspr(Reg(x)) => {
+                self.i = self.font_addr +
+                         Self::FONT_SPRITE_BYTES_PER as u16 *
+                         self.v[x] as u16;
+                Ok(())
+            },
+            Op::Bcd(Reg(x)) => {
+                self.check_font_write(self.i as usize, 3)?;
+                self.write_ram(self.i as usize, &Self::bcd(self.v[x]))
+            },
+            Op::Str(Reg(x)) => {
+                let i = self.i as usize;
+                let j = i + x;
+                if self.transfer_in_bounds(j) {
+                    self.check_font_write(i, x + 1)?;
+                    let v = self.v;
+                    self.write_ram(i, &v[..=x])?;
+                    self.increment_after_transfer(j)
+                } else {
+                    Err(Error::DataAbort)
+                }
+            },
+            Op::Read(Reg(x)) => {
+                let i = self.i as usize;
+                let j = i + x;
+                if self.transfer_in_bounds(j) {
+                    self.v[..=x].copy_from_slice(self.ram.slice(i, x + 1)?);
+                    self.increment_after_transfer(j)
+                } else {
+                    Err(Error::DataAbort)
+                }
+            },
+        }
+    }
+
+    fn refresh_display(&mut self) -> Result<()> {
+        if self.display_driver.is_none() {
+            Err(Error::DriverMissing)
+        } else if self.double_buffer {
+            self.frame_pending = true;
+            Ok(())
+        } else {
+            self.present()
+        }
+    }
+
+    fn present(&mut self) -> Result<()> {
+        let (width, height) = self.display_dimensions();
+        if let Some(display_driver) = &mut self.display_driver {
+            display_driver.refresh(&self.vram[..width * height]);
+            Ok(())
+        } else {
+            Err(Error::DriverMissing)
+        }
+    }
+
+    /* Scroll amounts are given in hi-res pixels. In lo-res each step is half
+       a pixel, rounded according to the lores_scroll_rounds_up quirk. */
+    fn scroll_amount(&self, n: u8) -> isize {
+        if self.hires {
+            n as isize
+        } else if self.quirks.lores_scroll_rounds_up {
+            n.div_ceil(2) as isize
+        } else {
+            (n / 2) as isize
+        }
+    }
+
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = self.display_dimensions();
+        let old = self.vram[..width * height].to_vec();
+        for y in 0..height {
+            for x in 0..width {
+                let sx = x as isize - dx;
+                let sy = y as isize - dy;
+                let in_bounds = sx >= 0 && sy >= 0 &&
+                                (sx as usize) < width && (sy as usize) < height;
+                self.vram[y * width + x] =
+                    in_bounds && old[sy as usize * width + sx as usize];
+            }
+        }
+    }
+
+    fn write_ram(&mut self, addr: usize, bytes: &[u8]) -> Result<()> {
+        let dest = self.ram.slice_mut(addr, bytes.len())?;
+        /* pc has already moved past the instruction doing the write. */
+        let pc = self.pc.wrapping_sub(2);
+        let writes = dest.iter().zip(bytes).enumerate().map(|(n, (&old, &new))| {
+            MemWrite { pc, addr: (addr + n) as u16, old, new }
+        });
+        if let Some(trace) = &mut self.mem_writes {
+            trace.extend(writes.clone());
+        }
+        if let Some(undo) = &mut self.last_undo {
+            undo.ram.extend(writes.clone().map(|w| (w.addr as usize, w.old)));
+        }
+        if let Some(hook) = &mut self.self_mod_hook {
+            /* Both bytes of the highest instruction count as code. */
+            let code_end = self.highest_pc as usize + 1;
+            writes
+                .filter(|w| (Self::LOAD_OFFSET..=code_end).contains(&(w.addr as usize)))
+                .for_each(|w| hook(&w));
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /* I spans the variant's RAM: 12 bits, or all 16 on XO-CHIP. */
+    fn set_i(&mut self, i: u32) -> Result<()> {
+        let max = self.ram_bytes() as u32 - 1;
+        if i > max && self.quirks.strict_index {
+            return Err(Error::IndexOverflow(i));
+        }
+        self.i = (i & max) as u16;
+        Ok(())
+    }
+
+    fn increment_after_transfer(&mut self, last: usize) -> Result<()> {
+        if self.quirks.memory_increments_i {
+            self.set_i(last as u32 + 1)
+        } else {
+            Ok(())
+        }
+    }
+
+    /* Checked here rather than left to the next fetch, so the error points
+       at the jump that went wrong. */
+    fn jump_target(&self, addr: u16) -> Result<u16> {
+        if self.quirks.wrap_jumps {
+            Ok((addr as usize % self.ram_bytes()) as u16)
+        } else if addr as usize + 1 < self.ram_bytes() {
+            Ok(addr)
+        } else {
+            Err(Error::BadJump(addr))
+        }
+    }
+
+    fn check_font_write(&self, addr: usize, len: usize) -> Result<()> {
+        let font = self.font_addr as usize;
+        if self.protect_font &&
+                addr < font + Self::FONT_SPRITES_BYTES &&
+                addr + len > font {
+            Err(Error::ProtectedWrite)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn transfer_in_bounds(&self, last: usize) -> bool {
+        last < self.ram_bytes() &&
+            (!self.quirks.strict_address_space || last <= Self::ADDRESS_SPACE_END)
+    }
+}
+
+impl Default for Cpu {
+    fn default() -> Self {
+        Self::new(Variant::Schip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+    use super::super::test_support::{CaptureDisplay, assert_screen_eq, rom};
+
+    struct ScriptedInput {
+        keys: Rc<RefCell<VecDeque<Option<u8>>>>,
+    }
+
+    impl driver::Input for ScriptedInput {
+        fn poll(&self, _key: u8) -> bool {
+            false
+        }
+
+        fn block(&self) -> u8 {
+            unimplemented!()
+        }
+
+        fn try_block(&self) -> Option<u8> {
+            self.keys.borrow_mut().pop_front().unwrap_or(None)
+        }
+    }
+
+    struct HeldKey(u8);
+
+    impl driver::Input for HeldKey {
+        fn poll(&self, key: u8) -> bool {
+            key == self.0
+        }
+
+        fn block(&self) -> u8 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn key_pressed() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        assert!(!cpu.key_pressed(0x5));
+
+        cpu.set_input_driver(Some(Box::new(HeldKey(0x5))));
+        assert!(cpu.key_pressed(0x5));
+        assert!(!cpu.key_pressed(0x6));
+    }
+
+    #[test]
+    fn atomic() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        cpu.exec(Op::Ld(Reg(0), 200)).unwrap();
+        cpu.exec(Op::Ldd(Reg(0))).unwrap();
+        cpu.exec(Op::Movd(Reg(0))).unwrap();
+
+        assert_eq!(cpu.v[0], 200);
+        thread::sleep(Duration::from_millis(500));
+
+        cpu.exec(Op::Movd(Reg(0))).unwrap();
+        assert!(cpu.v[0] < 175 && cpu.v[0] > 165);
+
+        cpu.exec(Op::Ld(Reg(0), 200)).unwrap();
+        cpu.exec(Op::Lds(Reg(0))).unwrap();
+        thread::sleep(Duration::from_millis(250));
+
+        let ds = cpu.timer.st.load(ORDERING);
+        assert!(ds < 187 && ds > 183);
+    }
+
+    #[test]
+    fn bcd() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        cpu.exec(Op::Ld(Reg(0), 135)).unwrap();
+        cpu.exec(Op::Ldi(0x400)).unwrap();
+        cpu.exec(Op::Bcd(Reg(0))).unwrap();
+        cpu.exec(Op::Read(Reg(2))).unwrap();
+
+        assert_eq!(cpu.v[0], 1);
+        assert_eq!(cpu.v[1], 3);
+        assert_eq!(cpu.v[2], 5);
+
+        for v in 0..=u8::MAX {
+            assert_eq!(Cpu::bcd(v), [v / 100, (v / 10) % 10, v % 10]);
+        }
+        assert_eq!(Cpu::bcd(255), [2, 5, 5]);
+
+        /* Digits that would run off the end of RAM are an error, not a
+           partial write. */
+        cpu.exec(Op::Ldi(Cpu::RAM_BYTES as u16 - 2)).unwrap();
+        assert_eq!(cpu.exec(Op::Bcd(Reg(0))), Err(Error::DataAbort));
+        assert_eq!(cpu.ram.read(Cpu::RAM_BYTES - 2), Ok(0xff));
+    }
+
+    #[test]
+    fn draw() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.set_quirks(Quirks::default());
+        let (display, frame) = CaptureDisplay::new();
+        cpu.set_display_driver(Some(Box::new(display)));
+
+        let sprite: [u8; 3] = [
+            0b11111111,
+            0b10000001,
+            0b11111111,
+        ];
+
+        cpu.exec(Op::Ld(Reg(0), sprite[0])).unwrap();
+        cpu.exec(Op::Ld(Reg(1), sprite[1])).unwrap();
+        cpu.exec(Op::Ld(Reg(2), sprite[2])).unwrap();
+        cpu.exec(Op::Ldi(0x400)).unwrap();
+        cpu.exec(Op::Str(Reg(2))).unwrap();
+        cpu.exec(Op::Ld(Reg(3), 0x15)).unwrap();
+        cpu.exec(Op::Ld(Reg(4), 0x05)).unwrap();
+        cpu.exec(Op::Draw(Reg(3), Reg(4), 3)).unwrap();
+
+        assert_screen_eq(&frame.borrow(), r#"
+            .
+            .
+            .
+            .
+            .
+            .....................########
+            .....................#......#
+            .....................########
+        "#);
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0x00);
+
+        /* Draw the same sprite again to clear it. */
+        cpu.exec(Op::Draw(Reg(3), Reg(4), 3)).unwrap();
+
+        assert_screen_eq(&frame.borrow(), "");
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0x01);
+
+        /* Sprites wrap around both edges of the screen. */
+        cpu.exec(Op::Ld(Reg(3), 60)).unwrap();
+        cpu.exec(Op::Ld(Reg(4), 30)).unwrap();
+        cpu.exec(Op::Draw(Reg(3), Reg(4), 3)).unwrap();
+
+        let mut expected = String::new();
+        expected.push_str("####........................................................####\n");
+        expected.push_str(&".\n".repeat(29));
+        expected.push_str("####........................................................####\n");
+        expected.push_str("...#........................................................#...\n");
+        assert_screen_eq(&frame.borrow(), &expected);
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0x00);
+    }
+
+    #[test]
+    fn load_and_tick() {
+        let program = rom![Ld(Reg(0), 0x12), Ld(Reg(1), 0x02), Addr(Reg(0), Reg(1))];
+        assert_eq!(program, [0x60, 0x12, 0x61, 0x02, 0x80, 0x14]);
+
+        let mut cpu = Cpu::new(Variant::Schip);
+        let lo = Cpu::LOAD_OFFSET as u16;
+        cpu.load(&program).unwrap();
+        assert_eq!(cpu.pc, lo);
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, lo + 2);
+        assert_eq!(cpu.v[0], 0x12);
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, lo + 4);
+        assert_eq!(cpu.v[1], 0x02);
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, lo + 6);
+        assert_eq!(cpu.v[0], 0x14);
+
+        assert_eq!(cpu.tick(), Err(Error::BadInstruction));
+        assert_eq!(cpu.pc, lo + 6);
+    }
+
+    #[test]
+    fn key_wait_run_frame() {
+        let program: [u8; 4] = [
+            0xf3,
+            0x0a, /* key r3 */
+            0x61,
+            0x02, /* ld r1, 0x02 */
+        ];
+
+        let keys = Rc::new(RefCell::new(VecDeque::from(vec![None, None, Some(0x5)])));
+        let mut cpu = Cpu::new(Variant::Schip);
+        let lo = Cpu::LOAD_OFFSET as u16;
+        cpu.load(&program).unwrap();
+        cpu.set_input_driver(Some(Box::new(ScriptedInput { keys: Rc::clone(&keys) })));
+
+        cpu.run_frame(10).unwrap();
+        assert_eq!(cpu.pc, lo);
+        assert_eq!(cpu.state(), RunState::WaitingForKey);
+
+        cpu.run_frame(10).unwrap();
+        assert_eq!(cpu.pc, lo);
+        assert_eq!(keys.borrow().len(), 1);
+
+        /* The third frame gets the key and runs on until the bad instruction
+           following the program. */
+        assert_eq!(cpu.run_frame(10), Err(Error::BadInstruction));
+        assert_eq!(cpu.state(), RunState::Halted);
+        assert_eq!(cpu.v[3], 0x5);
+        assert_eq!(cpu.v[1], 0x2);
+        assert_eq!(cpu.pc, lo + 4);
+    }
+
+    #[test]
+    fn skip_unknown_opcodes() {
+        let program: [u8; 6] = [
+            0x60,
+            0x12, /* ld r0, 0x12 */
+            0xff,
+            0xff, /* invalid */
+            0x61,
+            0x02, /* ld r1, 0x02 */
+        ];
+
+        let mut cpu = Cpu::new(Variant::Schip);
+        let lo = Cpu::LOAD_OFFSET as u16;
+        cpu.load(&program).unwrap();
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.tick(), Err(Error::BadInstruction));
+        assert_eq!(cpu.pc, lo + 2);
+
+        cpu.set_skip_unknown_opcodes(true);
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, lo + 4);
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.pc, lo + 6);
+        assert_eq!(cpu.v[0], 0x12);
+        assert_eq!(cpu.v[1], 0x02);
+    }
+
+    #[test]
+    fn run_state() {
+        let keys = Rc::new(RefCell::new(VecDeque::new()));
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.set_input_driver(Some(Box::new(ScriptedInput { keys: Rc::clone(&keys) })));
+        assert_eq!(cpu.state(), RunState::Running);
+
+        cpu.exec(Op::Key(Reg(0))).unwrap();
+        assert_eq!(cpu.state(), RunState::WaitingForKey);
+
+        keys.borrow_mut().push_back(Some(0xa));
+        cpu.exec(Op::Key(Reg(0))).unwrap();
+        assert_eq!(cpu.state(), RunState::Running);
+        assert_eq!(cpu.v[0], 0xa);
+
+        keys.borrow_mut().push_back(Some(driver::KEY_CANCELLED));
+        cpu.exec(Op::Key(Reg(0))).unwrap();
+        assert_eq!(cpu.state(), RunState::WaitingForKey);
+        assert_eq!(cpu.v[0], 0xa);
+
+        cpu.load(&[0xff, 0xff]).unwrap();
+        assert_eq!(cpu.tick(), Err(Error::BadInstruction));
+        assert_eq!(cpu.state(), RunState::Halted);
+    }
+
+    #[test]
+    fn fetch_big_endian() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&[0xa2, 0xf0]).unwrap();
+
+        assert_eq!(cpu.fetch_at(Cpu::LOAD_OFFSET as u16), Ok(0xa2f0));
+        assert_eq!(cpu.fetch(), Ok(0xa2f0));
+        assert_eq!(cpu.pc, Cpu::LOAD_OFFSET as u16);
+    }
+
+    #[test]
+    fn tick_n() {
+        let program: [u8; 8] = [
+            0x60,
+            0x12, /* ld r0, 0x12 */
+            0x00,
+            0xe0, /* cls */
+            0x61,
+            0x02, /* ld r1, 0x02 */
+            0xff,
+            0xff, /* invalid */
+        ];
+
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&program).unwrap();
+        assert_eq!(cpu.tick_n(10), (1, Err(Error::DriverMissing)));
+
+        cpu.load(&program).unwrap();
+        cpu.set_stop_on_nonfatal(false);
+        assert_eq!(cpu.tick_n(10), (3, Err(Error::BadInstruction)));
+        assert_eq!(cpu.v[1], 0x02);
+
+        cpu.load(&program).unwrap();
+        assert_eq!(cpu.tick_n(2), (2, Ok(())));
+    }
+
+    #[test]
+    fn test_draw() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        /* Draw font sprite 0 at (0, 0), then probe with font sprite 1. */
+        cpu.exec(Op::Ld(Reg(0), 0)).unwrap();
+        cpu.exec(Op::Ldspr(Reg(0))).unwrap();
+        assert_eq!(cpu.exec(Op::Draw(Reg(0), Reg(0), 5)), Err(Error::DriverMissing));
+        let vram = cpu.vram;
+
+        cpu.exec(Op::Ld(Reg(1), 1)).unwrap();
+        cpu.exec(Op::Ldspr(Reg(1))).unwrap();
+        cpu.exec(Op::Ld(Reg(2), 0)).unwrap();
+        let collisions = cpu.test_draw(Reg(0), Reg(0), 5).unwrap();
+
+        /* 0: f0 90 90 90 f0, 1: 20 60 20 20 70 */
+        let w = Cpu::DISPLAY_WIDTH;
+        assert_eq!(collisions, vec![2, 4 * w + 1, 4 * w + 2, 4 * w + 3]);
+        assert_eq!(cpu.vram[..], vram[..]);
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0x00);
+
+        cpu.exec(Op::Ld(Reg(3), 0x08)).unwrap();
+        assert_eq!(cpu.test_draw(Reg(3), Reg(0), 5), Ok(vec![]));
+
+        cpu.exec(Op::Ldi(0xffe)).unwrap();
+        assert_eq!(cpu.test_draw(Reg(0), Reg(0), 5), Err(Error::DataAbort));
+    }
+
+    #[test]
+    fn set_index() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&[0x12, 0x34]).unwrap();
+
+        assert_eq!(cpu.set_index(Cpu::RAM_BYTES as u16), Err(Error::DataAbort));
+        assert_eq!(cpu.index_register(), 0x000);
+
+        cpu.set_index(Cpu::LOAD_OFFSET as u16).unwrap();
+        assert_eq!(cpu.index_register(), Cpu::LOAD_OFFSET as u16);
+
+        cpu.exec(Op::Read(Reg(1))).unwrap();
+        assert_eq!(cpu.v[0], 0x12);
+        assert_eq!(cpu.v[1], 0x34);
+    }
+
+    #[test]
+    fn manual_timer() {
+        let mut cpu = Cpu::new_without_timer_thread();
+
+        cpu.exec(Op::Ld(Reg(0), 100)).unwrap();
+        cpu.exec(Op::Ldd(Reg(0))).unwrap();
+        cpu.exec(Op::Lds(Reg(0))).unwrap();
+        for _ in 0..60 {
+            cpu.tick_timers();
+        }
+        cpu.exec(Op::Movd(Reg(1))).unwrap();
+
+        assert_eq!(cpu.v[1], 40);
+        assert_eq!(cpu.timer.st.load(ORDERING), 40);
+    }
+
+    #[test]
+    fn strict_address_space() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.set_quirks(Quirks { strict_address_space: true, ..Quirks::default() });
+        for x in 0..5 {
+            cpu.exec(Op::Ld(Reg(x), x as u8 + 1)).unwrap();
+        }
+        cpu.exec(Op::Ldi(0xffc)).unwrap();
+
+        cpu.exec(Op::Str(Reg(3))).unwrap();
+        assert_eq!(cpu.ram.slice(0xffc, 4).unwrap(), [1, 2, 3, 4]);
+        cpu.exec(Op::Read(Reg(3))).unwrap();
+
+        assert_eq!(cpu.exec(Op::Str(Reg(4))), Err(Error::DataAbort));
+        assert_eq!(cpu.exec(Op::Read(Reg(4))), Err(Error::DataAbort));
+        assert_eq!(cpu.v[4], 5);
+    }
+
+    #[test]
+    fn validate_rom() {
+        let rom: [u8; 9] = [
+            0x60,
+            0x12, /* ld r0, 0x12 */
+            0xff,
+            0xff, /* invalid */
+            0x61,
+            0x02, /* ld r1, 0x02 */
+            0x80,
+            0x1f, /* invalid */
+            0xff, /* trailing byte */
+        ];
+
+        let lo = Cpu::LOAD_OFFSET as u16;
+        assert_eq!(Cpu::validate_rom(&rom), vec![lo + 2, lo + 6]);
+        assert!(Cpu::validate_rom(&rom[..2]).is_empty());
+    }
+
+    #[test]
+    fn instrumentation() {
+        let program: [u8; 6] = [
+            0x70,
+            0x01, /* add r0, 0x01 */
+            0x80,
+            0x14, /* addr r0, r1 */
+            0x12,
+            0x00, /* jmp 0x200 */
+        ];
+
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&program).unwrap();
+        assert_eq!(cpu.tick_n(300), (300, Ok(())));
+        assert!(cpu.timing_report().is_empty());
+
+        cpu.set_instrumentation(true);
+        assert_eq!(cpu.tick_n(3000), (3000, Ok(())));
+
+        let report = cpu.timing_report();
+        assert!(report[&OpCategory::Arithmetic] > Duration::from_secs(0));
+        assert!(report.contains_key(&OpCategory::Flow));
+        assert!(!report.contains_key(&OpCategory::Display));
+
+        cpu.set_instrumentation(false);
+        assert!(cpu.timing_report().is_empty());
+    }
+
+    #[test]
+    fn scroll() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        /* One pixel in the top left corner, scrolled once in each mode. */
+        let mut scrolled = |hires, rounds_up, op| {
+            cpu.set_quirks(Quirks { lores_scroll_rounds_up: rounds_up, ..Quirks::default() });
+            cpu.exec(if hires { Op::High } else { Op::Low }).unwrap_err();
+            cpu.vram[0] = true;
+            cpu.exec(op).unwrap_err();
+            let (width, _) = cpu.display_dimensions();
+            let offset = cpu.vram.iter().position(|px| *px);
+            offset.map(|o| (o % width, o / width))
+        };
+
+        assert_eq!(scrolled(true, false, Op::Scd(4)), Some((0, 4)));
+        assert_eq!(scrolled(false, false, Op::Scd(4)), Some((0, 2)));
+        assert_eq!(scrolled(false, false, Op::Scd(3)), Some((0, 1)));
+        assert_eq!(scrolled(false, true, Op::Scd(3)), Some((0, 2)));
+        assert_eq!(scrolled(true, false, Op::Scr), Some((4, 0)));
+        assert_eq!(scrolled(false, false, Op::Scr), Some((2, 0)));
+        assert_eq!(scrolled(true, false, Op::Scl), None);
+    }
+
+    #[test]
+    fn load_and_run() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        let frames = Cell::new(0);
+
+        /* 0x200: add v0, 1; 0x202: jmp 0x200 */
+        let no_errors = |e: &Error| panic!("unexpected error: {}", e);
+        cpu.load_and_run(&[0x70, 0x01, 0x12, 0x00], || {
+            frames.set(frames.get() + 1);
+            frames.get() <= 3
+        }, no_errors).unwrap();
+
+        assert_eq!(frames.get(), 4);
+        assert_eq!(cpu.v[0], 3 * Cpu::RUN_CYCLES_PER_FRAME as u8 / 2);
+
+        /* Stack underflow is fatal and ends the run early. */
+        assert_eq!(cpu.load_and_run(&[0x00, 0xee], || true, no_errors),
+                   Err(Error::StackUnderflow));
+
+        /* Drawing without a display driver isn't, and gets reported. */
+        let mut errors = Vec::new();
+        frames.set(0);
+        cpu.load_and_run(&[0xd0, 0x01, 0x12, 0x00], || {
+            frames.set(frames.get() + 1);
+            frames.get() <= 2
+        }, |e| errors.push(e.clone())).unwrap();
+        assert_eq!(errors, [Error::DriverMissing, Error::DriverMissing]);
+    }
+
+    #[test]
+    fn display_dimensions() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        assert_eq!(cpu.display_dimensions(), (64, 32));
+
+        cpu.exec(Op::High).unwrap_err();
+        assert_eq!(cpu.display_dimensions(), (128, 64));
+
+        cpu.exec(Op::Low).unwrap_err();
+        assert_eq!(cpu.display_dimensions(), (64, 32));
+    }
+
+    #[test]
+    fn pixels() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        cpu.set_pixel(3, 2, true);
+        cpu.set_pixel(Cpu::DISPLAY_WIDTH, 0, true);
+        assert!(cpu.get_pixel(3, 2));
+        assert!(!cpu.get_pixel(4, 2));
+        assert!(!cpu.get_pixel(Cpu::DISPLAY_WIDTH, 0));
+
+        /* A one pixel sprite over the primed pixel collides and clears it. */
+        cpu.exec(Op::Ld(Reg(0), 0x80)).unwrap();
+        cpu.exec(Op::Ldi(0x400)).unwrap();
+        cpu.exec(Op::Str(Reg(0))).unwrap();
+        cpu.exec(Op::Ld(Reg(0), 3)).unwrap();
+        cpu.exec(Op::Ld(Reg(1), 2)).unwrap();
+        assert_eq!(cpu.exec(Op::Draw(Reg(0), Reg(1), 1)), Err(Error::DriverMissing));
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 1);
+        assert!(!cpu.get_pixel(3, 2));
+    }
+
+    #[test]
+    fn every_register() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        for x in 0..Cpu::REG_COUNT {
+            let op = Op::decode(0x6000 | (x as u16) << 8 | x as u16).unwrap();
+            assert_eq!(op, Op::Ld(Reg(x), x as u8));
+            cpu.exec(op).unwrap();
+            assert_eq!(cpu.v[x], x as u8);
+        }
+    }
+
+    #[test]
+    fn driver_status() {
+        struct NullDisplay;
+
+        impl driver::Display for NullDisplay {
+            fn refresh(&mut self, _vram: &[bool]) {}
+        }
+
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.set_display_driver(Some(Box::new(NullDisplay)));
+
+        assert_eq!(cpu.driver_status(), DriverStatus {
+            display: true,
+            sound: false,
+            input: false,
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "gzip")]
+    fn load_gzipped() {
+        /* ld v0, 5; add v0, 1; jmp 0x202 */
+        let rom = [0x60, 0x05, 0x70, 0x01, 0x12, 0x02];
+        let rom_gz = [
+            0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x4b, 0x60,
+            0x2d, 0x60, 0x14, 0x62, 0x02, 0x00, 0xdd, 0xfa, 0xd1, 0x8d, 0x06, 0x00,
+            0x00, 0x00,
+        ];
+
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load_maybe_compressed(&rom_gz).unwrap();
+        assert_eq!(cpu.ram.slice(Cpu::LOAD_OFFSET, rom.len()).unwrap(), rom);
+        assert_eq!(cpu.tick_n(3), (3, Ok(())));
+        assert_eq!(cpu.v[0], 6);
+
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load_maybe_compressed(&rom).unwrap();
+        assert_eq!(cpu.ram.slice(Cpu::LOAD_OFFSET, rom.len()).unwrap(), rom);
+        assert_eq!(cpu.load_maybe_compressed(&rom_gz[..12]), Err(Error::LoadFailure));
+    }
+
+    #[test]
+    fn exec_all() {
+        let mut cpu = Cpu::new(Variant::Schip);
 
-        let sprite: [u8; 3] = [
-            0b11111111,
-            0b10000001,
-            0b11111111,
-        ];
+        cpu.exec_all(&[
+            Op::Ld(Reg(0), 0xf0),
+            Op::Ld(Reg(1), 0x20),
+            Op::Addr(Reg(0), Reg(1)),
+            Op::Add(Reg(0), 0x05),
+        ]).unwrap();
+        assert_eq!(cpu.v[0], 0x15);
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 1);
 
-        cpu.exec(Op::Ld(Reg(0), sprite[0])).unwrap();
-        cpu.exec(Op::Ld(Reg(1), sprite[1])).unwrap();
-        cpu.exec(Op::Ld(Reg(2), sprite[2])).unwrap();
-        cpu.exec(Op::Ldi(0x400)).unwrap();
-        cpu.exec(Op::Str(Reg(2))).unwrap();
-        cpu.exec(Op::Ld(Reg(3), 0x15)).unwrap();
-        cpu.exec(Op::Ld(Reg(4), 0x05)).unwrap();
-        assert_eq!(cpu.exec(Op::Draw(Reg(3), Reg(4), 3)), Err(Error::DriverMissing));
-
-        let row1_start = 0x05 * Cpu::DISPLAY_WIDTH + 0x15;
-        let row1_end = 0x05 * Cpu::DISPLAY_WIDTH + 0x1d;
-        let row2_start = 0x06 * Cpu::DISPLAY_WIDTH + 0x15;
-        let row2_end = 0x06 * Cpu::DISPLAY_WIDTH + 0x1d;
-        let row3_start = 0x07 * Cpu::DISPLAY_WIDTH + 0x15;
-        let row3_end = 0x07 * Cpu::DISPLAY_WIDTH + 0x1d;
-
-        assert_eq!(cpu.vram[row1_start..row1_end], [true, true, true, true, true, true, true, true]);
-        assert_eq!(cpu.vram[row2_start..row2_end], [true, false, false, false, false, false, false, true]);
-        assert_eq!(cpu.vram[row3_start..row3_end], [true, true, true, true, true, true, true, true]);
-        assert_eq!(cpu.v[Cpu::FLAG_REG], 0x00);
+        /* Ret on an empty stack stops the run before the last Ld. */
+        assert_eq!(cpu.exec_all(&[Op::Ld(Reg(2), 1), Op::Ret, Op::Ld(Reg(3), 1)]),
+                   Err(Error::StackUnderflow));
+        assert_eq!(cpu.v[2], 1);
+        assert_eq!(cpu.v[3], 0);
+    }
 
-        /* Draw the same sprite again to clear it. */
-        assert_eq!(cpu.exec(Op::Draw(Reg(3), Reg(4), 3)), Err(Error::DriverMissing));
+    #[test]
+    fn shift_source() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        let shr = Op::decode(0x8126).unwrap();
+        let shl = Op::decode(0x812e).unwrap();
 
-        assert_eq!(cpu.vram[row1_start..row1_end], [false, false, false, false, false, false, false, false]);
-        assert_eq!(cpu.vram[row2_start..row2_end], [false, false, false, false, false, false, false, false]);
-        assert_eq!(cpu.vram[row3_start..row3_end], [false, false, false, false, false, false, false, false]);
-        assert_eq!(cpu.v[Cpu::FLAG_REG], 0x01);
+        cpu.exec_all(&[Op::Ld(Reg(1), 0x81), Op::Ld(Reg(2), 0x42), shr.clone()]).unwrap();
+        assert_eq!((cpu.v[1], cpu.v[2], cpu.v[Cpu::FLAG_REG]), (0x40, 0x42, 1));
+        cpu.exec_all(&[Op::Ld(Reg(1), 0x81), shl.clone()]).unwrap();
+        assert_eq!((cpu.v[1], cpu.v[2], cpu.v[Cpu::FLAG_REG]), (0x02, 0x42, 1));
 
-        cpu.exec(Op::Ld(Reg(3), 60)).unwrap();
-        cpu.exec(Op::Ld(Reg(4), 30)).unwrap();
-        assert_eq!(cpu.exec(Op::Draw(Reg(3), Reg(4), 3)), Err(Error::DriverMissing));
-
-        let row1_unwrapped_start = 30 * Cpu::DISPLAY_WIDTH + 60;
-        let row1_unwrapped_end = 30 * Cpu::DISPLAY_WIDTH + 64;
-        let row2_unwrapped_start = 31 * Cpu::DISPLAY_WIDTH + 60;
-        let row2_unwrapped_end = 31 * Cpu::DISPLAY_WIDTH + 64;
-        let row3_unwrapped_start = 0 * Cpu::DISPLAY_WIDTH + 60;
-        let row3_unwrapped_end = 0 * Cpu::DISPLAY_WIDTH + 64;
-        let row1_wrapped_start = 30 * Cpu::DISPLAY_WIDTH + 0;
-        let row1_wrapped_end = 30 * Cpu::DISPLAY_WIDTH + 4;
-        let row2_wrapped_start = 31 * Cpu::DISPLAY_WIDTH + 0;
-        let row2_wrapped_end = 31 * Cpu::DISPLAY_WIDTH + 4;
-        let row3_wrapped_start = 0 * Cpu::DISPLAY_WIDTH + 0;
-        let row3_wrapped_end = 0 * Cpu::DISPLAY_WIDTH + 4;
-
-        assert_eq!(cpu.vram[row1_unwrapped_start..row1_unwrapped_end], [true, true, true, true]);
-        assert_eq!(cpu.vram[row2_unwrapped_start..row2_unwrapped_end], [true, false, false, false]);
-        assert_eq!(cpu.vram[row3_unwrapped_start..row3_unwrapped_end], [true, true, true, true]);
-        assert_eq!(cpu.vram[row1_wrapped_start..row1_wrapped_end], [true, true, true, true]);
-        assert_eq!(cpu.vram[row2_wrapped_start..row2_wrapped_end], [false, false, false, true]);
-        assert_eq!(cpu.vram[row3_wrapped_start..row3_wrapped_end], [true, true, true, true]);
-        assert_eq!(cpu.v[Cpu::FLAG_REG], 0x00);
+        cpu.set_quirks(Quirks { shift_uses_vy: true, ..Quirks::default() });
+        cpu.exec_all(&[Op::Ld(Reg(1), 0x81), shr]).unwrap();
+        assert_eq!((cpu.v[1], cpu.v[2], cpu.v[Cpu::FLAG_REG]), (0x21, 0x42, 0));
+        cpu.exec_all(&[Op::Ld(Reg(1), 0x81), shl]).unwrap();
+        assert_eq!((cpu.v[1], cpu.v[2], cpu.v[Cpu::FLAG_REG]), (0x84, 0x42, 0));
     }
 
     #[test]
-    fn load_and_tick() {
-        let program: [u8; 6] = [
-            0x60,
-            0x12, /* ld r0, 0x12 */
-            0x61,
-            0x02, /* ld r1, 0x02 */
-            0x80,
-            0x14, /* addr r0, r1 */
-        ];
+    fn reset_and_load() {
+        let mut cpu = Cpu::new(Variant::Schip);
 
-        let mut cpu = Cpu::new();
-        let lo = Cpu::LOAD_OFFSET as u16;
-        cpu.load(&program).unwrap();
-        assert_eq!(cpu.pc, lo);
+        /* call 0x206; ld v0, 1; ldi 0x300; draw v0, v0, 5 */
+        cpu.exec(Op::Ld(Reg(5), 0x55)).unwrap();
+        cpu.exec(Op::Ldd(Reg(5))).unwrap();
+        cpu.load(&[0x22, 0x06, 0x60, 0x01, 0x00, 0x00, 0xa3, 0x00, 0xd0, 0x05]).unwrap();
+        cpu.set_pixel(0, 0, true);
+        assert_eq!(cpu.tick_n(2), (2, Ok(())));
+        assert_eq!(cpu.sp, 1);
 
+        /* ld v1, 2; add v1, 3; jmp 0x204 */
+        let second = [0x61, 0x02, 0x71, 0x03, 0x12, 0x04];
+        cpu.reset_and_load(&second).unwrap();
+        assert_eq!((cpu.pc, cpu.sp, cpu.i), (Cpu::LOAD_OFFSET as u16, 0, 0));
+        assert_eq!(cpu.v, [0; Cpu::REG_COUNT]);
+        assert_eq!(cpu.timer.dt.load(ORDERING), 0);
+        assert!(!cpu.get_pixel(0, 0));
+        assert_eq!(cpu.ram.read(Cpu::LOAD_OFFSET + second.len()), Ok(0xff));
+
+        assert_eq!(cpu.tick_n(4), (4, Ok(())));
+        assert_eq!(cpu.v[1], 5);
+        assert_eq!(cpu.pc, 0x204);
+    }
+
+    #[test]
+    fn protect_font() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.exec_all(&[Op::Ld(Reg(0), 135), Op::Ldi(0x000)]).unwrap();
+
+        cpu.set_protect_font(true);
+        assert_eq!(cpu.exec(Op::Bcd(Reg(0))), Err(Error::ProtectedWrite));
+        assert_eq!(cpu.exec(Op::Str(Reg(0))), Err(Error::ProtectedWrite));
+        assert_eq!(cpu.ram.slice(0, 3).unwrap(), &Cpu::font_glyph(0)[..3]);
+
+        /* Just past the font is fine. */
+        cpu.exec_all(&[Op::Ldi(Cpu::FONT_SPRITES_RAM_END as u16), Op::Bcd(Reg(0))]).unwrap();
+
+        cpu.set_protect_font(false);
+        cpu.exec_all(&[Op::Ldi(0x000), Op::Bcd(Reg(0))]).unwrap();
+        assert_eq!(cpu.ram.slice(0, 3).unwrap(), [1, 3, 5]);
+    }
+
+    #[test]
+    fn mem_write_trace() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&[0xf1, 0x55]).unwrap();
+        cpu.exec_all(&[Op::Ld(Reg(0), 0x12), Op::Ld(Reg(1), 0x34), Op::Ldi(0x300)]).unwrap();
+
+        cpu.set_mem_write_trace(true);
+        cpu.pc = Cpu::LOAD_OFFSET as u16;
         cpu.tick().unwrap();
-        assert_eq!(cpu.pc, lo + 2);
-        assert_eq!(cpu.v[0], 0x12);
 
+        assert_eq!(cpu.take_mem_writes(), [
+            MemWrite { pc: 0x200, addr: 0x300, old: 0xff, new: 0x12 },
+            MemWrite { pc: 0x200, addr: 0x301, old: 0xff, new: 0x34 },
+        ]);
+        assert!(cpu.take_mem_writes().is_empty());
+
+        cpu.set_mem_write_trace(false);
+        cpu.exec(Op::Bcd(Reg(0))).unwrap();
+        assert!(cpu.take_mem_writes().is_empty());
+    }
+
+    #[test]
+    fn self_mod_check() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        let writes = Rc::new(RefCell::new(Vec::new()));
+        let sink = Rc::clone(&writes);
+        cpu.on_self_mod_write(Some(Box::new(move |w| sink.borrow_mut().push(*w))));
+
+        /* 0x200: ld i, 0x202; 0x202: ld v0, 0x12; 0x204: str v0;
+           0x206: ld i, 0x300; 0x208: str v0 */
+        cpu.load(&[0xa2, 0x02, 0x60, 0x12, 0xf0, 0x55, 0xa3, 0x00, 0xf0, 0x55]).unwrap();
+        for _ in 0..5 {
+            cpu.tick().unwrap();
+        }
+
+        assert_eq!(*writes.borrow(), [
+            MemWrite { pc: 0x204, addr: 0x202, old: 0x60, new: 0x12 },
+        ]);
+
+        cpu.on_self_mod_write(None);
+        cpu.exec_all(&[Op::Ldi(0x200), Op::Str(Reg(0))]).unwrap();
+        assert_eq!(writes.borrow().len(), 1);
+    }
+
+    #[test]
+    fn undo_last() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&[0x60, 0x05, 0x70, 0x03]).unwrap();
         cpu.tick().unwrap();
-        assert_eq!(cpu.pc, lo + 4);
-        assert_eq!(cpu.v[1], 0x02);
+        cpu.set_undo_journal(true);
 
         cpu.tick().unwrap();
-        assert_eq!(cpu.pc, lo + 6);
-        assert_eq!(cpu.v[0], 0x14);
+        assert_eq!((cpu.v[0], cpu.pc), (0x08, 0x204));
+        assert_eq!(cpu.undo_last(), Ok(true));
+        assert_eq!((cpu.v[0], cpu.pc), (0x05, 0x202));
+        assert_eq!(cpu.undo_last(), Ok(false));
 
-        assert_eq!(cpu.tick(), Err(Error::BadInstruction));
-        assert_eq!(cpu.pc, lo + 6);
+        cpu.exec_all(&[Op::Ldi(0x300), Op::Str(Reg(0))]).unwrap();
+        assert_eq!(cpu.ram.read(0x300), Ok(0x05));
+        cpu.undo_last().unwrap();
+        assert_eq!(cpu.ram.read(0x300), Ok(0xff));
+        assert_eq!(cpu.i, 0x300);
+
+        cpu.set_undo_journal(false);
+        cpu.exec(Op::Add(Reg(0), 1)).unwrap();
+        assert_eq!(cpu.undo_last(), Ok(false));
+    }
+
+    #[test]
+    fn jump_out_of_range() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        cpu.exec(Op::Ld(Reg(0), 0x20)).unwrap();
+        assert_eq!(cpu.exec(Op::Jmpi(0xff0)), Err(Error::BadJump(0x1010)));
+        assert!(Error::BadJump(0x1010).fatal());
+        assert_eq!(cpu.exec(Op::Jmp(0xfff)), Err(Error::BadJump(0xfff)));
+        cpu.exec(Op::Jmp(0xffe)).unwrap();
+        assert_eq!(cpu.pc, 0xffe);
+
+        /* A bad call fails before anything is pushed. */
+        assert_eq!(cpu.exec(Op::Call(0xfff)), Err(Error::BadJump(0xfff)));
+        assert_eq!(cpu.sp, 0);
+
+        cpu.set_quirks(Quirks { wrap_jumps: true, ..Quirks::default() });
+        cpu.exec(Op::Jmpi(0xff0)).unwrap();
+        assert_eq!(cpu.pc, 0x010);
+
+        /* XO-CHIP's 64K of RAM leaves room past 0xfff to wrap into. */
+        cpu.set_variant(Variant::XoChip);
+        cpu.set_quirks(Quirks { wrap_jumps: true, ..Quirks::default() });
+        cpu.exec(Op::Jmpi(0xff0)).unwrap();
+        assert_eq!(cpu.pc, 0x1010);
+    }
+
+    #[test]
+    fn instructions() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&[0x60, 0x05, 0xa3, 0x00, 0x00, 0x00, 0x12, 0x00]).unwrap();
+
+        let ops: Vec<_> = cpu.instructions().take(4).collect();
+        assert_eq!(ops, [
+            (0x200, Some(Op::Ld(Reg(0), 0x05))),
+            (0x202, Some(Op::Ldi(0x300))),
+            (0x204, Op::decode(0x0000)),
+            (0x206, Some(Op::Jmp(0x200))),
+        ]);
+        assert_eq!(cpu.instructions().count(), (Cpu::RAM_BYTES - Cpu::LOAD_OFFSET) / 2);
+        assert_eq!(cpu.instructions().last().unwrap().0, 0xffe);
+
+        cpu.exec_all(&[Op::Ld(Reg(0), 0x12), Op::Ldi(0x200), Op::Str(Reg(0))]).unwrap();
+        assert_eq!(cpu.instructions().next(), Some((0x200, Some(Op::Jmp(0x205)))));
+    }
+
+    #[test]
+    fn set_registers() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        let mut regs = [0u8; Cpu::REG_COUNT];
+        for (n, r) in regs.iter_mut().enumerate() {
+            *r = n as u8 * 0x11;
+        }
+
+        cpu.set_registers(regs);
+        assert_eq!(cpu.registers(), regs);
+        cpu.exec(Op::Addr(Reg(1), Reg(2))).unwrap();
+        assert_eq!(cpu.registers()[1], 0x33);
+
+        cpu.registers_mut()[0xe] = 0x01;
+        cpu.exec(Op::Or(Reg(0xe), Reg(0x4))).unwrap();
+        assert_eq!(cpu.registers()[0xe], 0x45);
+    }
+
+    #[test]
+    fn halt_loop() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        /* 0x200: ld v0, 0x04; 0x202: jp v0, 0x200; 0x204: jp 0x204 */
+        cpu.load(&[0x60, 0x04, 0xb2, 0x00, 0x12, 0x04]).unwrap();
+
+        assert_eq!(cpu.tick_n(10), (10, Ok(())));
+        assert_eq!(cpu.pc, 0x204);
+
+        cpu.set_halt_loop_detection(true);
+        assert_eq!(cpu.tick(), Err(Error::HaltLoop(0x204)));
+        assert!(!Error::HaltLoop(0x204).fatal());
+        assert_eq!((cpu.pc, cpu.state()), (0x204, RunState::Running));
+
+        cpu.exec(Op::Ld(Reg(0), 0x02)).unwrap();
+        cpu.pc = 0x202;
+        assert_eq!(cpu.tick(), Err(Error::HaltLoop(0x202)));
+
+        cpu.pc = 0x200;
+        assert_eq!(cpu.tick_n(10), (2, Err(Error::HaltLoop(0x204))));
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn load_file() {
+        let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        cpu.load_file(fixtures.join("ld_halt.ch8")).unwrap();
+        assert_eq!(cpu.read_memory(Cpu::LOAD_OFFSET, 4), Ok(&[0x60, 0x2a, 0x12, 0x02][..]));
+        cpu.tick().unwrap();
+        assert_eq!(cpu.v[0], 0x2a);
+
+        assert_eq!(cpu.load_file(fixtures.join("missing.ch8")),
+                   Err(Error::Io(std::io::ErrorKind::NotFound)));
+    }
+
+    #[test]
+    fn clip_sprites() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.set_quirks(Quirks::default());
+        let (display, _) = CaptureDisplay::new();
+        cpu.set_display_driver(Some(Box::new(display)));
+        let row = |cpu: &Cpu, y: usize| -> Vec<bool> {
+            cpu.vram[y * Cpu::DISPLAY_WIDTH..(y + 1) * Cpu::DISPLAY_WIDTH].to_vec()
+        };
+
+        /* A two row, full width sprite at (63, 31) wraps onto the far edges
+           under the modern quirks. */
+        cpu.ram.write(0x300, 0xff).unwrap();
+        cpu.ram.write(0x301, 0xff).unwrap();
+        cpu.exec_all(&[Op::Ld(Reg(0), 63), Op::Ld(Reg(1), 31), Op::Ldi(0x300),
+                       Op::Draw(Reg(0), Reg(1), 2)]).unwrap();
+        assert!(row(&cpu, 31)[63] && row(&cpu, 31)[..7].iter().all(|&p| p));
+        assert!(row(&cpu, 0)[63] && row(&cpu, 0)[6]);
+
+        /* With clipping only the origin pixel lands, but the origin itself
+           still wraps: 127 is the same column as 63. */
+        cpu.exec(Op::Cls).unwrap();
+        cpu.set_quirks(Quirks { clip_sprites: true, ..Quirks::default() });
+        cpu.exec_all(&[Op::Ld(Reg(0), 127), Op::Draw(Reg(0), Reg(1), 2)]).unwrap();
+        assert_eq!(cpu.vram.iter().filter(|&&p| p).count(), 1);
+        assert!(row(&cpu, 31)[63]);
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0);
+    }
+
+    #[test]
+    fn step_detailed() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&rom![Ld(Reg(0), 0xff), Ld(Reg(1), 0x02), Addr(Reg(0), Reg(1)), Cls]).unwrap();
+        cpu.set_display_driver(Some(Box::new(CaptureDisplay::new().0)));
+        assert_eq!(cpu.tick_n(2), (2, Ok(())));
+
+        assert_eq!(cpu.step_detailed(), Ok(StepResult {
+            op: Op::Addr(Reg(0), Reg(1)),
+            pc_before: 0x204,
+            pc_after: 0x206,
+            changed_regs: vec![(0, 0x01), (Cpu::FLAG_REG, 1)],
+            drew: false,
+        }));
+
+        let cls = cpu.step_detailed().unwrap();
+        assert!(cls.drew && cls.changed_regs.is_empty());
+        assert_eq!(cpu.step_detailed().map(|s| s.op), Err(Error::BadInstruction));
+    }
+
+    #[test]
+    fn rng_replay() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.set_rng_seed(Some(0x5eed));
+        let draws: Vec<u8> = (0..20).map(|_| {
+            cpu.exec(Op::Rand(Reg(0), 0xff)).unwrap();
+            cpu.v[0]
+        }).collect();
+        assert_eq!(cpu.rng_call_count(), 20);
+
+        /* Replaying the 13th draw only takes the call index. */
+        cpu.reset();
+        assert_eq!(cpu.rng_call_count(), 0);
+        cpu.fast_forward_rng(12);
+        cpu.exec(Op::Rand(Reg(1), 0xff)).unwrap();
+        assert_eq!(cpu.v[1], draws[12]);
+        assert_eq!(cpu.rng_call_count(), 13);
+
+        let mut other = Cpu::new(Variant::Schip);
+        other.set_rng_seed(Some(0x5eed));
+        other.exec(Op::Rand(Reg(0), 0x0f)).unwrap();
+        assert_eq!(other.v[0], draws[0] & 0x0f);
+    }
+
+    #[test]
+    fn last_slot() {
+        let mut cpu = Cpu::new(Variant::XoChip);
+
+        cpu.ram.write(0xfffe, 0x60).unwrap();
+        cpu.ram.write(0xffff, 0x2a).unwrap();
+        cpu.pc = 0xfffe;
+        assert_eq!(cpu.next_pc(), Ok(0x0000));
+        cpu.tick().unwrap();
+        assert_eq!(cpu.v[0], 0x2a);
+        assert_eq!(cpu.pc, 0x0000);
+
+        /* A skip from the second to last slot steps over the last one. */
+        cpu.pc = 0xfffc;
+        cpu.exec(Op::Se(Reg(0), 0x2a)).unwrap();
+        assert_eq!(cpu.pc, 0x0000);
+
+        cpu.pc = 0xfffe;
+        cpu.exec(Op::Call(0x300)).unwrap();
+        assert_eq!(cpu.pc, 0x300);
+        cpu.exec(Op::Ret).unwrap();
+        assert_eq!(cpu.pc, 0x0000);
+    }
+
+    #[test]
+    fn index_width() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.exec_all(&[Op::Ld(Reg(0), 0x20), Op::Ldi(0xff0), Op::Addi(Reg(0))]).unwrap();
+        assert_eq!(cpu.i, 0x010);
+        cpu.exec(Op::Ldi(0x1234)).unwrap();
+        assert_eq!(cpu.i, 0x234);
+
+        cpu.set_quirks(Quirks { strict_index: true, ..Quirks::default() });
+        cpu.exec(Op::Ldi(0xfff)).unwrap();
+        assert_eq!(cpu.exec(Op::Ldi(0x1234)), Err(Error::IndexOverflow(0x1234)));
+        cpu.exec(Op::Ldi(0xff0)).unwrap();
+        assert_eq!(cpu.exec(Op::Addi(Reg(0))), Err(Error::IndexOverflow(0x1010)));
+        assert!(Error::IndexOverflow(0x1010).fatal());
+        assert_eq!(cpu.i, 0xff0);
+
+        /* XO-CHIP's I reaches all of its 64K, so only 16 bit overflow
+           counts. */
+        cpu.set_variant(Variant::XoChip);
+        cpu.exec_all(&[Op::Ldi(0x1234), Op::Ld(Reg(0), 0x20), Op::Addi(Reg(0))]).unwrap();
+        assert_eq!(cpu.i, 0x1254);
+        cpu.set_quirks(Quirks { strict_index: true, ..Quirks::default() });
+        cpu.exec(Op::Ldi(0xfff0)).unwrap();
+        assert_eq!(cpu.exec(Op::Addi(Reg(0))), Err(Error::IndexOverflow(0x10010)));
+        assert_eq!(cpu.i, 0xfff0);
+        cpu.set_quirks(Quirks::default());
+        cpu.exec(Op::Addi(Reg(0))).unwrap();
+        assert_eq!(cpu.i, 0x0010);
+    }
+
+    #[test]
+    fn memory_increments_i() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.exec_all(&[Op::Ldi(0x300), Op::Str(Reg(3))]).unwrap();
+        assert_eq!(cpu.i, 0x300);
+
+        cpu.set_quirks(Quirks { memory_increments_i: true, ..Quirks::default() });
+        cpu.exec_all(&[Op::Str(Reg(3)), Op::Read(Reg(1))]).unwrap();
+        assert_eq!(cpu.i, 0x306);
+
+        /* Reading the very last bytes of RAM moves I across 0xfff. */
+        cpu.exec_all(&[Op::Ldi(0xffe), Op::Read(Reg(1))]).unwrap();
+        assert_eq!(cpu.i, 0x000);
+        cpu.set_quirks(Quirks { memory_increments_i: true, strict_index: true,
+                                ..Quirks::default() });
+        cpu.exec(Op::Ldi(0xffe)).unwrap();
+        assert_eq!(cpu.exec(Op::Read(Reg(1))), Err(Error::IndexOverflow(0x1000)));
+    }
+
+    #[test]
+    fn add_imm_sets_vf() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        cpu.exec_all(&[Op::Ld(Reg(Cpu::FLAG_REG), 0x42), Op::Ld(Reg(0), 0xff), Op::Add(Reg(0), 2)])
+            .unwrap();
+        assert_eq!((cpu.v[0], cpu.v[Cpu::FLAG_REG]), (1, 0x42));
+
+        cpu.set_quirks(Quirks { add_imm_sets_vf: true, ..Quirks::default() });
+        cpu.exec(Op::Add(Reg(0), 0xff)).unwrap();
+        assert_eq!((cpu.v[0], cpu.v[Cpu::FLAG_REG]), (0, 1));
+        cpu.exec(Op::Add(Reg(0), 1)).unwrap();
+        assert_eq!((cpu.v[0], cpu.v[Cpu::FLAG_REG]), (1, 0));
+    }
+
+    #[test]
+    fn sub_borrow() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        let mut sub = |op: fn(Reg, Reg) -> Op, a, b| {
+            cpu.exec_all(&[Op::Ld(Reg(0), a), Op::Ld(Reg(1), b), op(Reg(0), Reg(1))]).unwrap();
+            (cpu.v[0], cpu.v[Cpu::FLAG_REG])
+        };
+
+        assert_eq!(sub(Op::Subr, 5, 3), (2, 1));
+        assert_eq!(sub(Op::Subr, 3, 5), (0xfe, 0));
+        assert_eq!(sub(Op::Subr, 4, 4), (0, 1));
+
+        assert_eq!(sub(Op::Subnr, 3, 5), (2, 1));
+        assert_eq!(sub(Op::Subnr, 5, 3), (0xfe, 0));
+        assert_eq!(sub(Op::Subnr, 4, 4), (0, 1));
+    }
+
+    #[test]
+    fn fetch_edges() {
+        let cpu = Cpu::new_without_timer_thread();
+
+        assert_eq!(cpu.fetch_at(Cpu::FONT_SPRITES_RAM_START as u16), Ok(0xf090));
+        assert_eq!(cpu.fetch_at(Cpu::RAM_BYTES as u16 - 1), Err(Error::PrefetchAbort));
+        assert_eq!(cpu.fetch_at(u16::MAX), Err(Error::PrefetchAbort));
+    }
+
+    #[test]
+    fn current_op() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.load(&[0x6a, 0x05, 0xff, 0xff]).unwrap();
+
+        assert_eq!(cpu.current_opcode(), Ok(0x6a05));
+        assert_eq!(cpu.current_op(), Some(Op::Ld(Reg(0xa), 0x05)));
+        assert_eq!(cpu.program_counter(), 0x200);
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.current_opcode(), Ok(0xffff));
+        assert_eq!(cpu.current_op(), None);
+    }
+
+    #[test]
+    fn shared_state() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        let shared = cpu.shared_state();
+
+        /* 0x200: add v0, 1; 0x202: jmp 0x200 */
+        cpu.load(&[0x70, 0x01, 0x12, 0x00]).unwrap();
+
+        let reader = thread::spawn(move || {
+            let mut last = 0;
+            while last < 100 {
+                let snapshot = shared.latest();
+                assert!(snapshot.v[0] >= last);
+                assert_eq!(snapshot.vram.len(), Cpu::VRAM_BYTES);
+                last = snapshot.v[0];
+            }
+        });
+
+        for _ in 0..100 {
+            cpu.run_frame(2).unwrap();
+        }
+        reader.join().unwrap();
+        assert_eq!(cpu.snapshot().v[0], 100);
+    }
+
+    #[test]
+    fn advance_timers() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.exec_all(&[Op::Ld(Reg(0), 10), Op::Ldd(Reg(0))]).unwrap();
+
+        cpu.advance_timers(Duration::from_millis(100));
+        cpu.exec(Op::Movd(Reg(1))).unwrap();
+        assert_eq!(cpu.v[1], 4);
+
+        /* The thread source leaves counting to the thread. */
+        let mut cpu = Cpu::with_timer_source(Box::new(ThreadSource));
+        cpu.exec_all(&[Op::Ld(Reg(0), 200), Op::Ldd(Reg(0))]).unwrap();
+        cpu.advance_timers(Duration::from_secs(1));
+        cpu.exec(Op::Movd(Reg(1))).unwrap();
+        assert!(cpu.v[1] > 150);
+    }
+
+    #[test]
+    fn timer_expire_callbacks() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        let expired = Arc::new(Mutex::new(Vec::new()));
+        let (dt_log, st_log) = (Arc::clone(&expired), Arc::clone(&expired));
+        cpu.on_delay_expire(Some(Box::new(move || dt_log.lock().unwrap().push("dt"))));
+        cpu.on_sound_expire(Some(Box::new(move || st_log.lock().unwrap().push("st"))));
+
+        cpu.exec_all(&[Op::Ld(Reg(0), 2), Op::Ldd(Reg(0)), Op::Ld(Reg(0), 4)]).unwrap();
+        cpu.exec(Op::Lds(Reg(0))).unwrap();
+        cpu.tick_timers();
+        assert!(expired.lock().unwrap().is_empty());
+        cpu.tick_timers();
+        assert_eq!(*expired.lock().unwrap(), ["dt"]);
+        cpu.advance_timers(Duration::from_millis(50));
+        assert_eq!(*expired.lock().unwrap(), ["dt", "st"]);
+    }
+
+    struct FrameLog {
+        frames: Rc<RefCell<Vec<Vec<bool>>>>,
+    }
+
+    impl driver::Display for FrameLog {
+        fn refresh(&mut self, vram: &[bool]) {
+            self.frames.borrow_mut().push(vram.to_vec());
+        }
+    }
+
+    #[test]
+    fn double_buffer() {
+        let mut cpu = Cpu::new(Variant::Schip);
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        cpu.set_display_driver(Some(Box::new(FrameLog { frames: Rc::clone(&frames) })));
+        cpu.set_double_buffer(true).unwrap();
+
+        /* Two rows of the 0 glyph drawn one row at a time, then a spin:
+           ld i, 0; drw v0, v0, 1; ld i, 1; ld v1, 1; drw v0, v1, 1; jmp 0x20a */
+        cpu.load(&[0xa0, 0x00, 0xd0, 0x01, 0xa0, 0x01, 0x61, 0x01, 0xd0, 0x11, 0x12, 0x0a])
+            .unwrap();
+        cpu.run_frame(6).unwrap();
+
+        assert_eq!(frames.borrow().len(), 1);
+        assert_screen_eq(&frames.borrow()[0], "
+            ####
+            #..#
+        ");
+
+        /* Nothing new was drawn, so there's nothing to show. */
+        cpu.run_frame(6).unwrap();
+        assert_eq!(frames.borrow().len(), 1);
+
+        cpu.set_double_buffer(false).unwrap();
+        cpu.exec(Op::Cls).unwrap();
+        assert_eq!(frames.borrow().len(), 2);
+    }
+
+    #[test]
+    fn draw_resets_vf() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        cpu.exec_all(&[Op::Ld(Reg(Cpu::FLAG_REG), 1), Op::Ldi(0)]).unwrap();
+        assert_eq!(cpu.exec(Op::Draw(Reg(0), Reg(0), 1)), Err(Error::DriverMissing));
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0);
+
+        cpu.exec(Op::Ld(Reg(Cpu::FLAG_REG), 1)).unwrap();
+        assert_eq!(cpu.exec(Op::Draw(Reg(0), Reg(0), 0)), Err(Error::DriverMissing));
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0);
+
+        /* Drawn at VF = 8, which the collision result must not disturb
+           midway through the sprite. */
+        cpu.exec(Op::Ld(Reg(Cpu::FLAG_REG), 8)).unwrap();
+        cpu.exec(Op::Draw(Reg(Cpu::FLAG_REG), Reg(0), 5)).unwrap_err();
+        assert!(cpu.get_pixel(8, 4));
+        assert_eq!(cpu.v[Cpu::FLAG_REG], 0);
+    }
+
+    #[test]
+    fn frame_cap() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.set_max_frame_instructions(1000);
+
+        /* 0x200: add v0, 1; 0x202: jp 0x202 */
+        cpu.load(&[0x70, 0x01, 0x12, 0x02]).unwrap();
+        assert!(!cpu.frame_capped());
+        cpu.run_frame(1000).unwrap();
+        assert!(!cpu.frame_capped());
+        cpu.run_frame(usize::MAX).unwrap();
+        assert_eq!(cpu.program_counter(), 0x202);
+        assert_eq!(cpu.v[0], 1);
+        assert!(cpu.frame_capped());
+
+        cpu.set_max_frame_instructions(2000);
+        assert!(!cpu.frame_capped());
+        cpu.run_frame(2001).unwrap();
+        assert!(cpu.frame_capped());
+        cpu.reset();
+        assert!(!cpu.frame_capped());
+    }
+
+    #[test]
+    fn next_pc() {
+        let mut cpu = Cpu::new(Variant::Schip);
+
+        /* ld v0, 4; call 0x20a; jmp 0x300; se v0, 4; bnnn 0x300; ret */
+        cpu.load(&[0x60, 0x04, 0x22, 0x0a, 0x13, 0x00, 0x30, 0x04, 0xb3, 0x00, 0x00, 0xee])
+            .unwrap();
+
+        assert_eq!(cpu.next_pc(), Ok(0x202));
+        cpu.tick().unwrap();
+        assert_eq!(cpu.next_pc(), Ok(0x20a));
+        cpu.tick().unwrap();
+        assert_eq!(cpu.next_pc(), Ok(0x204));
+        assert_eq!(cpu.pc, 0x20a);
+
+        cpu.pc = 0x204;
+        assert_eq!(cpu.next_pc(), Ok(0x300));
+        cpu.pc = 0x206;
+        assert_eq!(cpu.next_pc(), Ok(0x208));
+        cpu.pc = 0x208;
+        assert_eq!(cpu.next_pc(), Ok(0x304));
+    }
+
+    #[test]
+    fn run_budget() {
+        let keys = Rc::new(RefCell::new(VecDeque::new()));
+        let mut cpu = Cpu::new(Variant::Schip);
+        cpu.set_input_driver(Some(Box::new(ScriptedInput { keys: Rc::clone(&keys) })));
+
+        /* add v0, 1 four times; ld v1, K; jmp 0x200 */
+        cpu.load(&[0x70, 0x01, 0x70, 0x01, 0x70, 0x01, 0x70, 0x01, 0xf1, 0x0a, 0x12, 0x00])
+            .unwrap();
+
+        assert_eq!(cpu.run_budget(3), Ok(3));
+        assert_eq!(cpu.v[0], 3);
+
+        keys.borrow_mut().push_back(None);
+        assert_eq!(cpu.run_budget(10), Ok(1));
+        assert_eq!(cpu.state(), RunState::WaitingForKey);
+        assert_eq!(cpu.pc, 0x208);
+
+        keys.borrow_mut().push_back(Some(0xa));
+        assert_eq!(cpu.run_budget(2), Ok(2));
+        assert_eq!(cpu.v[1], 0xa);
+        assert_eq!(cpu.pc, 0x200);
+
+        assert_eq!(cpu.run_budget(0), Ok(0));
+    }
+
+    #[test]
+    fn stack_depth() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        assert_eq!(cpu.stack_depth(), Cpu::MAX_STACK_DEPTH);
+
+        /* A routine that does nothing but call itself. */
+        cpu.set_stack_depth(4);
+        cpu.load(&rom![Call(0x200)]).unwrap();
+        for depth in 1..=4 {
+            assert_eq!(cpu.tick(), Ok(()));
+            assert_eq!(cpu.sp, depth);
+        }
+        assert_eq!(cpu.tick(), Err(Error::StackOverflow));
+        assert_eq!(cpu.sp, 4);
+
+        cpu.reset();
+        assert_eq!((cpu.stack_depth(), cpu.sp), (4, 0));
+
+        cpu.set_stack_depth(0);
+        assert_eq!(cpu.stack_depth(), 1);
+    }
+
+    #[test]
+    fn lint_rom() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.load(&rom![Ld(Reg(0), 0x01), Scr, Sys(0x300), Jmp(0x200)]).unwrap();
+
+        assert_eq!(cpu.lint_rom(Variant::Chip8), [
+            (0x202, Op::Scr, LintIssue::Unsupported),
+            (0x204, Op::Sys(0x300), LintIssue::MachineCode),
+        ]);
+        assert_eq!(cpu.lint_rom(Variant::Schip), [(0x204, Op::Sys(0x300), LintIssue::MachineCode)]);
+        assert_eq!(cpu.lint_rom(Variant::XoChip), cpu.lint_rom(Variant::Schip));
+    }
+
+    #[test]
+    fn font_address() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        assert_eq!(cpu.font_address(), 0x000);
+
+        cpu.set_font_address(0x050).unwrap();
+        cpu.exec_all(&[Op::Ld(Reg(0), 0xa), Op::Ldspr(Reg(0))]).unwrap();
+        assert_eq!(cpu.i, 0x050 + 5 * 0xa);
+        assert_eq!(cpu.read_memory(cpu.i as usize, 5), Ok(Cpu::font_glyph(0xa)));
+        assert_eq!(cpu.read_memory(0x000, 2), Ok(&[0x00, 0x00][..]));
+
+        cpu.set_protect_font(true);
+        assert_eq!(cpu.exec(Op::Bcd(Reg(0))), Err(Error::ProtectedWrite));
+        cpu.exec_all(&[Op::Ldi(0x000), Op::Bcd(Reg(0))]).unwrap();
+
+        /* The font stays put across a reset. */
+        cpu.reset();
+        assert_eq!(cpu.font_address(), 0x050);
+        assert_eq!(cpu.read_memory(0x050, 5), Ok(Cpu::font_glyph(0)));
+        assert_eq!(cpu.read_memory(0x000, 3), Ok(&[0x00; 3][..]));
+
+        let last = (Cpu::LOAD_OFFSET - 0x50) as u16;
+        assert_eq!(cpu.set_font_address(last + 1), Err(Error::DataAbort));
+        assert_eq!(cpu.font_address(), 0x050);
+        cpu.set_font_address(last).unwrap();
+    }
+
+    #[test]
+    fn variants() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        assert_eq!(cpu.variant(), Variant::Schip);
+        assert_eq!(*cpu.quirks(), Quirks::default());
+        assert_eq!(cpu.ram_bytes(), 0x1000);
+
+        /* Dropping to a variant without hires leaves it, blanking vram. */
+        let frames = Rc::new(RefCell::new(Vec::new()));
+        cpu.set_display_driver(Some(Box::new(FrameLog { frames: Rc::clone(&frames) })));
+        cpu.exec(Op::High).unwrap();
+        cpu.vram[0] = true;
+        cpu.set_variant(Variant::Chip8);
+        assert_eq!(*cpu.quirks(), Variant::Chip8.quirks());
+        assert!(!cpu.hires && !cpu.vram[0]);
+        assert_eq!(frames.borrow().last().map(Vec::len), Some(Cpu::VRAM_BYTES));
+        assert_eq!(cpu.ram_bytes(), Variant::Chip8.ram_bytes());
+        assert_eq!(cpu.exec(Op::High), Err(Error::UnimplementedOp(Op::High)));
+        assert!(!cpu.hires);
+        assert_eq!(cpu.exec(Op::Scr), Err(Error::UnimplementedOp(Op::Scr)));
+
+        /* Quirks can still be overridden afterwards. */
+        cpu.set_quirks(Quirks::default());
+        assert_eq!(cpu.variant(), Variant::Chip8);
+
+        let mut cpu = Cpu::new(Variant::XoChip);
+        assert!(cpu.quirks().shift_uses_vy);
+        cpu.set_display_driver(Some(Box::new(FrameLog { frames })));
+        assert_eq!(cpu.exec(Op::High), Ok(()));
+        assert!(cpu.hires);
+
+        /* XO-CHIP's RAM goes all the way to 0xffff, and survives a reset. */
+        assert_eq!(cpu.ram_bytes(), 0x10000);
+        assert_eq!(cpu.set_index(0xfffe), Ok(()));
+        cpu.reset();
+        assert_eq!(cpu.ram_bytes(), 0x10000);
+        assert_eq!(cpu.read_memory(0xffff, 1), Ok(&[0xff][..]));
+        cpu.set_variant(Variant::Schip);
+        assert_eq!(cpu.set_index(0x1000), Err(Error::DataAbort));
+    }
+
+    #[test]
+    fn trace_hook() {
+        let entries = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&entries);
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.set_trace_hook(Some(Box::new(move |e: &TraceEntry| log.borrow_mut().push(e.clone()))));
+        cpu.load(&rom![Ld(Reg(3), 0x2a), Ret]).unwrap();
+
+        cpu.tick().unwrap();
+        assert_eq!(cpu.tick(), Err(Error::StackUnderflow));
+        let mut regs = [0; Cpu::REG_COUNT];
+        regs[3] = 0x2a;
+        assert_eq!(*entries.borrow(), [
+            TraceEntry { pc: 0x200, opcode: 0x632a, op: Op::Ld(Reg(3), 0x2a), regs, error: None },
+            TraceEntry {
+                pc: 0x202, opcode: 0x00ee, op: Op::Ret, regs,
+                error: Some(Error::StackUnderflow),
+            },
+        ]);
+
+        cpu.set_trace_hook(None);
+        cpu.reset();
+        cpu.load(&rom![Ld(Reg(3), 0x2a)]).unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(entries.borrow().len(), 2);
+    }
+
+    /* A key that reads the other way every time it's polled. */
+    struct FlickeringKey(Cell<bool>);
+
+    impl driver::Input for FlickeringKey {
+        fn poll(&self, _key: u8) -> bool {
+            self.0.set(!self.0.get());
+            self.0.get()
+        }
+
+        fn block(&self) -> u8 {
+            0x0
+        }
+    }
+
+    #[test]
+    fn latch_input_per_frame() {
+        let skips = |cpu: &mut Cpu| -> Vec<bool> {
+            (0..3).map(|_| {
+                let pc = cpu.pc;
+                cpu.exec(Op::Skp(Reg(0))).unwrap();
+                let skipped = cpu.pc == pc + 4;
+                cpu.pc = pc;
+                skipped
+            }).collect()
+        };
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.load(&rom![Jmp(0x200)]).unwrap();
+        cpu.set_input_driver(Some(Box::new(FlickeringKey(Cell::new(false)))));
+        cpu.exec(Op::Ld(Reg(0), 0x5)).unwrap();
+        cpu.pc = 0x200;
+
+        assert_eq!(skips(&mut cpu), [true, false, true]);
+
+        cpu.set_latch_input_per_frame(true);
+        assert_eq!(skips(&mut cpu), [false; 3]);
+        cpu.exec(Op::Sknp(Reg(0))).unwrap();
+        assert_eq!(cpu.pc, 0x204);
+
+        /* The next frame takes a fresh reading. */
+        cpu.pc = 0x200;
+        cpu.tick_timers();
+        assert_eq!(skips(&mut cpu), [true; 3]);
+        assert!(!cpu.key_pressed(0x5));
+    }
+
+    #[test]
+    fn latch_clears_each_frame_under_tick() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.set_input_driver(Some(Box::new(FlickeringKey(Cell::new(false)))));
+        cpu.set_latch_input_per_frame(true);
+
+        /* 0x200: ld v0, 0x5; 0x202: skp v0; 0x204: jp 0x202; 0x206: jp 0x202 */
+        cpu.load(&[0x60, 0x05, 0xe0, 0x9e, 0x12, 0x02, 0x12, 0x02]).unwrap();
+        let mut landed = Vec::new();
+        for frame in 0..3 {
+            if frame > 0 {
+                cpu.tick_timers();
+            }
+            for _ in 0..4 {
+                cpu.tick().unwrap();
+                landed.push(cpu.pc);
+            }
+        }
+
+        /* The key reads down, up, down from frame to frame, and holds still
+           within each one. */
+        assert_eq!(landed, [
+            0x202, 0x206, 0x202, 0x206,
+            0x202, 0x204, 0x202, 0x204,
+            0x202, 0x206, 0x202, 0x206,
+        ]);
     }
 }
@@ -0,0 +1,36 @@
+use super::cpu::Cpu;
+use super::op::Op;
+
+/* A listing of every word in rom as it would sit in memory, one per line:
+
+       0200  6a05  LD VA, 0x05
+       0202  ffff  ???
+
+   Words that don't decode are marked ??? and a trailing odd byte is listed
+   on its own. Data mixed in with code gets decoded like anything else. */
+pub fn listing(rom: &[u8]) -> String {
+    let mut out = String::new();
+    for (n, word) in rom.chunks(2).enumerate() {
+        let addr = Cpu::LOAD_OFFSET + n * 2;
+        if let [hi, lo] = *word {
+            let code = ((hi as u16) << 8) | lo as u16;
+            let mnemonic = Op::decode(code).map_or("???".to_owned(), |op| op.to_string());
+            out.push_str(&format!("{:04x}  {:04x}  {}\n", addr, code, mnemonic));
+        } else {
+            out.push_str(&format!("{:04x}  {:02x}\n", addr, word[0]));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn listing_lines() {
+        assert_eq!(listing(&[0x6a, 0x05, 0xff, 0xff, 0x12]),
+                   "0200  6a05  LD VA, 0x05\n0202  ffff  ???\n0204  12\n");
+        assert_eq!(listing(&[]), "");
+    }
+}
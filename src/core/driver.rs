@@ -9,8 +9,24 @@ pub trait Sound: Send {
     fn stop_buzz(&self);
 }
 
+/* What block and try_block hand back for a wait that was cancelled rather
+   than ended by a key press. It's outside the keypad, so it can't be
+   mistaken for a real key. */
+pub const KEY_CANCELLED: u8 = 0xff;
+
 pub trait Input {
     fn poll(&self, key: u8) -> bool;
 
     fn block(&self) -> u8;
+
+    /* Non-blocking variant of block. Returns None until a key is available;
+       implementations that can't wait asynchronously may simply block. */
+    fn try_block(&self) -> Option<u8> {
+        Some(self.block())
+    }
+
+    /* Ends a pending block or try_block with KEY_CANCELLED. Does nothing if
+       no block is pending, or for drivers whose blocks always end on their
+       own. */
+    fn cancel_block(&self) {}
 }
@@ -1,16 +1,22 @@
 use std::error;
 use std::fmt;
+use std::io;
 
 use super::op::Op;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Error {
     BadInstruction,
+    BadJump(u16),
     DataAbort,
     DriverMissing,
+    HaltLoop(u16),
+    IndexOverflow(u32),
+    Io(io::ErrorKind),
     LoadFailure,
     MalformedOp(Op),
     PrefetchAbort,
+    ProtectedWrite,
     StackOverflow,
     StackUnderflow,
     UnimplementedOp(Op),
@@ -22,17 +28,23 @@ impl error::Error for Error {}
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self)
+        write!(f, "{:?}", self)
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e.kind())
     }
 }
 
 impl Error {
     pub fn fatal(&self) -> bool {
-        match *self {
-            Error::DriverMissing => false,
-            Error::MalformedOp(_) => false,
-            Error::UnimplementedOp(_) => false,
-            _ => true,
-        }
+        !matches!(*self,
+                  Error::DriverMissing |
+                  Error::HaltLoop(_) |
+                  Error::MalformedOp(_) |
+                  Error::ProtectedWrite |
+                  Error::UnimplementedOp(_))
     }
 }
@@ -0,0 +1,329 @@
+use super::crc::crc32;
+use super::error::{Result, Error};
+
+/* Just enough gzip (RFC 1952) and DEFLATE (RFC 1951) to unpack a ROM. Any
+   malformed input is a LoadFailure, as is output longer than the caller's
+   max_len, so a small bomb can't inflate into an unbounded buffer. */
+
+const MAGIC: [u8; 2] = [0x1f, 0x8b];
+const METHOD_DEFLATE: u8 = 8;
+
+const FHCRC: u8 = 0x02;
+const FEXTRA: u8 = 0x04;
+const FNAME: u8 = 0x08;
+const FCOMMENT: u8 = 0x10;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DIST_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DIST_EXTRA: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+
+/* Order in which code length code lengths are stored in a dynamic block. */
+const CLEN_ORDER: [usize; 19] = [
+    16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15,
+];
+
+pub fn is_gzip(data: &[u8]) -> bool {
+    data.starts_with(&MAGIC)
+}
+
+pub fn decompress(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    if data.len() < 18 || !is_gzip(data) || data[2] != METHOD_DEFLATE {
+        return Err(Error::LoadFailure);
+    }
+
+    let flags = data[3];
+    let mut pos = 10;
+
+    if flags & FEXTRA != 0 {
+        let xlen = *data.get(pos).ok_or(Error::LoadFailure)? as usize |
+                   (*data.get(pos + 1).ok_or(Error::LoadFailure)? as usize) << 8;
+        pos += 2 + xlen;
+    }
+    for flag in &[FNAME, FCOMMENT] {
+        if flags & flag != 0 {
+            let len = data.get(pos..)
+                .and_then(|rest| rest.iter().position(|&b| b == 0))
+                .ok_or(Error::LoadFailure)?;
+            pos += len + 1;
+        }
+    }
+    if flags & FHCRC != 0 {
+        pos += 2;
+    }
+
+    let body = data.get(pos..data.len() - 8).ok_or(Error::LoadFailure)?;
+    let out = inflate(body, max_len)?;
+
+    let trailer = &data[data.len() - 8..];
+    let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+    let size = u32::from_le_bytes([trailer[4], trailer[5], trailer[6], trailer[7]]);
+    if crc != crc32(&out) || size != out.len() as u32 {
+        return Err(Error::LoadFailure);
+    }
+
+    Ok(out)
+}
+
+struct Bits<'a> {
+    data: &'a [u8],
+    pos: usize,
+    buf: u32,
+    count: u32,
+}
+
+impl<'a> Bits<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Bits { data, pos: 0, buf: 0, count: 0 }
+    }
+
+    /* DEFLATE packs bits starting from the least significant. */
+    fn take(&mut self, n: u32) -> Result<u32> {
+        while self.count < n {
+            let byte = *self.data.get(self.pos).ok_or(Error::LoadFailure)?;
+            self.buf |= (byte as u32) << self.count;
+            self.pos += 1;
+            self.count += 8;
+        }
+        let val = self.buf & ((1u64 << n) - 1) as u32;
+        self.buf >>= n;
+        self.count -= n;
+        Ok(val)
+    }
+
+    /* Skips to the next byte boundary, handing back any whole bytes that
+       were buffered but not consumed. */
+    fn align(&mut self) {
+        self.pos -= (self.count / 8) as usize;
+        self.buf = 0;
+        self.count = 0;
+    }
+}
+
+/* Canonical Huffman code, stored as the number of codes of each length
+   followed by the symbols in code order. */
+struct Huffman {
+    counts: [u16; 16],
+    symbols: Vec<u16>,
+}
+
+impl Huffman {
+    fn new(lengths: &[u8]) -> Self {
+        let mut counts = [0u16; 16];
+        for &len in lengths {
+            counts[len as usize] += 1;
+        }
+        counts[0] = 0;
+
+        let mut offsets = [0u16; 16];
+        for len in 1..16 {
+            offsets[len] = offsets[len - 1] + counts[len - 1];
+        }
+
+        let mut symbols = vec![0; lengths.len()];
+        for (sym, &len) in lengths.iter().enumerate() {
+            if len != 0 {
+                symbols[offsets[len as usize] as usize] = sym as u16;
+                offsets[len as usize] += 1;
+            }
+        }
+
+        Huffman { counts, symbols }
+    }
+
+    fn decode(&self, bits: &mut Bits) -> Result<u16> {
+        let (mut code, mut first, mut index) = (0i32, 0i32, 0i32);
+        for len in 1..16 {
+            code |= bits.take(1)? as i32;
+            let count = self.counts[len] as i32;
+            if code - first < count {
+                return Ok(self.symbols[(index + code - first) as usize]);
+            }
+            index += count;
+            first = (first + count) << 1;
+            code <<= 1;
+        }
+        Err(Error::LoadFailure)
+    }
+}
+
+fn inflate(data: &[u8], max_len: usize) -> Result<Vec<u8>> {
+    let mut bits = Bits::new(data);
+    let mut out = Vec::new();
+
+    loop {
+        let last = bits.take(1)? == 1;
+        match bits.take(2)? {
+            0 => {
+                bits.align();
+                let start = bits.pos;
+                let header = data.get(start..start + 4).ok_or(Error::LoadFailure)?;
+                let len = u16::from_le_bytes([header[0], header[1]]);
+                let nlen = u16::from_le_bytes([header[2], header[3]]);
+                if len != !nlen {
+                    return Err(Error::LoadFailure);
+                }
+                let stored = data.get(start + 4..start + 4 + len as usize)
+                    .ok_or(Error::LoadFailure)?;
+                if out.len() + stored.len() > max_len {
+                    return Err(Error::LoadFailure);
+                }
+                out.extend_from_slice(stored);
+                bits.pos = start + 4 + len as usize;
+            },
+            1 => {
+                let mut lengths = [0u8; 288];
+                lengths[..144].iter_mut().for_each(|l| *l = 8);
+                lengths[144..256].iter_mut().for_each(|l| *l = 9);
+                lengths[256..280].iter_mut().for_each(|l| *l = 7);
+                lengths[280..].iter_mut().for_each(|l| *l = 8);
+                let lit = Huffman::new(&lengths);
+                let dist = Huffman::new(&[5; 30]);
+                inflate_block(&mut bits, &mut out, max_len, &lit, &dist)?;
+            },
+            2 => {
+                let (lit, dist) = dynamic_codes(&mut bits)?;
+                inflate_block(&mut bits, &mut out, max_len, &lit, &dist)?;
+            },
+            _ => return Err(Error::LoadFailure),
+        }
+
+        if last {
+            return Ok(out);
+        }
+    }
+}
+
+fn dynamic_codes(bits: &mut Bits) -> Result<(Huffman, Huffman)> {
+    let nlit = bits.take(5)? as usize + 257;
+    let ndist = bits.take(5)? as usize + 1;
+    let nclen = bits.take(4)? as usize + 4;
+
+    let mut clens = [0u8; 19];
+    for &i in &CLEN_ORDER[..nclen] {
+        clens[i] = bits.take(3)? as u8;
+    }
+    let clen = Huffman::new(&clens);
+
+    let mut lengths = Vec::with_capacity(nlit + ndist);
+    while lengths.len() < nlit + ndist {
+        let (val, repeat) = match clen.decode(bits)? {
+            sym @ 0..=15 => (sym as u8, 1),
+            16 => (*lengths.last().ok_or(Error::LoadFailure)?, 3 + bits.take(2)?),
+            17 => (0, 3 + bits.take(3)?),
+            18 => (0, 11 + bits.take(7)?),
+            _ => return Err(Error::LoadFailure),
+        };
+        for _ in 0..repeat {
+            lengths.push(val);
+        }
+    }
+    if lengths.len() != nlit + ndist {
+        return Err(Error::LoadFailure);
+    }
+
+    Ok((Huffman::new(&lengths[..nlit]), Huffman::new(&lengths[nlit..])))
+}
+
+fn inflate_block(bits: &mut Bits, out: &mut Vec<u8>, max_len: usize,
+                 lit: &Huffman, dist: &Huffman) -> Result<()> {
+    loop {
+        match lit.decode(bits)? {
+            sym @ 0..=255 if out.len() < max_len => out.push(sym as u8),
+            0..=255 => return Err(Error::LoadFailure),
+            256 => return Ok(()),
+            sym => {
+                let sym = sym as usize - 257;
+                if sym >= LENGTH_BASE.len() {
+                    return Err(Error::LoadFailure);
+                }
+                let len = LENGTH_BASE[sym] as usize +
+                          bits.take(LENGTH_EXTRA[sym] as u32)? as usize;
+
+                let sym = dist.decode(bits)? as usize;
+                if sym >= DIST_BASE.len() {
+                    return Err(Error::LoadFailure);
+                }
+                let back = DIST_BASE[sym] as usize +
+                           bits.take(DIST_EXTRA[sym] as u32)? as usize;
+                if back > out.len() || out.len() + len > max_len {
+                    return Err(Error::LoadFailure);
+                }
+
+                /* Copies may overlap what they produce, so go a byte at a
+                   time. */
+                let start = out.len() - back;
+                for n in 0..len {
+                    out.push(out[start + n]);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MAX: usize = 0x1000;
+
+    /* "hello hello hello hello\n", a single fixed Huffman block. */
+    const FIXED_GZ: [u8; 29] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0xcb, 0x48,
+        0xcd, 0xc9, 0xc9, 0x57, 0xc8, 0x40, 0x27, 0xb9, 0x00, 0x00, 0x88, 0x59,
+        0x0b, 0x18, 0x00, 0x00, 0x00,
+    ];
+
+    /* Skewed enough that gzip picks a dynamic Huffman block. */
+    const DYNAMIC_TEXT: &[u8] = b"badcaaabbacaaacbcbababcbabaaddbabaabaabc";
+    const DYNAMIC_GZ: [u8; 44] = [
+        0x1f, 0x8b, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x02, 0x03, 0x1d, 0x85,
+        0x89, 0x0d, 0x00, 0x00, 0x10, 0x43, 0x66, 0xa5, 0xdd, 0x7f, 0x86, 0x7b,
+        0x42, 0x90, 0x06, 0x50, 0x6e, 0x31, 0xb2, 0x7c, 0x69, 0x7f, 0x67, 0x06,
+        0x76, 0x14, 0x4a, 0x1b, 0x28, 0x00, 0x00, 0x00,
+    ];
+
+    #[test]
+    fn stored_block() {
+        let stored = [0x01, 0x03, 0x00, 0xfc, 0xff, b'a', b'b', b'c'];
+        assert_eq!(inflate(&stored, 3), Ok(b"abc".to_vec()));
+        assert_eq!(inflate(&stored, 2), Err(Error::LoadFailure));
+    }
+
+    #[test]
+    fn huffman_blocks() {
+        assert_eq!(decompress(&FIXED_GZ, MAX), Ok(b"hello hello hello hello\n".to_vec()));
+        assert_eq!(decompress(&DYNAMIC_GZ, MAX), Ok(DYNAMIC_TEXT.to_vec()));
+    }
+
+    #[test]
+    fn bad_input() {
+        assert!(!is_gzip(&[0x12, 0x00]));
+        assert_eq!(decompress(&FIXED_GZ[..20], MAX), Err(Error::LoadFailure));
+
+        let mut corrupt = FIXED_GZ;
+        corrupt[12] ^= 0xff;
+        assert_eq!(decompress(&corrupt, MAX), Err(Error::LoadFailure));
+    }
+
+    #[test]
+    fn output_limit() {
+        /* FIXED_GZ inflates to 24 bytes, mostly from a back reference. */
+        assert!(decompress(&FIXED_GZ, 24).is_ok());
+        assert_eq!(decompress(&FIXED_GZ, 23), Err(Error::LoadFailure));
+        assert_eq!(decompress(&FIXED_GZ, 4), Err(Error::LoadFailure));
+        assert_eq!(decompress(&DYNAMIC_GZ, DYNAMIC_TEXT.len() - 1), Err(Error::LoadFailure));
+    }
+}
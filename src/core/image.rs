@@ -0,0 +1,112 @@
+use super::crc::crc32;
+
+/* Renders a sprite to a PNG, for documenting ROMs. Each of the rows bytes
+   from addr on is one row of eight pixels, every pixel scale x scale image
+   pixels, lit white on black. Rows past the end of ram come out blank. */
+pub fn sprite_to_image(ram: &[u8], addr: usize, rows: usize, scale: usize) -> Vec<u8> {
+    let scale = scale.max(1);
+    let width = 8 * scale;
+    let height = rows * scale;
+    let row_bytes = width / 8;
+
+    let mut pixels = Vec::with_capacity(height * (row_bytes + 1));
+    for n in 0..rows {
+        let byte = addr.checked_add(n).and_then(|a| ram.get(a)).cloned().unwrap_or(0);
+        let mut line = vec![0u8; row_bytes];
+        for x in 0..width {
+            if byte & (0x80 >> (x / scale)) != 0 {
+                line[x / 8] |= 0x80 >> (x % 8);
+            }
+        }
+        for _ in 0..scale {
+            /* Filter type None. */
+            pixels.push(0);
+            pixels.extend_from_slice(&line);
+        }
+    }
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    /* 1 bit grayscale, default compression and filtering, no interlace. */
+    ihdr.extend_from_slice(&[1, 0, 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+    chunk(&mut png, b"IHDR", &ihdr);
+    chunk(&mut png, b"IDAT", &zlib_stored(&pixels));
+    chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+/* A zlib stream of stored DEFLATE blocks. Sprites are tiny, so there is
+   nothing to gain from actually compressing them. */
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xffff).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[0x01, 0x00, 0x00, 0xff, 0xff]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let len = block.len() as u16;
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::cpu::Cpu;
+
+    fn be32(bytes: &[u8]) -> u32 {
+        u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+    }
+
+    #[test]
+    fn font_glyph() {
+        let png = sprite_to_image(Cpu::font_glyph(0), 0, 5, 4);
+
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(be32(&png[8..]), 13);
+        assert_eq!(&png[12..16], b"IHDR");
+        assert_eq!((be32(&png[16..]), be32(&png[20..])), (32, 20));
+        assert_eq!(be32(&png[29..]), crc32(&png[12..29]));
+        assert!(png.ends_with(b"IEND\xae\x42\x60\x82"));
+
+        /* The top row of '0' is 0xf0: the left half lit, every line
+           repeated scale times. */
+        let idat = &png[41..];
+        let pixels = &idat[2 + 5..];
+        assert_eq!(&pixels[..5], [0x00, 0xff, 0xff, 0x00, 0x00]);
+        assert_eq!(&pixels[15..20], [0x00, 0xff, 0xff, 0x00, 0x00]);
+        assert_eq!(&pixels[20..25], [0x00, 0xf0, 0x0f, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        assert_eq!(zlib_stored(b""), [0x78, 0x01, 0x01, 0x00, 0x00, 0xff, 0xff, 0, 0, 0, 1]);
+    }
+}
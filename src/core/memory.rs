@@ -0,0 +1,122 @@
+use super::error::{Result, Error};
+
+/* Bounds checked RAM. Every access outside of the address space is a
+   DataAbort rather than a panic. */
+pub struct Memory {
+    bytes: Vec<u8>,
+}
+
+impl Memory {
+    /* The standard 4K. Variants with more RAM resize from there. */
+    pub const BYTES: usize = 0x1000;
+
+    pub fn new() -> Self {
+        Self::filled(0x00)
+    }
+
+    pub fn filled(val: u8) -> Self {
+        Memory {
+            bytes: vec![val; Self::BYTES],
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    /* Growing fills the new bytes with val; shrinking drops the top. */
+    pub fn resize(&mut self, size: usize, val: u8) {
+        self.bytes.resize(size, val);
+    }
+
+    pub fn read(&self, addr: usize) -> Result<u8> {
+        self.bytes.get(addr).cloned().ok_or(Error::DataAbort)
+    }
+
+    pub fn write(&mut self, addr: usize, val: u8) -> Result<()> {
+        let byte = self.bytes.get_mut(addr).ok_or(Error::DataAbort)?;
+        *byte = val;
+        Ok(())
+    }
+
+    /* Words are stored big-endian, high byte first. */
+    pub fn read_u16(&self, addr: usize) -> Result<u16> {
+        let h = self.read(addr)? as u16;
+        let l = self.read(addr + 1)? as u16;
+        Ok((h << 8) | l)
+    }
+
+    pub fn slice(&self, addr: usize, len: usize) -> Result<&[u8]> {
+        self.bytes.get(addr..addr + len).ok_or(Error::DataAbort)
+    }
+
+    pub fn slice_mut(&mut self, addr: usize, len: usize) -> Result<&mut [u8]> {
+        self.bytes.get_mut(addr..addr + len).ok_or(Error::DataAbort)
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_write() {
+        let mut mem = Memory::new();
+
+        mem.write(0x123, 0xab).unwrap();
+        assert_eq!(mem.read(0x123), Ok(0xab));
+        assert_eq!(mem.read(Memory::BYTES), Err(Error::DataAbort));
+        assert_eq!(mem.write(Memory::BYTES, 0), Err(Error::DataAbort));
+
+        mem.write(0x124, 0xcd).unwrap();
+        assert_eq!(mem.read_u16(0x123), Ok(0xabcd));
+    }
+
+    #[test]
+    fn words() {
+        let mut mem = Memory::new();
+
+        mem.slice_mut(0x800, 2).unwrap().copy_from_slice(&[0x12, 0x34]);
+        assert_eq!(mem.read_u16(0x800), Ok(0x1234));
+
+        /* The last byte has no partner, and reading past it must fail
+           rather than panic. */
+        mem.write(Memory::BYTES - 1, 0xff).unwrap();
+        assert_eq!(mem.read_u16(Memory::BYTES - 2), Ok(0x00ff));
+        assert_eq!(mem.read_u16(Memory::BYTES - 1), Err(Error::DataAbort));
+        assert_eq!(mem.read_u16(Memory::BYTES), Err(Error::DataAbort));
+        assert_eq!(mem.read_u16(usize::MAX), Err(Error::DataAbort));
+    }
+
+    #[test]
+    fn slices() {
+        let mut mem = Memory::filled(0xff);
+
+        mem.slice_mut(0xffe, 2).unwrap().copy_from_slice(&[1, 2]);
+        assert_eq!(mem.slice(0xffd, 3), Ok(&[0xff, 1, 2][..]));
+        assert_eq!(mem.slice(0xffe, 3), Err(Error::DataAbort));
+        assert!(mem.slice_mut(Memory::BYTES, 1).is_err());
+        assert_eq!(mem.slice(Memory::BYTES, 0), Ok(&[][..]));
+    }
+
+    #[test]
+    fn resize() {
+        let mut mem = Memory::new();
+        mem.write(0xfff, 0x12).unwrap();
+
+        mem.resize(0x10000, 0xff);
+        assert_eq!(mem.size(), 0x10000);
+        assert_eq!(mem.slice(0xfff, 2), Ok(&[0x12, 0xff][..]));
+        assert_eq!(mem.read(0x10000), Err(Error::DataAbort));
+
+        mem.resize(Memory::BYTES, 0xff);
+        assert_eq!(mem.read(0x1000), Err(Error::DataAbort));
+        assert_eq!(mem.read(0xfff), Ok(0x12));
+    }
+}
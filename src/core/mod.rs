@@ -1,5 +1,20 @@
+#[cfg(any(feature = "gzip", feature = "image"))]
+mod crc;
 pub mod cpu;
+pub mod disasm;
 pub mod driver;
 pub mod error;
+#[cfg(feature = "gzip")]
+pub mod gzip;
+#[cfg(feature = "image")]
+pub mod image;
+pub mod memory;
 pub mod op;
+pub mod pacer;
+pub mod quirks;
+pub mod screen;
+#[cfg(test)]
+pub mod test_support;
 pub mod timer;
+pub mod variant;
+pub mod vram;
@@ -1,3 +1,7 @@
+use std::fmt;
+
+pub use super::variant::Variant;
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Reg(pub usize);
 
@@ -5,6 +9,11 @@ pub struct Reg(pub usize);
 pub enum Op {
     Cls,
     Ret,
+    Scd(u8),
+    Scr,
+    Scl,
+    Low,
+    High,
     Sys(u16),
     Jmp(u16),
     Call(u16),
@@ -40,7 +49,99 @@ pub enum Op {
     Read(Reg),
 }
 
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum OpCategory {
+    Flow,
+    Branch,
+    Arithmetic,
+    Memory,
+    Display,
+    Input,
+    Timer,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LintIssue {
+    /* Only exists in a later variant. */
+    Unsupported,
+    /* 0NNN calls into native code on the original hardware, which this
+       emulator can't run under any variant. */
+    MachineCode,
+}
+
 impl Op {
+    pub fn category(&self) -> OpCategory {
+        match *self {
+            Op::Sys(_) | Op::Jmp(_) | Op::Call(_) | Op::Ret | Op::Jmpi(_) => OpCategory::Flow,
+            Op::Se(..) | Op::Sne(..) | Op::Sre(..) | Op::Srne(..) => OpCategory::Branch,
+            Op::Ld(..) | Op::Add(..) | Op::Mov(..) | Op::Or(..) | Op::And(..) |
+            Op::Xor(..) | Op::Addr(..) | Op::Subr(..) | Op::Shr(..) |
+            Op::Subnr(..) | Op::Shl(..) | Op::Rand(..) => OpCategory::Arithmetic,
+            Op::Ldi(_) | Op::Addi(_) | Op::Ldspr(_) | Op::Bcd(_) | Op::Str(_) |
+            Op::Read(_) => OpCategory::Memory,
+            Op::Cls | Op::Draw(..) | Op::Scd(_) | Op::Scr | Op::Scl | Op::Low |
+            Op::High => OpCategory::Display,
+            Op::Skp(_) | Op::Sknp(_) | Op::Key(_) => OpCategory::Input,
+            Op::Movd(_) | Op::Ldd(_) | Op::Lds(_) => OpCategory::Timer,
+        }
+    }
+
+    /* Rough COSMAC VIP machine cycles, including the interpreter's fetch and
+       decode overhead. Draw and Cls leave out the wait for the display
+       interrupt, and ops that loop over registers or rows scale with them. */
+    pub fn cycle_cost(&self) -> u32 {
+        const FETCH: u32 = 40;
+
+        FETCH + match *self {
+            Op::Ld(..) => 6,
+            Op::Se(..) | Op::Sne(..) | Op::Ret | Op::Movd(_) | Op::Ldd(_) |
+            Op::Lds(_) | Op::Add(..) => 10,
+            Op::Jmp(_) | Op::Ldi(_) | Op::Sys(_) => 12,
+            Op::Sre(..) | Op::Srne(..) | Op::Skp(_) | Op::Sknp(_) | Op::Key(_) => 14,
+            Op::Addi(_) => 16,
+            Op::Ldspr(_) => 20,
+            Op::Jmpi(_) => 22,
+            Op::Cls | Op::Scd(_) | Op::Scr | Op::Scl | Op::Low | Op::High => 24,
+            Op::Call(_) => 26,
+            Op::Rand(..) => 36,
+            Op::Mov(..) | Op::Or(..) | Op::And(..) | Op::Xor(..) | Op::Addr(..) |
+            Op::Subr(..) | Op::Shr(..) | Op::Subnr(..) | Op::Shl(..) => 44,
+            Op::Bcd(_) => 84,
+            Op::Str(Reg(x)) | Op::Read(Reg(x)) => 14 + 14 * (x as u32 + 1),
+            Op::Draw(_, _, m) => 46 + 58 * m as u32,
+        }
+    }
+
+    /* Whether a ROM targeting variant has any business using this op. */
+    pub fn lint(&self, variant: Variant) -> Option<LintIssue> {
+        match *self {
+            Op::Sys(_) => Some(LintIssue::MachineCode),
+            _ if !variant.supports(self) => Some(LintIssue::Unsupported),
+            _ => None,
+        }
+    }
+
+    /* The V registers this op names, in operand order. */
+    pub fn regs(&self) -> Vec<usize> {
+        match *self {
+            Op::Sre(Reg(x), Reg(y)) | Op::Mov(Reg(x), Reg(y)) | Op::Or(Reg(x), Reg(y)) |
+            Op::And(Reg(x), Reg(y)) | Op::Xor(Reg(x), Reg(y)) | Op::Addr(Reg(x), Reg(y)) |
+            Op::Subr(Reg(x), Reg(y)) | Op::Shr(Reg(x), Reg(y)) | Op::Subnr(Reg(x), Reg(y)) |
+            Op::Shl(Reg(x), Reg(y)) | Op::Srne(Reg(x), Reg(y)) |
+            Op::Draw(Reg(x), Reg(y), _) => vec![x, y],
+            Op::Se(Reg(x), _) | Op::Sne(Reg(x), _) | Op::Ld(Reg(x), _) | Op::Add(Reg(x), _) |
+            Op::Rand(Reg(x), _) | Op::Skp(Reg(x)) | Op::Sknp(Reg(x)) | Op::Movd(Reg(x)) |
+            Op::Key(Reg(x)) | Op::Ldd(Reg(x)) | Op::Lds(Reg(x)) | Op::Addi(Reg(x)) |
+            Op::Ldspr(Reg(x)) | Op::Bcd(Reg(x)) | Op::Str(Reg(x)) |
+            Op::Read(Reg(x)) => vec![x],
+            _ => Vec::new(),
+        }
+    }
+
+    pub fn is_valid(code: u16) -> bool {
+        Self::decode(code).is_some()
+    }
+
     pub fn decode(code: u16) -> Option<Self> {
         let nib3 = ((code & 0xf000) >> 12) as u8;
         let nib2 = ((code & 0xf00) >> 8) as u8;
@@ -55,6 +156,11 @@ impl Op {
         match (nib3, nib2, nib1, nib0) {
             (0, 0, 0xe, 0) => Some(Op::Cls),
             (0, 0, 0xe, 0xe) => Some(Op::Ret),
+            (0, 0, 0xc, n) => Some(Op::Scd(n)),
+            (0, 0, 0xf, 0xb) => Some(Op::Scr),
+            (0, 0, 0xf, 0xc) => Some(Op::Scl),
+            (0, 0, 0xf, 0xe) => Some(Op::Low),
+            (0, 0, 0xf, 0xf) => Some(Op::High),
             (0, _, _, _) => Some(Op::Sys(nnn)),
             (1, _, _, _) => Some(Op::Jmp(nnn)),
             (2, _, _, _) => Some(Op::Call(nnn)),
@@ -69,9 +175,9 @@ impl Op {
             (8, _, _, 3) => Some(Op::Xor(x, y)),
             (8, _, _, 4) => Some(Op::Addr(x, y)),
             (8, _, _, 5) => Some(Op::Subr(x, y)),
-            (8, _, _, 6) => Some(Op::Shr(y, x)),
+            (8, _, _, 6) => Some(Op::Shr(x, y)),
             (8, _, _, 7) => Some(Op::Subnr(x, y)),
-            (8, _, _, 0xe) => Some(Op::Shl(y, x)),
+            (8, _, _, 0xe) => Some(Op::Shl(x, y)),
             (9, _, _, 0) => Some(Op::Srne(x, y)),
             (0xa, _, _, _) => Some(Op::Ldi(nnn)),
             (0xb, _, _, _) => Some(Op::Jmpi(nnn)),
@@ -91,16 +197,135 @@ impl Op {
             _ => None,
         }
     }
+
+    /* The opcode decode would turn back into this op. Registers and
+       operands are masked to the bits the encoding has room for. */
+    pub fn encode(&self) -> u16 {
+        let nnn = |nnn: u16| nnn & 0xfff;
+        let xkk = |hi: u16, &Reg(x): &Reg, kk: u8| hi << 12 | (x as u16 & 0xf) << 8 | kk as u16;
+        let xyn = |hi: u16, &Reg(x): &Reg, &Reg(y): &Reg, n: u8| {
+            hi << 12 | (x as u16 & 0xf) << 8 | (y as u16 & 0xf) << 4 | (n as u16 & 0xf)
+        };
+        let fx = |x: &Reg, kk: u8| xkk(0xf, x, kk);
+
+        match self {
+            Op::Cls => 0x00e0,
+            Op::Ret => 0x00ee,
+            Op::Scd(n) => 0x00c0 | (*n as u16 & 0xf),
+            Op::Scr => 0x00fb,
+            Op::Scl => 0x00fc,
+            Op::Low => 0x00fe,
+            Op::High => 0x00ff,
+            Op::Sys(addr) => nnn(*addr),
+            Op::Jmp(addr) => 0x1000 | nnn(*addr),
+            Op::Call(addr) => 0x2000 | nnn(*addr),
+            Op::Se(x, kk) => xkk(3, x, *kk),
+            Op::Sne(x, kk) => xkk(4, x, *kk),
+            Op::Sre(x, y) => xyn(5, x, y, 0),
+            Op::Ld(x, kk) => xkk(6, x, *kk),
+            Op::Add(x, kk) => xkk(7, x, *kk),
+            Op::Mov(x, y) => xyn(8, x, y, 0),
+            Op::Or(x, y) => xyn(8, x, y, 1),
+            Op::And(x, y) => xyn(8, x, y, 2),
+            Op::Xor(x, y) => xyn(8, x, y, 3),
+            Op::Addr(x, y) => xyn(8, x, y, 4),
+            Op::Subr(x, y) => xyn(8, x, y, 5),
+            Op::Shr(x, y) => xyn(8, x, y, 6),
+            Op::Subnr(x, y) => xyn(8, x, y, 7),
+            Op::Shl(x, y) => xyn(8, x, y, 0xe),
+            Op::Srne(x, y) => xyn(9, x, y, 0),
+            Op::Ldi(addr) => 0xa000 | nnn(*addr),
+            Op::Jmpi(addr) => 0xb000 | nnn(*addr),
+            Op::Rand(x, kk) => xkk(0xc, x, *kk),
+            Op::Draw(x, y, n) => xyn(0xd, x, y, *n),
+            Op::Skp(x) => xkk(0xe, x, 0x9e),
+            Op::Sknp(x) => xkk(0xe, x, 0xa1),
+            Op::Movd(x) => fx(x, 0x07),
+            Op::Key(x) => fx(x, 0x0a),
+            Op::Ldd(x) => fx(x, 0x15),
+            Op::Lds(x) => fx(x, 0x18),
+            Op::Addi(x) => fx(x, 0x1e),
+            Op::Ldspr(x) => fx(x, 0x29),
+            Op::Bcd(x) => fx(x, 0x33),
+            Op::Str(x) => fx(x, 0x55),
+            Op::Read(x) => fx(x, 0x65),
+        }
+    }
+}
+
+/* Cowgod style mnemonics, e.g. "LD V1, 0x2a" or "DRW V0, V1, 5". */
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Op::Cls => write!(f, "CLS"),
+            Op::Ret => write!(f, "RET"),
+            Op::Scd(n) => write!(f, "SCD {}", n),
+            Op::Scr => write!(f, "SCR"),
+            Op::Scl => write!(f, "SCL"),
+            Op::Low => write!(f, "LOW"),
+            Op::High => write!(f, "HIGH"),
+            Op::Sys(nnn) => write!(f, "SYS 0x{:03x}", nnn),
+            Op::Jmp(nnn) => write!(f, "JP 0x{:03x}", nnn),
+            Op::Call(nnn) => write!(f, "CALL 0x{:03x}", nnn),
+            Op::Se(Reg(x), kk) => write!(f, "SE V{:X}, 0x{:02x}", x, kk),
+            Op::Sne(Reg(x), kk) => write!(f, "SNE V{:X}, 0x{:02x}", x, kk),
+            Op::Sre(Reg(x), Reg(y)) => write!(f, "SE V{:X}, V{:X}", x, y),
+            Op::Ld(Reg(x), kk) => write!(f, "LD V{:X}, 0x{:02x}", x, kk),
+            Op::Add(Reg(x), kk) => write!(f, "ADD V{:X}, 0x{:02x}", x, kk),
+            Op::Mov(Reg(x), Reg(y)) => write!(f, "LD V{:X}, V{:X}", x, y),
+            Op::Or(Reg(x), Reg(y)) => write!(f, "OR V{:X}, V{:X}", x, y),
+            Op::And(Reg(x), Reg(y)) => write!(f, "AND V{:X}, V{:X}", x, y),
+            Op::Xor(Reg(x), Reg(y)) => write!(f, "XOR V{:X}, V{:X}", x, y),
+            Op::Addr(Reg(x), Reg(y)) => write!(f, "ADD V{:X}, V{:X}", x, y),
+            Op::Subr(Reg(x), Reg(y)) => write!(f, "SUB V{:X}, V{:X}", x, y),
+            Op::Shr(Reg(x), Reg(y)) => write!(f, "SHR V{:X}, V{:X}", x, y),
+            Op::Subnr(Reg(x), Reg(y)) => write!(f, "SUBN V{:X}, V{:X}", x, y),
+            Op::Shl(Reg(x), Reg(y)) => write!(f, "SHL V{:X}, V{:X}", x, y),
+            Op::Srne(Reg(x), Reg(y)) => write!(f, "SNE V{:X}, V{:X}", x, y),
+            Op::Ldi(nnn) => write!(f, "LD I, 0x{:03x}", nnn),
+            Op::Jmpi(nnn) => write!(f, "JP V0, 0x{:03x}", nnn),
+            Op::Rand(Reg(x), kk) => write!(f, "RND V{:X}, 0x{:02x}", x, kk),
+            Op::Draw(Reg(x), Reg(y), m) => write!(f, "DRW V{:X}, V{:X}, {}", x, y, m),
+            Op::Skp(Reg(x)) => write!(f, "SKP V{:X}", x),
+            Op::Sknp(Reg(x)) => write!(f, "SKNP V{:X}", x),
+            Op::Movd(Reg(x)) => write!(f, "LD V{:X}, DT", x),
+            Op::Key(Reg(x)) => write!(f, "LD V{:X}, K", x),
+            Op::Ldd(Reg(x)) => write!(f, "LD DT, V{:X}", x),
+            Op::Lds(Reg(x)) => write!(f, "LD ST, V{:X}", x),
+            Op::Addi(Reg(x)) => write!(f, "ADD I, V{:X}", x),
+            Op::Ldspr(Reg(x)) => write!(f, "LD F, V{:X}", x),
+            Op::Bcd(Reg(x)) => write!(f, "LD B, V{:X}", x),
+            Op::Str(Reg(x)) => write!(f, "LD [I], V{:X}", x),
+            Op::Read(Reg(x)) => write!(f, "LD V{:X}, [I]", x),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn encode_round_trip() {
+        /* Every opcode that decodes encodes back to itself. */
+        for code in 0..=0xffff {
+            if let Some(op) = Op::decode(code) {
+                assert_eq!(op.encode(), code, "{:?}", op);
+            }
+        }
+        assert_eq!(Op::Ld(Reg(0xa), 0x05).encode(), 0x6a05);
+        assert_eq!(Op::Jmp(0x1234).encode(), 0x1234);
+    }
+
     #[test]
     fn op_decode() {
         assert_eq!(Op::decode(0x00e0), Some(Op::Cls));
         assert_eq!(Op::decode(0x00ee), Some(Op::Ret));
+        assert_eq!(Op::decode(0x00c5), Some(Op::Scd(5)));
+        assert_eq!(Op::decode(0x00fb), Some(Op::Scr));
+        assert_eq!(Op::decode(0x00fc), Some(Op::Scl));
+        assert_eq!(Op::decode(0x00fe), Some(Op::Low));
+        assert_eq!(Op::decode(0x00ff), Some(Op::High));
         assert_eq!(Op::decode(0x0123), Some(Op::Sys(0x123)));
         assert_eq!(Op::decode(0x1456), Some(Op::Jmp(0x456)));
         assert_eq!(Op::decode(0x2789), Some(Op::Call(0x789)));
@@ -115,9 +340,9 @@ mod tests {
         assert_eq!(Op::decode(0x8ef3), Some(Op::Xor(Reg(0xe), Reg(0xf))));
         assert_eq!(Op::decode(0x8014), Some(Op::Addr(Reg(0), Reg(1))));
         assert_eq!(Op::decode(0x8235), Some(Op::Subr(Reg(2), Reg(3))));
-        assert_eq!(Op::decode(0x8456), Some(Op::Shr(Reg(5), Reg(4))));
+        assert_eq!(Op::decode(0x8456), Some(Op::Shr(Reg(4), Reg(5))));
         assert_eq!(Op::decode(0x8677), Some(Op::Subnr(Reg(6), Reg(7))));
-        assert_eq!(Op::decode(0x889e), Some(Op::Shl(Reg(9), Reg(8))));
+        assert_eq!(Op::decode(0x889e), Some(Op::Shl(Reg(8), Reg(9))));
         assert_eq!(Op::decode(0x9ab0), Some(Op::Srne(Reg(0xa), Reg(0xb))));
         assert_eq!(Op::decode(0xacde), Some(Op::Ldi(0xcde)));
         assert_eq!(Op::decode(0xbef0), Some(Op::Jmpi(0xef0)));
@@ -136,5 +361,29 @@ mod tests {
         assert_eq!(Op::decode(0xf165), Some(Op::Read(Reg(1))));
         assert_eq!(Op::decode(0xffff), None);
     }
-}
 
+    #[test]
+    fn op_is_valid() {
+        assert!(Op::is_valid(0x00e0));
+        assert!(Op::is_valid(0xd123));
+        assert!(!Op::is_valid(0x5001));
+        assert!(!Op::is_valid(0xffff));
+    }
+
+    #[test]
+    fn op_cycle_cost() {
+        assert!(Op::Draw(Reg(0), Reg(1), 1).cycle_cost() > Op::Ld(Reg(0), 0).cycle_cost());
+        assert!(Op::Draw(Reg(0), Reg(1), 8).cycle_cost() >
+                Op::Draw(Reg(0), Reg(1), 1).cycle_cost());
+        assert!(Op::Str(Reg(0xf)).cycle_cost() > Op::Str(Reg(0)).cycle_cost());
+    }
+
+    #[test]
+    fn op_display() {
+        assert_eq!(Op::Cls.to_string(), "CLS");
+        assert_eq!(Op::Jmp(0x2a0).to_string(), "JP 0x2a0");
+        assert_eq!(Op::Ld(Reg(0xa), 0x05).to_string(), "LD VA, 0x05");
+        assert_eq!(Op::Draw(Reg(0), Reg(1), 5).to_string(), "DRW V0, V1, 5");
+        assert_eq!(Op::Read(Reg(3)).to_string(), "LD V3, [I]");
+    }
+}
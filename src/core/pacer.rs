@@ -0,0 +1,98 @@
+use std::time::{Duration, Instant};
+
+/* Fixed timestep pacing for a run loop. Real time is accumulated and paid
+   out in whole frames, so a slow frame is made up for by running extra ones
+   afterwards. After a long stall (a window drag, a debugger pause) only
+   max_catch_up frames are run and the rest of the backlog is dropped;
+   otherwise an emulator that can't keep up would fall further behind with
+   every frame it tried to catch up on. */
+pub struct Pacer {
+    frame_time: Duration,
+    max_catch_up: u32,
+    accumulator: Duration,
+    last: Instant,
+}
+
+impl Pacer {
+    pub const DEFAULT_MAX_CATCH_UP: u32 = 5;
+
+    pub fn new(frame_time: Duration, now: Instant) -> Self {
+        Pacer {
+            frame_time,
+            max_catch_up: Self::DEFAULT_MAX_CATCH_UP,
+            accumulator: Duration::from_secs(0),
+            last: now,
+        }
+    }
+
+    pub fn set_max_catch_up(&mut self, frames: u32) {
+        self.max_catch_up = frames.max(1);
+    }
+
+    /* Frames that should be run to be back in step at now. */
+    pub fn frames_due(&mut self, now: Instant) -> u32 {
+        self.accumulator += now.saturating_duration_since(self.last);
+        self.last = now;
+
+        let due = self.accumulator.as_nanos() / self.frame_time.as_nanos().max(1);
+        if due > self.max_catch_up as u128 {
+            self.accumulator = Duration::from_secs(0);
+            self.max_catch_up
+        } else {
+            self.accumulator -= self.frame_time * due as u32;
+            due as u32
+        }
+    }
+
+    /* How long to sleep before the next frame is due. */
+    pub fn until_next(&self, now: Instant) -> Duration {
+        let pending = self.accumulator + now.saturating_duration_since(self.last);
+        self.frame_time.checked_sub(pending).unwrap_or_default()
+    }
+
+    /* Forgets any time that has passed, e.g. while paused. */
+    pub fn resync(&mut self, now: Instant) {
+        self.accumulator = Duration::from_secs(0);
+        self.last = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn steady_pace() {
+        let start = Instant::now();
+        let frame = Duration::from_millis(16);
+        let mut pacer = Pacer::new(frame, start);
+
+        assert_eq!(pacer.frames_due(start), 0);
+        assert_eq!(pacer.until_next(start), frame);
+        assert_eq!(pacer.frames_due(start + Duration::from_millis(10)), 0);
+        assert_eq!(pacer.until_next(start + Duration::from_millis(10)), Duration::from_millis(6));
+
+        /* A slow frame is paid back on the next one. */
+        assert_eq!(pacer.frames_due(start + Duration::from_millis(40)), 2);
+        assert_eq!(pacer.until_next(start + Duration::from_millis(40)), Duration::from_millis(8));
+        assert_eq!(pacer.frames_due(start + Duration::from_millis(48)), 1);
+    }
+
+    #[test]
+    fn stall_catch_up() {
+        let start = Instant::now();
+        let frame = Duration::from_millis(16);
+        let mut pacer = Pacer::new(frame, start);
+        pacer.set_max_catch_up(3);
+
+        /* Two seconds is 125 frames behind, but only three get run and the
+           backlog doesn't carry over. */
+        let after = start + Duration::from_secs(2);
+        assert_eq!(pacer.frames_due(after), 3);
+        assert_eq!(pacer.until_next(after), frame);
+        assert_eq!(pacer.frames_due(after + frame), 1);
+
+        pacer.resync(after + Duration::from_secs(10));
+        assert_eq!(pacer.frames_due(after + Duration::from_secs(10)), 0);
+    }
+}
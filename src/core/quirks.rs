@@ -0,0 +1,91 @@
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Quirks {
+    /* Fail Str/Read transfers that cross the classic 12-bit address
+       boundary at 0xfff, even if RAM extends past it. */
+    pub strict_address_space: bool,
+
+    /* SCHIP scrolls lo-res screens in hi-res pixels, i.e. half a lo-res
+       pixel per step. Odd amounts round down unless this is set. */
+    pub lores_scroll_rounds_up: bool,
+
+    /* The COSMAC VIP shifts VY into VX. Most later interpreters ignore VY
+       and shift VX in place, which is the default here. */
+    pub shift_uses_vy: bool,
+
+    /* Not a real interpreter quirk: 7XKK never touches VF per spec. This
+       sets VF to the carry anyway, for tests that want to see overflow. */
+    pub add_imm_sets_vf: bool,
+
+    /* BNNN can land past the end of RAM. By default that is a BadJump
+       error on the jump itself; this wraps the target around to the start
+       of the address space instead. */
+    pub wrap_jumps: bool,
+
+    /* DXYN always wraps the starting coordinate onto the screen. By default
+       the rest of the sprite wraps too; with this set, the parts that run
+       off the right or bottom edge are clipped, as on the COSMAC VIP. */
+    pub clip_sprites: bool,
+
+    /* FX55 and FX65 leave I pointing just past the registers they moved,
+       as on the COSMAC VIP. Later interpreters leave I alone. */
+    pub memory_increments_i: bool,
+
+    /* I is 12 bits wide, 16 on XO-CHIP. Anything that would push it past
+       the end of RAM masks it back into range by default; with this set it is an IndexOverflow
+       error instead, to catch ROMs that depend on one or the other. */
+    pub strict_index: bool,
+}
+
+impl Quirks {
+    pub const PROFILES: [&'static str; 4] = ["cosmac", "schip", "xochip", "modern"];
+
+    /* Quirk presets for well known interpreters, by the names in PROFILES. */
+    pub fn profile(name: &str) -> Option<Self> {
+        match name {
+            "cosmac" => Some(Quirks {
+                strict_address_space: true,
+                shift_uses_vy: true,
+                clip_sprites: true,
+                memory_increments_i: true,
+                ..Quirks::default()
+            }),
+            "schip" => Some(Quirks { clip_sprites: true, ..Quirks::default() }),
+            "xochip" => Some(Quirks {
+                shift_uses_vy: true,
+                memory_increments_i: true,
+                ..Quirks::default()
+            }),
+            "modern" => Some(Quirks::default()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn profiles() {
+        let cosmac = Quirks::profile("cosmac").unwrap();
+        assert!(cosmac.strict_address_space && cosmac.shift_uses_vy && cosmac.clip_sprites);
+        assert!(cosmac.memory_increments_i && !cosmac.strict_index);
+        assert!(!cosmac.lores_scroll_rounds_up && !cosmac.add_imm_sets_vf && !cosmac.wrap_jumps);
+
+        let schip = Quirks::profile("schip").unwrap();
+        assert!(schip.clip_sprites && !schip.shift_uses_vy && !schip.memory_increments_i);
+        assert!(!schip.strict_address_space);
+
+        let xochip = Quirks::profile("xochip").unwrap();
+        assert!(xochip.shift_uses_vy && xochip.memory_increments_i && !xochip.clip_sprites);
+        assert!(!xochip.strict_address_space);
+
+        let modern = Quirks::profile("modern").unwrap();
+        assert_eq!(modern, Quirks::default());
+        assert!(!modern.clip_sprites && !modern.shift_uses_vy && !modern.memory_increments_i);
+
+        assert_ne!(schip, modern);
+        assert_eq!(Quirks::profile("vip"), None);
+        assert!(Quirks::PROFILES.iter().all(|p| Quirks::profile(p).is_some()));
+    }
+}
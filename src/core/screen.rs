@@ -0,0 +1,74 @@
+use super::cpu::Cpu;
+
+/* Draws vram as text, one line per row, with '#' for lit pixels and '.' for
+   dark ones. The width is inferred from the size of vram. */
+pub fn render_ascii(vram: &[bool]) -> String {
+    let width = if vram.len() == Cpu::HIRES_VRAM_BYTES {
+        Cpu::HIRES_DISPLAY_WIDTH
+    } else {
+        Cpu::DISPLAY_WIDTH
+    };
+
+    let mut out = String::with_capacity(vram.len() + vram.len() / width);
+    for row in vram.chunks(width) {
+        out.extend(row.iter().map(|px| if *px { '#' } else { '.' }));
+        out.push('\n');
+    }
+    out
+}
+
+/* Indices of the pixels that differ between two frames, e.g. a screen and
+   a golden image of it. If one is longer, say hires against lores, its
+   extra pixels all count as different. */
+pub fn framebuffer_diff(a: &[bool], b: &[bool]) -> Vec<usize> {
+    let common = a.len().min(b.len());
+    (0..common)
+        .filter(|&i| a[i] != b[i])
+        .chain(common..a.len().max(b.len()))
+        .collect()
+}
+
+pub fn diff_count(a: &[bool], b: &[bool]) -> usize {
+    let common = a.len().min(b.len());
+    a.iter().zip(b).filter(|(x, y)| x != y).count() + a.len().max(b.len()) - common
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ascii() {
+        let mut vram = vec![false; Cpu::VRAM_BYTES];
+        vram[1] = true;
+        vram[Cpu::DISPLAY_WIDTH] = true;
+
+        let text = render_ascii(&vram);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), Cpu::DISPLAY_HEIGHT);
+        assert!(lines.iter().all(|l| l.len() == Cpu::DISPLAY_WIDTH));
+        assert!(lines[0].starts_with(".#.."));
+        assert!(lines[1].starts_with("#..."));
+
+        let vram = vec![false; Cpu::HIRES_VRAM_BYTES];
+        assert_eq!(render_ascii(&vram).lines().count(), Cpu::HIRES_DISPLAY_HEIGHT);
+    }
+
+    #[test]
+    fn diff() {
+        let golden = vec![false; Cpu::VRAM_BYTES];
+        let mut screen = golden.clone();
+        assert!(framebuffer_diff(&screen, &golden).is_empty());
+        assert_eq!(diff_count(&screen, &golden), 0);
+
+        screen[3] = true;
+        screen[Cpu::DISPLAY_WIDTH + 7] = true;
+        screen[Cpu::VRAM_BYTES - 1] = true;
+        assert_eq!(framebuffer_diff(&screen, &golden),
+                   [3, Cpu::DISPLAY_WIDTH + 7, Cpu::VRAM_BYTES - 1]);
+        assert_eq!(diff_count(&golden, &screen), 3);
+
+        assert_eq!(framebuffer_diff(&[true, false, true], &[true]), [1, 2]);
+        assert_eq!(diff_count(&[false], &[true, false, true]), 3);
+    }
+}
@@ -0,0 +1,103 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use super::cpu::Cpu;
+use super::driver;
+use super::op::Op;
+use super::screen;
+
+/* Assembles ops into the bytes of a ROM, e.g.
+
+       rom![Ld(Reg(0), 0x12), Add(Reg(0), 1)]
+
+   Op's variants are in scope inside the brackets; Reg has to be imported
+   by the caller. */
+macro_rules! rom {
+    ($($op:expr),* $(,)?) => {{
+        #[allow(unused_imports)]
+        use $crate::core::op::Op::*;
+        $crate::core::test_support::assemble(&[$($op),*])
+    }};
+}
+
+pub(crate) use rom;
+
+pub fn assemble(ops: &[Op]) -> Vec<u8> {
+    ops.iter().flat_map(|op| op.encode().to_be_bytes().to_vec()).collect()
+}
+
+/* Display driver that keeps a copy of the last frame it was handed. */
+pub struct CaptureDisplay {
+    frame: Rc<RefCell<Vec<bool>>>,
+}
+
+impl CaptureDisplay {
+    pub fn new() -> (Self, Rc<RefCell<Vec<bool>>>) {
+        let frame = Rc::new(RefCell::new(Vec::new()));
+        (CaptureDisplay { frame: Rc::clone(&frame) }, frame)
+    }
+}
+
+impl driver::Display for CaptureDisplay {
+    fn refresh(&mut self, vram: &[bool]) {
+        *self.frame.borrow_mut() = vram.to_owned();
+    }
+}
+
+/* Compares a frame against ASCII art of the screen, '#' for lit pixels and
+   '.' for dark ones. Lines are trimmed, and short lines or missing rows at
+   the bottom count as dark, so only the interesting part needs drawing. An
+   empty first or last line is ignored to allow raw string literals. */
+pub fn assert_screen_eq(actual: &[bool], expected_ascii: &str) {
+    let width = if actual.len() == Cpu::HIRES_VRAM_BYTES {
+        Cpu::HIRES_DISPLAY_WIDTH
+    } else {
+        Cpu::DISPLAY_WIDTH
+    };
+    let height = actual.len() / width;
+
+    let mut lines: Vec<&str> = expected_ascii.lines().map(str::trim).collect();
+    if lines.first() == Some(&"") {
+        lines.remove(0);
+    }
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+    assert!(lines.len() <= height, "expected screen has more than {} rows", height);
+
+    let mut expected = vec![false; actual.len()];
+    for (y, line) in lines.iter().enumerate() {
+        assert!(line.len() <= width, "row {} is wider than {} pixels", y, width);
+        for (x, c) in line.chars().enumerate() {
+            expected[y * width + x] = match c {
+                '#' => true,
+                '.' => false,
+                _ => panic!("unexpected {:?} at ({}, {})", c, x, y),
+            };
+        }
+    }
+
+    let diffs: Vec<(usize, usize)> = (0..actual.len())
+        .filter(|&n| actual[n] != expected[n])
+        .map(|n| (n % width, n / width))
+        .collect();
+    if !diffs.is_empty() {
+        panic!("screens differ at {} pixel(s), first at (x, y) = {:?}\n\
+                expected:\n{}\nactual:\n{}",
+               diffs.len(), diffs[0],
+               screen::render_ascii(&expected), screen::render_ascii(actual));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[should_panic(expected = "first at (x, y) = (1, 0)")]
+    fn screen_mismatch() {
+        let mut vram = vec![false; Cpu::VRAM_BYTES];
+        vram[0] = true;
+        assert_screen_eq(&vram, "##");
+    }
+}
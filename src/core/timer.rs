@@ -1,4 +1,4 @@
-use std::sync::atomic::{AtomicU8, AtomicBool, Ordering};
+use std::sync::atomic::{AtomicU8, AtomicU32, AtomicBool, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
@@ -6,85 +6,388 @@ use std::time::Duration;
 use super::driver;
 
 type SoundDriver = Arc<Mutex<Option<Box<dyn driver::Sound>>>>;
+type Callback = Arc<Mutex<CallbackSlot>>;
 
+#[derive(Default)]
+struct CallbackSlot {
+    callback: Option<Box<dyn FnMut() + Send>>,
+    /* Set whenever the callback is swapped, so expire knows not to put back
+       one that was replaced while it ran. */
+    replaced: bool,
+}
+
+/* Relaxed is enough here. Each of dt, st and buzzing is only ever used on
+   its own, never to publish other data, and every atomic location has a
+   single modification order that all threads agree on. A thread that
+   stores to dt and then loads it sees its own store or a later decrement,
+   never an older value, so Ldd followed by Movd can't read stale time.
+   The strict-atomics feature switches everything to SeqCst, which is
+   useful for ruling the orderings out when chasing a timing bug. */
+#[cfg(not(feature = "strict-atomics"))]
+pub const ORDERING: Ordering = Ordering::Relaxed;
+#[cfg(feature = "strict-atomics")]
+pub const ORDERING: Ordering = Ordering::SeqCst;
+
+/* Decides when dt and st count down. Hosts with their own clock, such as a
+   browser animation loop, can implement this and feed it elapsed time
+   through Cpu::advance_timers instead of relying on a background thread. */
+pub trait TimerSource {
+    /* How many 60hz ticks are due after another delta of host time. */
+    fn ticks_due(&mut self, delta: Duration) -> u32;
+
+    /* Sources that want the Timer's own 60hz thread return true. */
+    fn needs_thread(&self) -> bool {
+        false
+    }
+}
+
+/* The default: the timer thread does the counting and host time is
+   ignored. */
+pub struct ThreadSource;
+
+impl TimerSource for ThreadSource {
+    fn ticks_due(&mut self, _delta: Duration) -> u32 {
+        0
+    }
+
+    fn needs_thread(&self) -> bool {
+        true
+    }
+}
+
+/* Counts purely from the time the host reports, carrying any partial tick
+   over to the next call. */
+#[derive(Debug, Default)]
+pub struct ManualSource {
+    carry: Duration,
+}
+
+impl ManualSource {
+    const PERIOD: Duration = Duration::from_nanos(1_000_000_000 / 60);
+}
+
+impl TimerSource for ManualSource {
+    fn ticks_due(&mut self, delta: Duration) -> u32 {
+        self.carry += delta;
+        let ticks = (self.carry.as_nanos() / Self::PERIOD.as_nanos()) as u32;
+        self.carry -= Self::PERIOD * ticks;
+        ticks
+    }
+}
+
+/* Timer threads that have been spawned and not yet joined. */
+static LIVE_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/* The thread handle is private so that the only way to be rid of a
+   running timer is to drop it, which always halts and joins the thread. */
 pub struct Timer {
-    pub thread: Option<thread::JoinHandle<()>>,
+    thread: Option<thread::JoinHandle<()>>,
     pub dt: Arc<AtomicU8>,
     pub st: Arc<AtomicU8>,
     pub halt: Arc<AtomicBool>,
+    pub buzzing: Arc<AtomicBool>,
+    /* The buzzer sounds for every tick that starts with st above this.
+       Zero is the spec's "buzz while st > 0". One is "buzz while st > 1":
+       less accurate, since every sound ends a tick early and single tick
+       ones never play, but a 16ms burst of audio is too short for most
+       hosts to fade in and out and tends to come out as a click. */
+    pub buzz_threshold: Arc<AtomicU8>,
+    /* Counts up, wrapping, once per tick. It marks the 60hz frames for
+       anything that needs to know when a new one starts. */
+    pub ticks: Arc<AtomicU32>,
     pub sound_driver: SoundDriver,
+    on_dt_expire: Callback,
+    on_st_expire: Callback,
 }
 
 impl Timer {
     pub fn new() -> Self {
-        let dt = Arc::new(AtomicU8::new(0x00));
-        let st = Arc::new(AtomicU8::new(0x00));
-        let halt = Arc::new(AtomicBool::new(false));
-        let sound_driver: SoundDriver = Arc::new(Mutex::new(None));
+        let mut timer = Self::new_manual();
 
-        let dt_clone = Arc::clone(&dt);
-        let st_clone = Arc::clone(&st);
-        let halt_clone = Arc::clone(&halt);
-        let sound_driver_clone = Arc::clone(&sound_driver);
-
-        let thread = thread::spawn(move || {
-            let mut st_was_pos = false;
+        let dt_clone = Arc::clone(&timer.dt);
+        let st_clone = Arc::clone(&timer.st);
+        let halt_clone = Arc::clone(&timer.halt);
+        let buzzing_clone = Arc::clone(&timer.buzzing);
+        let threshold_clone = Arc::clone(&timer.buzz_threshold);
+        let ticks_clone = Arc::clone(&timer.ticks);
+        let sound_driver_clone = Arc::clone(&timer.sound_driver);
+        let on_dt_clone = Arc::clone(&timer.on_dt_expire);
+        let on_st_clone = Arc::clone(&timer.on_st_expire);
 
+        LIVE_THREADS.fetch_add(1, Ordering::SeqCst);
+        timer.thread = Some(thread::spawn(move || {
             loop {
-                if halt_clone.load(Ordering::Relaxed) {
+                if halt_clone.load(ORDERING) {
                     break;
                 }
 
-                let v = dt_clone.load(Ordering::Relaxed);
-                if v > 0 {
-                    /* Only decrement if the value didn't just change out from
-                       under us. If it did, we'll catch up next cycle. Same
-                       goes for the sound timer below. */
-                    dt_clone.compare_and_swap(v, v - 1, Ordering::Relaxed);
-                }
-
-                let mut v = st_clone.load(Ordering::Relaxed);
-                if v > 0 {
-                    v = st_clone.compare_and_swap(v, v - 1, Ordering::Relaxed);
-                }
-
-                if v <= 1 && st_was_pos {
-                    let mut lock = sound_driver_clone.try_lock();
-                    if let Ok(ref mut mutex) = lock {
-                        if let Some(sound_driver) = &mut **mutex {
-                            sound_driver.stop_buzz();
-                        }
-                        st_was_pos = false;
-                    }
-                } else if  v > 1 && !st_was_pos {
-                    let mut lock = sound_driver_clone.try_lock();
-                    if let Ok(ref mut mutex) = lock {
-                        if let Some(sound_driver) = &mut **mutex {
-                            sound_driver.start_buzz();
-                        }
-                        st_was_pos = true;
-                    }
-                }
+                Self::step(&dt_clone, &st_clone, &buzzing_clone, &threshold_clone,
+                           &sound_driver_clone, &on_dt_clone, &on_st_clone);
+                ticks_clone.fetch_add(1, ORDERING);
 
                 thread::sleep(Duration::from_millis(16)); // Decent estimation of 60hz
             }
-        });
+            LIVE_THREADS.fetch_sub(1, Ordering::SeqCst);
+        }));
+
+        timer
+    }
 
+    /* A timer with no backing thread. The host drives it by calling tick
+       once per 60hz period. */
+    pub fn new_manual() -> Self {
         Timer {
-            thread: Some(thread),
-            dt,
-            st,
-            sound_driver,
-            halt
+            thread: None,
+            dt: Arc::new(AtomicU8::new(0x00)),
+            st: Arc::new(AtomicU8::new(0x00)),
+            halt: Arc::new(AtomicBool::new(false)),
+            buzzing: Arc::new(AtomicBool::new(false)),
+            buzz_threshold: Arc::new(AtomicU8::new(0)),
+            ticks: Arc::new(AtomicU32::new(0)),
+            sound_driver: Arc::new(Mutex::new(None)),
+            on_dt_expire: Callback::default(),
+            on_st_expire: Callback::default(),
+        }
+    }
+
+    pub fn live_threads() -> usize {
+        LIVE_THREADS.load(Ordering::SeqCst)
+    }
+
+    pub fn tick(&self) {
+        Self::step(&self.dt, &self.st, &self.buzzing, &self.buzz_threshold, &self.sound_driver,
+                   &self.on_dt_expire, &self.on_st_expire);
+        self.ticks.fetch_add(1, ORDERING);
+    }
+
+    /* Called on the tick where dt counts down to zero, from the timer
+       thread if there is one. It runs with the timer waiting on it, so it
+       should be cheap. None removes it. */
+    pub fn on_delay_expire(&self, callback: Option<Box<dyn FnMut() + Send>>) {
+        Self::replace(&self.on_dt_expire, callback);
+    }
+
+    /* Same as on_delay_expire, for st. */
+    pub fn on_sound_expire(&self, callback: Option<Box<dyn FnMut() + Send>>) {
+        Self::replace(&self.on_st_expire, callback);
+    }
+
+    fn replace(slot: &Callback, callback: Option<Box<dyn FnMut() + Send>>) {
+        let mut slot = slot.lock().unwrap();
+        slot.callback = callback;
+        slot.replaced = true;
+    }
+
+    /* A callback being swapped out right now is skipped rather than waited
+       on, same as the sound driver. The callback is taken out of its slot
+       while it runs, so it is free to replace or remove itself. */
+    fn expire(slot: &Callback) {
+        let callback = match slot.try_lock() {
+            Ok(mut slot) => {
+                slot.replaced = false;
+                slot.callback.take()
+            },
+            Err(_) => None,
+        };
+        if let Some(mut callback) = callback {
+            callback();
+            let mut slot = slot.lock().unwrap();
+            if !slot.replaced {
+                slot.callback = Some(callback);
+            }
+        }
+    }
+
+    fn step(dt: &AtomicU8, st: &AtomicU8, buzzing: &AtomicBool, threshold: &AtomicU8,
+            sound_driver: &SoundDriver, on_dt_expire: &Callback, on_st_expire: &Callback) {
+        let v = dt.load(ORDERING);
+        if v > 0 {
+            /* Only decrement if the value didn't just change out from
+               under us. If it did, we'll catch up next cycle. Same
+               goes for the sound timer below. */
+            let decremented = dt.compare_exchange(
+                v, v - 1, ORDERING, ORDERING).is_ok();
+            if decremented && v == 1 {
+                Self::expire(on_dt_expire);
+            }
+        }
+
+        /* v ends up as st at the start of this tick, before the decrement. */
+        let mut v = st.load(ORDERING);
+        if v > 0 {
+            match st.compare_exchange(v, v - 1, ORDERING, ORDERING) {
+                Ok(1) => Self::expire(on_st_expire),
+                Ok(_) => (),
+                Err(now) => v = now,
+            }
+        }
+
+        let threshold = threshold.load(ORDERING);
+        let st_was_pos = buzzing.load(ORDERING);
+        if v <= threshold && st_was_pos {
+            let mut lock = sound_driver.try_lock();
+            if let Ok(ref mut mutex) = lock {
+                if let Some(sound_driver) = &mut **mutex {
+                    sound_driver.stop_buzz();
+                }
+                buzzing.store(false, ORDERING);
+            }
+        } else if v > threshold && !st_was_pos {
+            let mut lock = sound_driver.try_lock();
+            if let Ok(ref mut mutex) = lock {
+                if let Some(sound_driver) = &mut **mutex {
+                    sound_driver.start_buzz();
+                }
+                buzzing.store(true, ORDERING);
+            }
         }
     }
 }
 
+impl Default for Timer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Drop for Timer {
     fn drop(&mut self) {
-        self.halt.store(true, Ordering::Relaxed);
+        self.halt.store(true, ORDERING);
         if let Some(thread) = self.thread.take() {
             thread.join().unwrap();
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn store_then_load() {
+        /* The timer thread may decrement dt at any point, but never by more
+           than one between a store and the load right after it. */
+        let timer = Timer::new();
+        for n in 2..=u8::MAX {
+            timer.dt.store(n, ORDERING);
+            let v = timer.dt.load(ORDERING);
+            assert!(v == n || v == n - 1, "stored {} but read {}", n, v);
+        }
+    }
+
+    struct FakeSound(Arc<Mutex<Vec<bool>>>);
+
+    impl driver::Sound for FakeSound {
+        fn start_buzz(&self) {
+            self.0.lock().unwrap().push(true);
+        }
+
+        fn stop_buzz(&self) {
+            self.0.lock().unwrap().push(false);
+        }
+    }
+
+    #[test]
+    fn manual_source() {
+        let mut source = ManualSource::default();
+
+        assert_eq!(source.ticks_due(Duration::from_millis(10)), 0);
+        assert_eq!(source.ticks_due(Duration::from_millis(10)), 1);
+        assert_eq!(source.ticks_due(Duration::from_secs(1)), 60);
+        assert!(!source.needs_thread());
+        assert!(ThreadSource.needs_thread());
+    }
+
+    #[test]
+    fn expire_callbacks() {
+        let timer = Timer::new_manual();
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        timer.on_delay_expire(Some(Box::new(move || {
+            fired_clone.fetch_add(1, Ordering::SeqCst);
+        })));
+
+        timer.dt.store(3, ORDERING);
+        timer.st.store(1, ORDERING);
+        for _ in 0..2 {
+            timer.tick();
+        }
+        assert_eq!(fired.load(Ordering::SeqCst), 0);
+        for _ in 0..5 {
+            timer.tick();
+        }
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+
+        timer.on_delay_expire(None);
+        timer.dt.store(1, ORDERING);
+        timer.tick();
+        assert_eq!(fired.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn callback_replaces_itself() {
+        let timer = Timer::new_manual();
+        let slot = Arc::clone(&timer.on_st_expire);
+        let fired = Arc::new(AtomicUsize::new(0));
+        let fired_clone = Arc::clone(&fired);
+        timer.on_sound_expire(Some(Box::new(move || {
+            let fired_clone = Arc::clone(&fired_clone);
+            Timer::replace(&slot, Some(Box::new(move || {
+                fired_clone.fetch_add(1, Ordering::SeqCst);
+            })));
+        })));
+
+        for _ in 0..3 {
+            timer.st.store(1, ORDERING);
+            timer.tick();
+        }
+        assert_eq!(fired.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn buzz_threshold() {
+        let timer = Timer::new_manual();
+        let buzzes = Arc::new(Mutex::new(Vec::new()));
+        *timer.sound_driver.lock().unwrap() = Some(Box::new(FakeSound(Arc::clone(&buzzes))));
+
+        /* A sound timer of 1 buzzes for exactly one tick. */
+        timer.st.store(1, ORDERING);
+        timer.tick();
+        assert!(timer.buzzing.load(ORDERING));
+        timer.tick();
+        assert!(!timer.buzzing.load(ORDERING));
+        assert_eq!(*buzzes.lock().unwrap(), [true, false]);
+
+        /* Raising the threshold drops the single tick blip. */
+        buzzes.lock().unwrap().clear();
+        timer.buzz_threshold.store(1, ORDERING);
+        timer.st.store(1, ORDERING);
+        timer.tick();
+        timer.tick();
+        assert!(buzzes.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn buzz_lengths() {
+        /* Start and stop calls, and the tick each came on. */
+        let buzzes_for = |threshold: u8, st: u8| {
+            let timer = Timer::new_manual();
+            let calls = Arc::new(Mutex::new(Vec::new()));
+            *timer.sound_driver.lock().unwrap() = Some(Box::new(FakeSound(Arc::clone(&calls))));
+            timer.buzz_threshold.store(threshold, ORDERING);
+            timer.st.store(st, ORDERING);
+
+            let mut buzzes = Vec::new();
+            for n in 0..4 {
+                timer.tick();
+                buzzes.extend(calls.lock().unwrap().drain(..).map(|on| (n, on)));
+            }
+            buzzes
+        };
+
+        assert_eq!(buzzes_for(0, 1), [(0, true), (1, false)]);
+        assert_eq!(buzzes_for(0, 2), [(0, true), (2, false)]);
+        assert_eq!(buzzes_for(1, 1), []);
+        assert_eq!(buzzes_for(1, 2), [(0, true), (1, false)]);
+    }
+}
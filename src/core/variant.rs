@@ -0,0 +1,98 @@
+use super::cpu::Cpu;
+use super::op::Op;
+use super::quirks::Quirks;
+
+/* The machines a ROM can be written for, each a superset of the one
+   before it. A Cpu's variant decides which ops it runs, how much RAM it
+   has and the quirks it starts with; the quirks can still be changed
+   afterwards. */
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Variant {
+    Chip8,
+    Schip,
+    XoChip,
+}
+
+impl Variant {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "chip8" => Some(Variant::Chip8),
+            "schip" => Some(Variant::Schip),
+            "xochip" => Some(Variant::XoChip),
+            _ => None,
+        }
+    }
+
+    /* The machine each of the Quirks::PROFILES presets was taken from. */
+    pub fn for_profile(name: &str) -> Option<Self> {
+        match name {
+            "cosmac" => Some(Variant::Chip8),
+            "schip" | "modern" => Some(Variant::Schip),
+            "xochip" => Some(Variant::XoChip),
+            _ => None,
+        }
+    }
+
+    pub fn quirks(self) -> Quirks {
+        let profile = match self {
+            Variant::Chip8 => "cosmac",
+            Variant::Schip => "schip",
+            Variant::XoChip => "xochip",
+        };
+        Quirks::profile(profile).unwrap()
+    }
+
+    /* Whether the 128x64 mode is there at all. Every variant starts out
+       in 64x32. */
+    pub fn has_hires(self) -> bool {
+        self != Variant::Chip8
+    }
+
+    /* XO-CHIP has 64K of RAM. The others have the standard 4K. */
+    pub fn ram_bytes(self) -> usize {
+        match self {
+            Variant::Chip8 | Variant::Schip => Cpu::RAM_BYTES,
+            Variant::XoChip => 0x10000,
+        }
+    }
+
+    pub fn supports(self, op: &Op) -> bool {
+        match *op {
+            Op::Scd(_) | Op::Scr | Op::Scl | Op::Low | Op::High => self.has_hires(),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::core::op::Reg;
+
+    #[test]
+    fn defaults() {
+        assert_eq!(Variant::Chip8.quirks(), Quirks::profile("cosmac").unwrap());
+        assert_eq!(Variant::Schip.quirks(), Quirks::profile("schip").unwrap());
+        assert_eq!(Variant::XoChip.quirks(), Quirks::profile("xochip").unwrap());
+
+        assert_eq!(Variant::for_profile("cosmac"), Some(Variant::Chip8));
+        assert_eq!(Variant::for_profile("modern"), Some(Variant::Schip));
+        assert_eq!(Variant::for_profile("xochip"), Some(Variant::XoChip));
+        assert!(Quirks::PROFILES.iter().all(|p| Variant::for_profile(p).is_some()));
+
+        assert_eq!(Variant::from_name("xochip"), Some(Variant::XoChip));
+        assert_eq!(Variant::from_name("SCHIP"), None);
+
+        assert!(!Variant::Chip8.has_hires());
+        assert!(Variant::Schip.has_hires() && Variant::XoChip.has_hires());
+
+        assert_eq!(Variant::Chip8.ram_bytes(), 0x1000);
+        assert_eq!(Variant::Schip.ram_bytes(), 0x1000);
+        assert_eq!(Variant::XoChip.ram_bytes(), 0x10000);
+
+        for variant in [Variant::Chip8, Variant::Schip, Variant::XoChip] {
+            assert!(variant.supports(&Op::Draw(Reg(0), Reg(1), 5)));
+            assert_eq!(variant.supports(&Op::High), variant.has_hires());
+        }
+    }
+}
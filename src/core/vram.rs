@@ -0,0 +1,104 @@
+use std::ops::{Deref, DerefMut};
+
+/* What happens to coordinates past the right or bottom edge. */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Edge {
+    /* Around to the opposite side, as sprites do by default. */
+    Wrap,
+    /* Off the screen: reads are unset and writes are dropped. */
+    Clip,
+}
+
+/* Row major vram, width x height pixels, addressed by x and y instead of
+   by offset. Works over a borrowed slice, shared for reading or mutable
+   for writing too. */
+pub struct VramView<T> {
+    vram: T,
+    width: usize,
+    height: usize,
+    edge: Edge,
+}
+
+impl<T: Deref<Target = [bool]>> VramView<T> {
+    pub fn new(vram: T, width: usize, height: usize, edge: Edge) -> Self {
+        assert!(vram.len() >= width * height, "vram too small for {}x{}", width, height);
+        VramView { vram, width, height, edge }
+    }
+
+    /* The vram offset of (x, y), or None if it's clipped. */
+    pub fn index(&self, x: usize, y: usize) -> Option<usize> {
+        match self.edge {
+            Edge::Wrap => Some(y % self.height * self.width + x % self.width),
+            Edge::Clip if x < self.width && y < self.height => Some(y * self.width + x),
+            Edge::Clip => None,
+        }
+    }
+
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        self.index(x, y).is_some_and(|i| self.vram[i])
+    }
+}
+
+impl<T: DerefMut<Target = [bool]>> VramView<T> {
+    pub fn set(&mut self, x: usize, y: usize, on: bool) {
+        if let Some(i) = self.index(x, y) {
+            self.vram[i] = on;
+        }
+    }
+
+    /* Inverts a pixel, as a set sprite bit does. Returns whether that
+       turned a lit pixel off. */
+    pub fn flip(&mut self, x: usize, y: usize) -> bool {
+        match self.index(x, y) {
+            Some(i) => {
+                self.vram[i] = !self.vram[i];
+                !self.vram[i]
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wrapped_edges() {
+        let mut vram = [false; 8 * 4];
+        let mut view = VramView::new(&mut vram[..], 8, 4, Edge::Wrap);
+
+        view.set(9, 0, true);
+        view.set(7, 5, true);
+        assert!(view.get(1, 0) && view.get(9, 4) && view.get(7, 1));
+        assert!(!view.get(0, 0));
+        assert_eq!(view.index(8, 4), Some(0));
+
+        assert!(!view.flip(8, 0));
+        assert!(view.flip(0, 4));
+        assert_eq!(vram.iter().filter(|&&px| px).count(), 2);
+        assert!(vram[1] && vram[8 + 7]);
+    }
+
+    #[test]
+    fn clipped_edges() {
+        let mut vram = [false; 8 * 4];
+        let mut view = VramView::new(&mut vram[..], 8, 4, Edge::Clip);
+
+        view.set(7, 3, true);
+        view.set(8, 0, true);
+        view.set(0, 4, true);
+        assert!(view.get(7, 3));
+        assert!(!view.get(8, 3) && !view.get(7, 4) && !view.get(15, 7));
+        assert_eq!(view.index(8, 0), None);
+        assert!(!view.flip(8, 3));
+        assert!(view.flip(7, 3));
+
+        /* A read-only view over a larger buffer only sees its own part. */
+        vram[8] = true;
+        vram[31] = true;
+        let view = VramView::new(&vram[..16], 8, 2, Edge::Clip);
+        assert!(view.get(0, 1));
+        assert!(!view.get(7, 3));
+    }
+}
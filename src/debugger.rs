@@ -0,0 +1,264 @@
+use std::collections::HashSet;
+
+use crate::core::cpu::{Cpu, TraceEntry};
+use crate::core::error::Error;
+use crate::core::op::Op;
+
+type Result<T> = std::result::Result<T, String>;
+
+/* Commands understood by the --debug REPL. Addresses and lengths are hex,
+   with or without a leading 0x. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Step,
+    Continue,
+    Break(u16),
+    Registers,
+    Memory(u16, u16),
+    Disassemble,
+}
+
+impl Command {
+    pub const HELP: &'static str =
+        "s: step  c: continue  b <addr>: toggle breakpoint  r: registers  \
+         m <addr> <len>: hexdump  d: disassemble around pc";
+
+    pub fn parse(line: &str) -> Result<Self> {
+        let mut words = line.split_whitespace();
+        let cmd = match words.next() {
+            Some("s") => Command::Step,
+            Some("c") => Command::Continue,
+            Some("b") => Command::Break(hex_arg(words.next())?),
+            Some("r") => Command::Registers,
+            Some("m") => Command::Memory(hex_arg(words.next())?, hex_arg(words.next())?),
+            Some("d") => Command::Disassemble,
+            Some(other) => return Err(format!("unknown command: {}", other)),
+            None => return Err("empty command".to_owned()),
+        };
+
+        match words.next() {
+            Some(extra) => Err(format!("unexpected argument: {}", extra)),
+            None => Ok(cmd),
+        }
+    }
+}
+
+fn hex_arg(word: Option<&str>) -> Result<u16> {
+    let word = word.ok_or("missing argument")?;
+    let digits = word.strip_prefix("0x").unwrap_or(word);
+    u16::from_str_radix(digits, 16).map_err(|_| format!("bad hex value: {}", word))
+}
+
+#[derive(Debug, Default)]
+pub struct Breakpoints {
+    addrs: HashSet<u16>,
+}
+
+impl Breakpoints {
+    /* Returns whether addr is now a breakpoint. */
+    pub fn toggle(&mut self, addr: u16) -> bool {
+        if !self.addrs.remove(&addr) {
+            self.addrs.insert(addr);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn hit(&self, cpu: &Cpu) -> bool {
+        self.addrs.contains(&cpu.program_counter())
+    }
+}
+
+pub fn registers(cpu: &Cpu) -> String {
+    let v: Vec<String> = cpu.registers()
+        .iter()
+        .enumerate()
+        .map(|(n, v)| format!("V{:X}={:02x}", n, v))
+        .collect();
+    format!("PC={:04x} I={:04x}\n{}\n", cpu.program_counter(), cpu.index_register(), v.join(" "))
+}
+
+/* Sixteen bytes per line, each line tagged with its address. */
+pub fn hexdump(cpu: &Cpu, addr: u16, len: usize) -> Result<String> {
+    let bytes = cpu.read_memory(addr as usize, len).map_err(|e| e.to_string())?;
+    let mut out = String::new();
+    for (n, row) in bytes.chunks(16).enumerate() {
+        let hex: Vec<String> = row.iter().map(|b| format!("{:02x}", b)).collect();
+        out.push_str(&format!("{:04x}  {}\n", addr as usize + n * 16, hex.join(" ")));
+    }
+    Ok(out)
+}
+
+/* A few instructions either side of pc, with the current one marked. */
+pub fn disassemble_around(cpu: &Cpu) -> String {
+    const CONTEXT: u16 = 3;
+
+    let pc = cpu.program_counter();
+    let first = pc.saturating_sub(CONTEXT * 2);
+    let mut out = String::new();
+    for addr in (first..=pc + CONTEXT * 2).step_by(2) {
+        let Ok(code) = cpu.fetch_at(addr) else { break };
+        let mnemonic = Op::decode(code).map_or("???".to_owned(), |op| op.to_string());
+        let mark = if addr == pc { '>' } else { ' ' };
+        out.push_str(&format!("{} {:04x}  {:04x}  {}\n", mark, addr, code, mnemonic));
+    }
+    out
+}
+
+/* A JSON object for --trace, one per line:
+       {"pc":512,"opcode":25386,"op":"LD V3, 0x2a","regs":[0,0,0,42,...]}
+   An instruction that faulted also gets an "error" field with the message. */
+pub fn trace_json(entry: &TraceEntry) -> String {
+    let regs: Vec<String> = entry.regs.iter().map(|r| r.to_string()).collect();
+    let error = entry.error.as_ref()
+        .map_or(String::new(), |e| format!(",\"error\":\"{}\"", json_escape(&e.to_string())));
+    format!("{{\"pc\":{},\"opcode\":{},\"op\":\"{}\",\"regs\":[{}]{}}}",
+            entry.pc, entry.opcode, json_escape(&entry.op.to_string()), regs.join(","), error)
+}
+
+fn json_escape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/* Everything outside the registers and RAM that a crash report needs to
+   put the machine back the way it was. */
+fn machine_state(cpu: &Cpu) -> String {
+    let stack: Vec<String> = cpu.stack().iter().map(|addr| format!("{:04x}", addr)).collect();
+    format!("SP={:02x} stack=[{}]
+DT={:02x} ST={:02x} hires={}
+variant={:?} quirks={:?}
+",
+            cpu.stack().len(), stack.join(" "), cpu.delay_timer(), cpu.sound_timer(),
+            cpu.hires(), cpu.variant(), cpu.quirks())
+}
+
+/* What --dump-state-on-crash writes when a fatal error stops the emulator.
+   There is no save state format yet, so the full RAM hexdump stands in for
+   one; along with the registers it's enough to rebuild the machine by hand. */
+pub fn crash_report(cpu: &Cpu, error: &Error) -> String {
+    let ram = hexdump(cpu, 0, cpu.ram_bytes()).unwrap_or_else(|e| e + "\n");
+    format!("fatal CPU error: {}\n\n{}{}\n{}\n{}", error, registers(cpu),
+            machine_state(cpu), disassemble_around(cpu), ram)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::core::op::Reg;
+    use crate::core::test_support::CaptureDisplay;
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("s"), Ok(Command::Step));
+        assert_eq!(Command::parse("  c "), Ok(Command::Continue));
+        assert_eq!(Command::parse("b 0x20a"), Ok(Command::Break(0x20a)));
+        assert_eq!(Command::parse("b 20A"), Ok(Command::Break(0x20a)));
+        assert_eq!(Command::parse("r"), Ok(Command::Registers));
+        assert_eq!(Command::parse("m 200 10"), Ok(Command::Memory(0x200, 0x10)));
+        assert_eq!(Command::parse("d"), Ok(Command::Disassemble));
+
+        assert!(Command::parse("").is_err());
+        assert!(Command::parse("x").is_err());
+        assert!(Command::parse("b").is_err());
+        assert!(Command::parse("b zz").is_err());
+        assert!(Command::parse("m 200").is_err());
+        assert!(Command::parse("s 1").is_err());
+    }
+
+    #[test]
+    fn views() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.load(&[0x6a, 0x05, 0x00, 0xe0]).unwrap();
+
+        assert_eq!(hexdump(&cpu, 0x200, 4), Ok("0200  6a 05 00 e0\n".to_owned()));
+        assert!(hexdump(&cpu, 0xfff, 2).is_err());
+        assert!(registers(&cpu).starts_with("PC=0200 I=0000\nV0=00 V1=00"));
+        assert!(disassemble_around(&cpu).contains("> 0200  6a05  LD VA, 0x05\n"));
+
+        let mut breakpoints = Breakpoints::default();
+        assert!(breakpoints.toggle(0x200));
+        assert!(breakpoints.hit(&cpu));
+        assert!(!breakpoints.toggle(0x200));
+        assert!(!breakpoints.hit(&cpu));
+    }
+
+    #[test]
+    fn crash_dump() {
+        let mut cpu = Cpu::new_without_timer_thread();
+        /* LD V1, 0x22 then RET with nothing on the stack. */
+        cpu.load(&[0x61, 0x22, 0x00, 0xee]).unwrap();
+        cpu.tick().unwrap();
+        let error = cpu.tick().unwrap_err();
+        assert!(error.fatal());
+
+        let report = crash_report(&cpu, &error);
+        assert!(report.starts_with(&format!("fatal CPU error: {}\n\n", error)));
+        assert!(report.contains("V0=00 V1=22"));
+        assert!(report.contains("\nSP=00 stack=[]\nDT=00 ST=00 hires=false\n"));
+        assert!(report.contains(&format!("\nvariant=Schip quirks={:?}\n", cpu.quirks())));
+        assert!(report.contains("  0202  00ee  RET\n"));
+        assert!(report.contains("\n0000  f0 90 90 90 f0"));
+        assert!(report.contains("\n0200  61 22 00 ee ff"));
+        assert!(report.ends_with(&format!("\n{:04x}  {}\n", Cpu::RAM_BYTES - 16,
+                                          ["ff"; 16].join(" "))));
+
+        /* Again from inside a call, with the timers and hires set, landing
+           on the unused 0xffff words at 0x300. */
+        cpu.load(&[0x61, 0x22, 0x00, 0xee]).unwrap();
+        cpu.set_display_driver(Some(Box::new(CaptureDisplay::new().0)));
+        cpu.exec_all(&[Op::Ld(Reg(0), 0x30), Op::Ldd(Reg(0)), Op::Lds(Reg(0)), Op::High,
+                       Op::Call(0x300)]).unwrap();
+        let error = cpu.tick().unwrap_err();
+        assert_eq!(error, Error::BadInstruction);
+        let report = crash_report(&cpu, &error);
+        assert!(report.contains("\nSP=01 stack=[020a]\nDT=30 ST=30 hires=true\n"));
+    }
+
+    #[test]
+    fn trace_lines() {
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let out = Rc::clone(&lines);
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.set_trace_hook(Some(Box::new(move |e: &TraceEntry| {
+            out.borrow_mut().push(trace_json(e));
+        })));
+        /* LD V3, 0x2a then SE V3, 0x2a, skipping the junk word after it. */
+        cpu.load(&[0x63, 0x2a, 0x33, 0x2a, 0xff, 0xff, 0x12, 0x00]).unwrap();
+        for _ in 0..3 {
+            cpu.tick().unwrap();
+        }
+
+        let regs = r#""regs":[0,0,0,42,0,0,0,0,0,0,0,0,0,0,0,0]"#;
+        assert_eq!(*lines.borrow(), [
+            format!(r#"{{"pc":512,"opcode":25386,"op":"LD V3, 0x2a",{}}}"#, regs),
+            format!(r#"{{"pc":514,"opcode":13098,"op":"SE V3, 0x2a",{}}}"#, regs),
+            format!(r#"{{"pc":518,"opcode":4608,"op":"JP 0x200",{}}}"#, regs),
+        ]);
+
+        /* A faulting RET is still traced, along with its error. */
+        cpu.load(&[0x00, 0xee]).unwrap();
+        assert!(cpu.tick().is_err());
+        assert_eq!(lines.borrow()[3],
+                   format!(r#"{{"pc":512,"opcode":238,"op":"RET",{},"error":"{}"}}"#,
+                           regs, Error::StackUnderflow));
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(json_escape(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+        assert_eq!(json_escape("\n"), r"\u000a");
+    }
+}
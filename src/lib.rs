@@ -1,2 +1,6 @@
+pub mod config;
 pub mod core;
+pub mod debugger;
 pub mod sdl;
+#[cfg(feature = "watch")]
+pub mod watch;
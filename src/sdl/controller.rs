@@ -1,18 +1,29 @@
-use std::collections::HashSet;
-use std::sync::{Arc, Weak};
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
+use std::iter;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicBool, AtomicU16, AtomicU32, Ordering};
 use std::sync::mpsc::{Sender, channel};
 use std::thread;
+use std::time::{Duration, Instant};
 
 use sdl2::audio::AudioSpecDesired;
-use sdl2::event::Event;
-use sdl2::keyboard::Scancode;
-use sdl2::render::WindowCanvas;
+use sdl2::EventPump;
+use sdl2::event::{Event, WindowEvent};
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::{Point, Rect};
+use sdl2::render::{BlendMode, Texture, TextureCreator, WindowCanvas};
+use sdl2::video::{FullscreenType, WindowContext};
 
 use crate::core::cpu::Cpu;
+use crate::core::driver::KEY_CANCELLED;
+use crate::core::screen;
 
 use super::driver::{InputDriver, SoundDriver, DisplayDriver};
+use super::hud;
 use super::io;
-use super::io::{Buzzer, SquareWave};
+use super::io::{Buzz, Buzzer, SquareWave};
 
 type Result<T> = std::result::Result<T, String>;
 
@@ -32,6 +43,7 @@ impl Chip8UI for sdl2::Sdl {
                     width,
                     height)
             .position_centered()
+            .resizable()
             .build()
             .map_err(|err| err.to_string() )?;
 
@@ -63,12 +75,233 @@ impl Chip8UI for sdl2::Sdl {
     }
 }
 
+/* The CHIP-8 hex keypad laid over the left side of a QWERTY keyboard:
+
+       1 2 3 C        1 2 3 4
+       4 5 6 D   ->   Q W E R
+       7 8 9 E        A S D F
+       A 0 B F        Z X C V
+*/
+pub const KEYPAD_DEFAULT: [(Scancode, u8); 16] = [
+    (Scancode::Num1, 0x1), (Scancode::Num2, 0x2), (Scancode::Num3, 0x3), (Scancode::Num4, 0xc),
+    (Scancode::Q, 0x4), (Scancode::W, 0x5), (Scancode::E, 0x6), (Scancode::R, 0xd),
+    (Scancode::A, 0x7), (Scancode::S, 0x8), (Scancode::D, 0x9), (Scancode::F, 0xe),
+    (Scancode::Z, 0xa), (Scancode::X, 0x0), (Scancode::C, 0xb), (Scancode::V, 0xf),
+];
+
+/* KEYPAD_DEFAULT by the letters printed on the keys rather than by their
+   position, for KeyMappingMode::Logical. */
+pub const KEYPAD_DEFAULT_LOGICAL: [(Keycode, u8); 16] = [
+    (Keycode::Num1, 0x1), (Keycode::Num2, 0x2), (Keycode::Num3, 0x3), (Keycode::Num4, 0xc),
+    (Keycode::Q, 0x4), (Keycode::W, 0x5), (Keycode::E, 0x6), (Keycode::R, 0xd),
+    (Keycode::A, 0x7), (Keycode::S, 0x8), (Keycode::D, 0x9), (Keycode::F, 0xe),
+    (Keycode::Z, 0xa), (Keycode::X, 0x0), (Keycode::C, 0xb), (Keycode::V, 0xf),
+];
+
+/* Physical maps keys by where they sit on the keyboard, whatever the
+   layout. Logical maps them by what the layout says they are, so a keymap
+   entry for Q follows the Q key on an AZERTY keyboard. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyMappingMode {
+    Physical,
+    Logical,
+}
+
+impl KeyMappingMode {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "physical" => Some(KeyMappingMode::Physical),
+            "logical" => Some(KeyMappingMode::Logical),
+            _ => None,
+        }
+    }
+}
+
+/* A resolved keymap, in whichever terms its mode looks keys up by. */
+#[derive(Debug, Clone, PartialEq)]
+pub enum Keymap {
+    Physical(HashMap<Scancode, u8>),
+    Logical(HashMap<Keycode, u8>),
+}
+
+impl Keymap {
+    pub fn get(&self, scancode: Scancode, keycode: Option<Keycode>) -> Option<u8> {
+        match self {
+            Keymap::Physical(map) => map.get(&scancode).cloned(),
+            Keymap::Logical(map) => keycode.and_then(|k| map.get(&k).cloned()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ControlAction {
+    Quit,
+    Pause,
+    ToggleFullscreen,
+    ToggleGrid,
+    ToggleHud,
+    DumpScreen,
+    Turbo,
+    Reset,
+}
+
+/* Frontend keys, kept apart from the CHIP-8 keypad. A bound key never
+   reaches the emulated keypad, even if the keymap names it too. */
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControlBindings {
+    pub bindings: Vec<(Scancode, ControlAction)>,
+}
+
+impl Default for ControlBindings {
+    fn default() -> Self {
+        ControlBindings {
+            bindings: vec![
+                (Scancode::Escape, ControlAction::Quit),
+                (Scancode::Space, ControlAction::Pause),
+                (Scancode::F11, ControlAction::ToggleFullscreen),
+                (Scancode::G, ControlAction::ToggleGrid),
+                (Scancode::H, ControlAction::ToggleHud),
+                (Scancode::P, ControlAction::DumpScreen),
+                (Scancode::Tab, ControlAction::Turbo),
+                (Scancode::F5, ControlAction::Reset),
+            ],
+        }
+    }
+}
+
+impl ControlBindings {
+    pub fn action(&self, s: Scancode) -> Option<ControlAction> {
+        self.bindings.iter().find(|(b, _)| *b == s).map(|(_, a)| *a)
+    }
+
+    /* The CHIP-8 keys held given every scancode that is down, along with
+       the keycode the current layout gives it. A key counts as held if any
+       of the keymaps maps a pressed key to it. Bindings always go by
+       scancode. */
+    pub fn keypad<I>(&self, pressed: I, keymaps: &[Keymap]) -> HashSet<u8>
+            where I: Iterator<Item = (Scancode, Option<Keycode>)> {
+        pressed
+            .filter(|(s, _)| self.action(*s).is_none())
+            .flat_map(|(s, k)| keymaps.iter().filter_map(move |m| m.get(s, k)))
+            .collect()
+    }
+}
+
+pub struct Settings {
+    pub scale: u32,
+    pub fg_color: Color,
+    pub bg_color: Color,
+    /* SDL scancode names, or key names for Logical mapping, mapped to
+       CHIP-8 keys. Empty selects the default layout. */
+    pub keymap: Vec<(String, u8)>,
+    /* More keymaps that drive the same keypad, e.g. a second player on
+       the other side of the keyboard. These never fall back on a default. */
+    pub extra_keymaps: Vec<Vec<(String, u8)>>,
+    pub key_mapping: KeyMappingMode,
+    pub controls: ControlBindings,
+    /* Only scale by whole numbers, so that every CHIP-8 pixel comes out the
+       same size when the window is resized to an odd size. */
+    pub integer_scale: bool,
+    /* Append the presented frame rate to the window title. */
+    pub fps_in_title: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            scale: Controller::SQUARE_SIZE,
+            fg_color: Color::RGB(255, 255, 255),
+            bg_color: Color::RGB(0, 0, 0),
+            keymap: Vec::new(),
+            extra_keymaps: Vec::new(),
+            key_mapping: KeyMappingMode::Physical,
+            controls: ControlBindings::default(),
+            integer_scale: false,
+            fps_in_title: false,
+        }
+    }
+}
+
+/* Offscreen pieces for the CRT effect. The picture is drawn at native
+   resolution into a target texture and stretched onto the window, then a
+   scanline overlay that darkens the lower half of every CHIP-8 row is
+   blended over it. The overlay only depends on the resolution, so it is
+   built once per mode rather than on every frame. */
+struct Crt<'a> {
+    image: Texture<'a>,
+    scanlines: Texture<'a>,
+    width: u32,
+    height: u32,
+}
+
+impl<'a> Crt<'a> {
+    const SCANLINE_ALPHA: u32 = 0x60;
+
+    fn new(creator: &'a TextureCreator<WindowContext>, vram: &[u8]) -> Result<Self> {
+        let (width, height) = Controller::screen_size(vram);
+        let image = creator
+            .create_texture_target(PixelFormatEnum::RGB888, width, height)
+            .map_err(|e| e.to_string())?;
+
+        /* One column, two texels per row: clear on top, translucent black
+           below. */
+        let mut scanlines = creator
+            .create_texture_static(PixelFormatEnum::RGBA8888, 1, height * 2)
+            .map_err(|e| e.to_string())?;
+        let texels: Vec<u8> = (0..height * 2)
+            .flat_map(|n| (if n % 2 == 1 { Self::SCANLINE_ALPHA } else { 0 }).to_ne_bytes().to_vec())
+            .collect();
+        scanlines.update(None, &texels, 4).map_err(|e| e.to_string())?;
+        scanlines.set_blend_mode(BlendMode::Blend);
+
+        Ok(Crt { image, scanlines, width, height })
+    }
+
+    fn fits(&self, vram: &[u8]) -> bool {
+        Controller::screen_size(vram) == (self.width, self.height)
+    }
+
+    fn draw(&mut self, canvas: &mut WindowCanvas, vram: &[u8], palette: &io::Palette,
+            dst: Rect) -> Result<()> {
+        let width = self.width;
+        let points = |planes: u8| -> Vec<Point> {
+            vram.iter()
+                .enumerate()
+                .filter(|(_, px)| **px == planes)
+                .map(|(i, _)| Point::new((i as u32 % width) as i32, (i as u32 / width) as i32))
+                .collect()
+        };
+
+        let mut drawn = Ok(());
+        canvas.with_texture_canvas(&mut self.image, |c| {
+            c.set_draw_color(palette.color(0));
+            c.clear();
+            for planes in 1..4 {
+                c.set_draw_color(palette.color(planes));
+                drawn = drawn.clone().and_then(|_| c.draw_points(&points(planes)[..]));
+            }
+        }).map_err(|e| e.to_string())?;
+        drawn?;
+
+        canvas.copy(&self.image, None, dst)?;
+        canvas.copy(&self.scanlines, None, dst)
+    }
+}
+
 // cido - controller in driver out
 // codi - controller out driver in
 pub struct Controller {
     cido_tx: Sender<io::Command>,
     thread: Option<thread::JoinHandle<Result<()>>>,
     alive: Weak<()>,
+    audio: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    turbo: Arc<AtomicBool>,
+    reset: Arc<AtomicBool>,
+    key_stats: Arc<Mutex<io::KeyStats>>,
+    pressed_mask: Arc<AtomicU16>,
+    /* f32 bits, since there's no atomic float. */
+    fps: Arc<AtomicU32>,
 }
 
 impl Drop for Controller {
@@ -87,13 +320,50 @@ impl Controller {
     const SCREEN_WIDTH: u32 = Cpu::DISPLAY_WIDTH as u32;
     const SCREEN_HEIGHT: u32 = Cpu::DISPLAY_HEIGHT as u32;
     const WINDOW_TITLE: &'static str = "CHIP-8 Emulator";
-    const DISP_REFRESH_DELAY: std::time::Duration = std::time::Duration::from_millis(2);
+    const DISP_REFRESH_DELAY: Duration = Duration::from_millis(2);
+    const TITLE_INTERVAL: Duration = Duration::from_secs(1);
+    pub const DEFAULT_FPS: u32 = 60;
 
-    pub fn new() -> Self {
+    pub fn new(title_suffix: Option<&str>) -> Self {
+        Self::with_settings(title_suffix, Settings::default())
+    }
+
+    pub fn with_settings(title_suffix: Option<&str>, settings: Settings) -> Self {
+        Self::try_with_settings(title_suffix, settings)
+            .unwrap_or_else(|e| panic!("UI setup failed: {}", e))
+    }
+
+    pub fn try_new(title_suffix: Option<&str>) -> Result<Self> {
+        Self::try_with_settings(title_suffix, Settings::default())
+    }
+
+    /* Like with_settings, but waits for the UI thread to finish setting up
+       SDL and hands back anything that went wrong instead of panicking. */
+    pub fn try_with_settings(title_suffix: Option<&str>, settings: Settings) -> Result<Self> {
         let (cido_tx, cido_rx) = channel::<io::Command>();
+        let (ready_tx, ready_rx) = channel::<Result<()>>();
+
+        let title = match title_suffix {
+            Some(suffix) => format!("{} \u{2014} {}", Self::WINDOW_TITLE, suffix),
+            None => Self::WINDOW_TITLE.to_owned(),
+        };
 
         let alive = Arc::new(());
         let control = Arc::downgrade(&alive);
+        let audio = Arc::new(AtomicBool::new(false));
+        let audio_flag = Arc::clone(&audio);
+        let paused = Arc::new(AtomicBool::new(false));
+        let paused_flag = Arc::clone(&paused);
+        let turbo = Arc::new(AtomicBool::new(false));
+        let turbo_flag = Arc::clone(&turbo);
+        let reset = Arc::new(AtomicBool::new(false));
+        let reset_flag = Arc::clone(&reset);
+        let key_stats = Arc::new(Mutex::new(io::KeyStats::default()));
+        let key_stats_shared = Arc::clone(&key_stats);
+        let pressed_mask = Arc::new(AtomicU16::new(0));
+        let pressed_mask_shared = Arc::clone(&pressed_mask);
+        let fps = Arc::new(AtomicU32::new(0));
+        let fps_shared = Arc::clone(&fps);
 
         let thread = thread::spawn(move || {
             // By moving alive into this thread, we ensure that the
@@ -103,113 +373,444 @@ impl Controller {
             let _alive = alive;
             let cido_rx = cido_rx;
 
-            let sdl_context = sdl2::init()?;
-
-            let mut canvas = sdl_context
-                .chip8_canvas(
-                    Self::WINDOW_TITLE,
-                    Self::SCREEN_WIDTH * Self::SQUARE_SIZE,
-                    Self::SCREEN_HEIGHT * Self::SQUARE_SIZE)?;
-            let buzzer = sdl_context.chip8_buzzer()?;
-            let mut event_pump = sdl_context.event_pump()?;
+            /* Report setup failures back to try_with_settings before
+               giving up, so they don't go unnoticed until drop. */
+            let (keymaps, _sdl_context, mut canvas, buzzer, mut event_pump) =
+                match Self::setup(&title, &settings) {
+                    Ok(setup) => {
+                        /* Set before reporting ready, so the flag is right
+                           by the time try_with_settings returns. */
+                        audio_flag.store(setup.3.available(), Ordering::Relaxed);
+                        let _ = ready_tx.send(Ok(()));
+                        setup
+                    },
+                    Err(e) => {
+                        let _ = ready_tx.send(Err(e.clone()));
+                        return Err(e);
+                    },
+                };
             let mut codi_tx: Option<Sender<io::Key>> = None;
 
             canvas.clear();
             canvas.present();
 
-            let mut needs_key = false;
+            let mut key_wait = io::KeyWait::default();
+            let mut last_vram = vec![0u8; Cpu::VRAM_BYTES];
+            let mut palette = io::Palette::new(settings.bg_color, settings.fg_color);
+            let mut frame_interval = Self::frame_interval(Self::DEFAULT_FPS);
+            let mut last_present: Option<Instant> = None;
+            let mut dirty = false;
+            let mut show_grid = false;
+            let mut hud_state: Option<io::CpuState> = None;
+            let mut show_hud = false;
+            let texture_creator = canvas.texture_creator();
+            let mut crt_effect = false;
+            let mut pixel_shape = io::PixelShape::Square;
+            let mut pixel_masks = io::PixelMasks::default();
+            let mut crt: Option<Crt> = None;
+            let mut fps_counter = io::FpsCounter::default();
+            let mut last_title = Instant::now();
 
             'running: loop {
-                let pressed_keys: HashSet<u8> = event_pump
-                    .keyboard_state()
-                    .pressed_scancodes()
-                    .filter_map(|s| match s {
-                        Scancode::Num1 => Some(0x1),
-                        Scancode::Num2 => Some(0x2),
-                        Scancode::Num3 => Some(0x3),
-                        Scancode::Num4 => Some(0xc),
-                        Scancode::Q => Some(0x4),
-                        Scancode::W => Some(0x5),
-                        Scancode::E => Some(0x6),
-                        Scancode::R => Some(0xd),
-                        Scancode::A => Some(0x7),
-                        Scancode::S => Some(0x8),
-                        Scancode::D => Some(0x9),
-                        Scancode::F => Some(0xe),
-                        Scancode::Z => Some(0xa),
-                        Scancode::X => Some(0x0),
-                        Scancode::C => Some(0xb),
-                        Scancode::V => Some(0xf),
-                        _ => None,
-                    })
-                    .collect();
+                let pressed_keys = settings.controls.keypad(
+                    event_pump.keyboard_state()
+                        .pressed_scancodes()
+                        .map(|s| (s, Keycode::from_scancode(s))),
+                    &keymaps);
+                let mask = io::key_mask(&pressed_keys);
+                if pressed_mask_shared.swap(mask, Ordering::Relaxed) != mask {
+                    dirty |= show_hud;
+                }
 
                 match cido_rx.try_recv() {
                     Ok(io::Command::BuzzStart) => buzzer.resume(),
                     Ok(io::Command::BuzzStop) => buzzer.pause(),
                     Ok(io::Command::DisplayRefresh(vram)) => {
-                        let light = sdl2::pixels::Color::RGB(255, 255, 255);
-                        let dark = sdl2::pixels::Color::RGB(0, 0, 0);
-                        for (i, px_set) in vram.iter().enumerate() {
-                            canvas.set_draw_color(
-                                if *px_set { light } else { dark
-                                }
-                            );
-                            let i = i as u32;
-                            let x = (i % Self::SCREEN_WIDTH) * Self::SQUARE_SIZE;
-                            let y = (i / Self::SCREEN_WIDTH) * Self::SQUARE_SIZE;
-                            canvas.fill_rect(
-                                sdl2::rect::Rect::new(
-                                    x as i32, y as i32, Self::SQUARE_SIZE, Self::SQUARE_SIZE
-                                )
-                            )?;
-                        }
-                        canvas.present();
+                        last_vram = vram;
+                        dirty = true;
+                    },
+                    Ok(io::Command::HudUpdate(state)) => {
+                        dirty |= show_hud && hud_state.as_ref() != Some(&state);
+                        hud_state = Some(state);
+                    },
+                    Ok(io::Command::DumpScreen) => print!("{}", Self::dump(&last_vram)),
+                    Ok(io::Command::KeyBlock) => key_wait.begin(&pressed_keys),
+                    Ok(io::Command::KeyCancel) => {
+                        Self::cancel_key_wait_now(&mut key_wait, &codi_tx)
                     },
-                    Ok(io::Command::KeyBlock) => needs_key = true,
                     Ok(io::Command::KeyChanSet(tx)) => codi_tx = tx,
                     Ok(io::Command::KeyPoll(k)) => {
                         if let Some(tx) =  &codi_tx {
-                            let _ = tx.send(
-                                if pressed_keys.contains(&k) { Some(k) } else { None }
-                            );
+                            let _ = tx.send(io::key_poll_reply(&pressed_keys, k));
                         }
                     },
                     Ok(io::Command::Quit) => break 'running,
+                    Ok(io::Command::Reset) => reset_flag.store(true, Ordering::Relaxed),
+                    Ok(io::Command::ToggleFullscreen) => {
+                        Self::toggle_fullscreen(&mut canvas)?;
+                        dirty = true;
+                    },
+                    Ok(io::Command::SetFps(fps)) => frame_interval = Self::frame_interval(fps),
+                    Ok(io::Command::SetCrtEffect(on)) => {
+                        crt_effect = on;
+                        dirty = true;
+                    },
+                    Ok(io::Command::SetPalette(p)) => {
+                        palette = p;
+                        dirty = true;
+                    },
+                    Ok(io::Command::SetPixelShape(shape)) => {
+                        pixel_shape = shape;
+                        dirty = true;
+                    },
+                    Ok(io::Command::SetTurbo(on)) => turbo_flag.store(on, Ordering::Relaxed),
+                    Ok(io::Command::ToggleHud) => {
+                        show_hud = !show_hud;
+                        dirty = true;
+                    },
+                    Ok(io::Command::ToggleGrid) => {
+                        show_grid = !show_grid;
+                        dirty = true;
+                    },
                     _ => (),
                 }
 
-                if needs_key && !pressed_keys.is_empty() {
+                if let Some(k) = key_wait.update(&pressed_keys) {
                     if let Some(tx) = &codi_tx {
-                        let _ = tx.send(Some(*pressed_keys.iter().nth(0).unwrap()));
+                        let _ = tx.send(Some(k));
                     }
-                    needs_key = false;
                 }
 
                 for event in event_pump.poll_iter() {
+                    key_stats_shared.lock().unwrap().record(&event, |sc, kc| {
+                        settings.controls.keypad(iter::once((sc, kc)), &keymaps)
+                    });
                     match event {
-                        Event::Quit {..} => {
-                            break 'running;
+                        Event::Quit {..} => break 'running,
+                        Event::KeyDown { scancode: Some(sc), repeat: false, .. } => {
+                            match settings.controls.action(sc) {
+                                /* Escape by default. Leaving the loop ends the
+                                   thread, which drops _alive and lets the main
+                                   loop see that the UI is gone. */
+                                Some(ControlAction::Quit) => break 'running,
+                                Some(ControlAction::Pause) => {
+                                    paused_flag.fetch_xor(true, Ordering::Relaxed);
+                                },
+                                Some(ControlAction::ToggleFullscreen) => {
+                                    Self::toggle_fullscreen(&mut canvas)?;
+                                    dirty = true;
+                                },
+                                Some(ControlAction::ToggleGrid) => {
+                                    show_grid = !show_grid;
+                                    dirty = true;
+                                },
+                                Some(ControlAction::ToggleHud) => {
+                                    show_hud = !show_hud;
+                                    dirty = true;
+                                },
+                                Some(ControlAction::DumpScreen) => {
+                                    print!("{}", Self::dump(&last_vram));
+                                },
+                                Some(ControlAction::Turbo) => {
+                                    turbo_flag.store(true, Ordering::Relaxed);
+                                },
+                                Some(ControlAction::Reset) => {
+                                    reset_flag.store(true, Ordering::Relaxed);
+                                },
+                                None => (),
+                            }
+                        },
+                        Event::KeyUp { scancode: Some(sc), .. }
+                                if settings.controls.action(sc) == Some(ControlAction::Turbo) => {
+                            turbo_flag.store(false, Ordering::Relaxed);
                         },
-                        _ => ()
+                        Event::Window { win_event: WindowEvent::SizeChanged(..), .. } |
+                        Event::Window { win_event: WindowEvent::Exposed, .. } => {
+                            dirty = true;
+                        },
+                        _ => (),
                     }
+                }
 
-                    canvas.present();
+                /* Draws can come in much faster than the display refreshes, so
+                   coalesce them and present at most once per frame interval. */
+                let frame_due = !matches!(last_present,
+                                          Some(t) if t.elapsed() < frame_interval);
+                if dirty && frame_due {
+                    let hud = hud_state.as_ref()
+                        .filter(|_| show_hud)
+                        .map(|state| (state, pressed_mask_shared.load(Ordering::Relaxed)));
+                    if crt_effect && !matches!(&crt, Some(c) if c.fits(&last_vram)) {
+                        crt = Some(Crt::new(&texture_creator, &last_vram)?);
+                    }
+                    let crt = crt.as_mut().filter(|_| crt_effect);
+                    let pixels = (pixel_shape, &mut pixel_masks);
+                    Self::render(&mut canvas, &last_vram, &settings, &palette, pixels, show_grid,
+                                 hud, crt)?;
+                    let now = Instant::now();
+                    last_present = Some(now);
+                    dirty = false;
+
+                    fps_counter.record(now);
+                    fps_shared.store(fps_counter.fps().to_bits(), Ordering::Relaxed);
+                    if settings.fps_in_title && now.duration_since(last_title) >= Self::TITLE_INTERVAL {
+                        let title = format!("{} ({:.0} fps)", title, fps_counter.fps());
+                        canvas.window_mut().set_title(&title).map_err(|e| e.to_string())?;
+                        last_title = now;
+                    }
                 }
 
                 thread::sleep(Self::DISP_REFRESH_DELAY);
             }
 
+            /* Closing the channel would wake a waiting CPU too, but as key 0;
+               this lets it tell the window went away. */
+            Self::cancel_key_wait_now(&mut key_wait, &codi_tx);
             Ok(())
         });
 
-        Controller {
+        if let Err(e) = ready_rx.recv().unwrap_or_else(|_| Err("UI thread panicked".to_owned())) {
+            let _ = thread.join();
+            return Err(e);
+        }
+
+        Ok(Controller {
             cido_tx,
             thread: Some(thread),
-            alive: control
+            alive: control,
+            audio,
+            paused,
+            turbo,
+            reset,
+            key_stats,
+            pressed_mask,
+            fps,
+        })
+    }
+
+    fn frame_interval(fps: u32) -> Duration {
+        if fps == 0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_nanos(1_000_000_000 / fps as u64)
+        }
+    }
+
+    /* Resolves scancode or key names, depending on mode, falling back on
+       the default layout when none are given. */
+    fn keymap(names: &[(String, u8)], mode: KeyMappingMode) -> Result<Keymap> {
+        let unknown = |name: &str| format!("unknown key name: {}", name);
+
+        match mode {
+            KeyMappingMode::Physical if names.is_empty() => {
+                Ok(Keymap::Physical(KEYPAD_DEFAULT.iter().cloned().collect()))
+            },
+            KeyMappingMode::Logical if names.is_empty() => {
+                Ok(Keymap::Logical(KEYPAD_DEFAULT_LOGICAL.iter().cloned().collect()))
+            },
+            KeyMappingMode::Physical => names.iter()
+                .map(|(name, k)| {
+                    Scancode::from_name(name).map(|s| (s, *k)).ok_or_else(|| unknown(name))
+                })
+                .collect::<Result<_>>()
+                .map(Keymap::Physical),
+            KeyMappingMode::Logical => names.iter()
+                .map(|(name, k)| {
+                    Keycode::from_name(name).map(|c| (c, *k)).ok_or_else(|| unknown(name))
+                })
+                .collect::<Result<_>>()
+                .map(Keymap::Logical),
         }
     }
 
+    #[allow(clippy::type_complexity)]
+    fn setup(title: &str, settings: &Settings)
+            -> Result<(Vec<Keymap>, sdl2::Sdl, WindowCanvas, Box<dyn Buzz>, EventPump)> {
+        let mut keymaps = vec![Self::keymap(&settings.keymap, settings.key_mapping)?];
+        for names in settings.extra_keymaps.iter().filter(|names| !names.is_empty()) {
+            keymaps.push(Self::keymap(names, settings.key_mapping)?);
+        }
+
+        let sdl_context = sdl2::init()?;
+
+        let canvas = sdl_context
+            .chip8_canvas(
+                title,
+                Self::SCREEN_WIDTH * settings.scale,
+                Self::SCREEN_HEIGHT * settings.scale)?;
+        let buzzer = io::buzzer_or_null(sdl_context.chip8_buzzer());
+        let event_pump = sdl_context.event_pump()?;
+
+        Ok((keymaps, sdl_context, canvas, buzzer, event_pump))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn render(canvas: &mut WindowCanvas, vram: &[u8], settings: &Settings,
+              palette: &io::Palette, pixels: (io::PixelShape, &mut io::PixelMasks),
+              grid: bool, hud: Option<(&io::CpuState, u16)>,
+              crt: Option<&mut Crt>) -> Result<()> {
+        let (out_w, out_h) = canvas.output_size()?;
+        let (screen_w, screen_h) = Self::screen_size(vram);
+
+        let (off_x, off_y, pic_w, pic_h) = Self::picture_geometry(
+            (out_w, out_h), (screen_w, screen_h), settings.integer_scale);
+
+        canvas.set_draw_color(palette.color(0));
+        canvas.clear();
+
+        /* The CRT path draws a texel per pixel, so it only does squares. */
+        if let Some(crt) = crt {
+            crt.draw(canvas, vram, palette, Rect::new(off_x as i32, off_y as i32, pic_w, pic_h))?;
+        } else {
+            let (shape, masks) = pixels;
+            let mut rects = Vec::new();
+            for (i, &planes) in vram.iter().enumerate().filter(|(_, px)| **px != 0) {
+                canvas.set_draw_color(palette.color(planes));
+                let i = i as u32;
+                let col = i % screen_w;
+                let row = i / screen_w;
+                let x0 = off_x + col * pic_w / screen_w;
+                let x1 = off_x + (col + 1) * pic_w / screen_w;
+                let y0 = off_y + row * pic_h / screen_h;
+                let y1 = off_y + (row + 1) * pic_h / screen_h;
+                rects.clear();
+                rects.extend(masks.get(shape, x1 - x0, y1 - y0).iter().map(|r| {
+                    Rect::new(r.x() + x0 as i32, r.y() + y0 as i32, r.width(), r.height())
+                }));
+                canvas.fill_rects(&rects)?;
+            }
+        }
+
+        if grid {
+            /* Thin lines on the pixel boundaries in a dim shade of the
+               foreground color. */
+            let fg = palette.color(1);
+            canvas.set_draw_color(Color::RGB(fg.r / 4, fg.g / 4, fg.b / 4));
+            for col in 1..screen_w {
+                let x = off_x + col * pic_w / screen_w;
+                canvas.fill_rect(Rect::new(x as i32, off_y as i32, 1, pic_h))?;
+            }
+            for row in 1..screen_h {
+                let y = off_y + row * pic_h / screen_h;
+                canvas.fill_rect(Rect::new(off_x as i32, y as i32, pic_w, 1))?;
+            }
+        }
+
+        if let Some((state, keys)) = hud {
+            hud::draw(canvas, state, keys, palette.color(1), palette.color(0))?;
+        }
+
+        canvas.present();
+        Ok(())
+    }
+
+    fn cancel_key_wait_now(key_wait: &mut io::KeyWait, codi_tx: &Option<Sender<io::Key>>) {
+        if let (true, Some(tx)) = (key_wait.cancel(), codi_tx) {
+            let _ = tx.send(Some(KEY_CANCELLED));
+        }
+    }
+
+    /* ASCII art of a frame, any lit plane counting as lit. */
+    fn dump(vram: &[u8]) -> String {
+        screen::render_ascii(&vram.iter().map(|&px| px != 0).collect::<Vec<bool>>())
+    }
+
+    fn screen_size(vram: &[u8]) -> (u32, u32) {
+        if vram.len() == Cpu::HIRES_VRAM_BYTES {
+            (Cpu::HIRES_DISPLAY_WIDTH as u32, Cpu::HIRES_DISPLAY_HEIGHT as u32)
+        } else {
+            (Self::SCREEN_WIDTH, Self::SCREEN_HEIGHT)
+        }
+    }
+
+    /* Where the picture goes in an out_w by out_h window, as x and y offsets
+       followed by width and height. The picture is scaled up as far as it
+       will go without distorting the aspect ratio and whatever is left over
+       is letterboxed. With integer scaling every CHIP-8 pixel is the same
+       whole number of window pixels across. */
+    fn picture_geometry((out_w, out_h): (u32, u32), (screen_w, screen_h): (u32, u32),
+                        integer: bool) -> (u32, u32, u32, u32) {
+        let (pic_w, pic_h) = if integer {
+            let scale = u32::max(1, u32::min(out_w / screen_w, out_h / screen_h));
+            (screen_w * scale, screen_h * scale)
+        } else {
+            let scale = f32::min(out_w as f32 / screen_w as f32,
+                                 out_h as f32 / screen_h as f32);
+            ((screen_w as f32 * scale) as u32, (screen_h as f32 * scale) as u32)
+        };
+        let off_x = out_w.saturating_sub(pic_w) / 2;
+        let off_y = out_h.saturating_sub(pic_h) / 2;
+        (off_x, off_y, pic_w, pic_h)
+    }
+
+    fn toggle_fullscreen(canvas: &mut WindowCanvas) -> Result<()> {
+        let window = canvas.window_mut();
+        let next = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        window.set_fullscreen(next)
+    }
+
+    pub fn toggle_fullscreen_mode(&self) {
+        let _ = self.cido_tx.send(io::Command::ToggleFullscreen);
+    }
+
+    /* Prints the last frame to stdout as text, same as the DumpScreen control. */
+    pub fn dump_screen(&self) {
+        let _ = self.cido_tx.send(io::Command::DumpScreen);
+    }
+
+    /* Shows or hides the register overlay, same as the ToggleHud control. */
+    pub fn toggle_hud(&self) {
+        let _ = self.cido_tx.send(io::Command::ToggleHud);
+    }
+
+    /* Hands the overlay a fresh copy of the CPU registers. */
+    pub fn update_hud(&self, cpu: &Cpu) {
+        let _ = self.cido_tx.send(io::Command::HudUpdate(io::CpuState {
+            pc: cpu.program_counter(),
+            i: cpu.index_register(),
+            v: cpu.registers().to_vec(),
+        }));
+    }
+
+    /* Shows or hides lines between pixels, same as the ToggleGrid control. */
+    pub fn toggle_grid(&self) {
+        let _ = self.cido_tx.send(io::Command::ToggleGrid);
+    }
+
+    /* Caps how often frames are presented. Zero removes the cap. */
+    pub fn set_fps(&self, fps: u32) {
+        let _ = self.cido_tx.send(io::Command::SetFps(fps));
+    }
+
+    /* Asks the host to restart the ROM, same as the Reset control. */
+    pub fn request_reset(&self) {
+        let _ = self.cido_tx.send(io::Command::Reset);
+    }
+
+    /* Square by default. */
+    pub fn set_pixel_shape(&self, shape: io::PixelShape) {
+        let _ = self.cido_tx.send(io::Command::SetPixelShape(shape));
+    }
+
+    /* Draws through an offscreen texture with every other row darkened,
+       for a CRT-ish look. Off by default. */
+    pub fn set_crt_effect(&self, on: bool) {
+        let _ = self.cido_tx.send(io::Command::SetCrtEffect(on));
+    }
+
+    /* Starts out as the settings' bg and fg colors. */
+    pub fn set_palette(&self, palette: io::Palette) {
+        let _ = self.cido_tx.send(io::Command::SetPalette(palette));
+    }
+
+    /* Lifts the speed cap, same as holding the Turbo control. */
+    pub fn set_turbo(&self, on: bool) {
+        let _ = self.cido_tx.send(io::Command::SetTurbo(on));
+    }
+
     pub fn get_sound_driver(&self) -> Box<SoundDriver> {
         Box::new(SoundDriver {
             cido_tx: self.cido_tx.clone()
@@ -222,20 +823,162 @@ impl Controller {
         Box::new(InputDriver {
             codi_rx,
             cido_tx: self.cido_tx.clone(),
+            blocking: Cell::new(false),
         })
     }
 
+    /* Same as the input driver's cancel_block, but usable from any thread,
+       e.g. while the CPU thread is stuck in a blocking FX0A. */
+    pub fn cancel_key_wait(&self) {
+        let _ = self.cido_tx.send(io::Command::KeyCancel);
+    }
+
     pub fn get_display_driver(&self) -> Box<DisplayDriver> {
         Box::new(DisplayDriver {
             cido_tx: self.cido_tx.clone()
         })
     }
 
+    /* True once the UI thread has opened an audio device. Otherwise
+       buzzing is silently ignored. */
+    pub fn audio_available(&self) -> bool {
+        self.audio.load(Ordering::Relaxed)
+    }
+
+    /* Toggled by the Pause control. The host is expected to stop running
+       the CPU while this is set. */
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /* Set while the Turbo control is held. The host is expected to run the
+       CPU as fast as it can while this is set. */
+    pub fn turbo(&self) -> bool {
+        self.turbo.load(Ordering::Relaxed)
+    }
+
+    /* True once per press of the Reset control. The host is expected to
+       reset the CPU and load the ROM again when this returns true. */
+    pub fn take_reset(&self) -> bool {
+        self.reset.swap(false, Ordering::Relaxed)
+    }
+
+    /* How many times each CHIP-8 key has been pressed and released, as
+       (presses, releases) indexed by key. */
+    pub fn key_stats(&self) -> [(u32, u32); 16] {
+        self.key_stats.lock().unwrap().counts()
+    }
+
+    /* The CHIP-8 keys held as of the last UI loop, bit n for key n. */
+    pub fn pressed_mask(&self) -> u16 {
+        self.pressed_mask.load(Ordering::Relaxed)
+    }
+
+    /* Frames actually presented per second, averaged over the last second. */
+    pub fn fps(&self) -> f32 {
+        f32::from_bits(self.fps.load(Ordering::Relaxed))
+    }
+
     pub fn alive(&self) -> bool {
-        if let Some(_) = self.alive.upgrade() {
-            true
-        } else {
-            false
-        }
+        self.alive.upgrade().is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keypad_default() {
+        let keys: HashSet<u8> = KEYPAD_DEFAULT.iter().map(|(_, k)| *k).collect();
+        let scancodes: HashSet<Scancode> = KEYPAD_DEFAULT.iter().map(|(s, _)| *s).collect();
+
+        assert_eq!(keys, (0..0x10).collect());
+        assert_eq!(scancodes.len(), KEYPAD_DEFAULT.len());
+        let keymap = Controller::keymap(&[], KeyMappingMode::Physical).unwrap();
+        assert_eq!(keymap.get(Scancode::V, None), Some(0xf));
+        assert_eq!(keymap.get(Scancode::P, None), None);
+
+        let logical: HashSet<u8> = KEYPAD_DEFAULT_LOGICAL.iter().map(|(_, k)| *k).collect();
+        assert_eq!(logical, keys);
+    }
+
+    #[test]
+    fn key_mapping_modes() {
+        let controls = ControlBindings::default();
+        /* What an AZERTY layout reports with the Q and A keys held. */
+        let azerty = || vec![(Scancode::Q, Some(Keycode::A)), (Scancode::A, Some(Keycode::Q))];
+
+        let physical = [Controller::keymap(&[], KeyMappingMode::Physical).unwrap()];
+        assert_eq!(controls.keypad(azerty().into_iter(), &physical),
+                   [0x4, 0x7].iter().cloned().collect());
+
+        let mut map = HashMap::new();
+        map.insert(Keycode::Q, 0x4);
+        let logical = Keymap::Logical(map);
+        assert_eq!(logical.get(Scancode::A, None), None);
+        let logical = [logical];
+        assert_eq!(controls.keypad(azerty().into_iter(), &logical),
+                   [0x4].iter().cloned().collect());
+
+        assert_eq!(KeyMappingMode::from_name("logical"), Some(KeyMappingMode::Logical));
+        assert_eq!(KeyMappingMode::from_name("qwerty"), None);
+    }
+
+    #[test]
+    fn control_bindings() {
+        let controls = ControlBindings::default();
+        let mut map: HashMap<Scancode, u8> = KEYPAD_DEFAULT.iter().cloned().collect();
+        map.insert(Scancode::Escape, 0x1);
+        let keymaps = [Keymap::Physical(map)];
+
+        assert_eq!(controls.action(Scancode::Escape), Some(ControlAction::Quit));
+        assert_eq!(controls.action(Scancode::Tab), Some(ControlAction::Turbo));
+        assert_eq!(controls.action(Scancode::F5), Some(ControlAction::Reset));
+        assert_eq!(controls.action(Scancode::Q), None);
+
+        let pressed = vec![(Scancode::Escape, None), (Scancode::Q, None)];
+        let keys = controls.keypad(pressed.into_iter(), &keymaps);
+        assert_eq!(keys, [0x4].iter().cloned().collect());
+    }
+
+    #[test]
+    fn extra_keymaps() {
+        let controls = ControlBindings::default();
+        let mut second = HashMap::new();
+        second.insert(Scancode::Up, 0x5);
+        second.insert(Scancode::Down, 0x8);
+        let keymaps = [
+            Controller::keymap(&[], KeyMappingMode::Physical).unwrap(),
+            Keymap::Physical(second),
+        ];
+
+        /* W and Up are the same CHIP-8 key, one for each player. */
+        let held = |keys: Vec<Scancode>| {
+            controls.keypad(keys.into_iter().map(|s| (s, None)), &keymaps)
+        };
+        assert_eq!(held(vec![Scancode::W]), [0x5].iter().cloned().collect());
+        assert_eq!(held(vec![Scancode::Up]), [0x5].iter().cloned().collect());
+        assert_eq!(held(vec![Scancode::W, Scancode::Up, Scancode::Down]),
+                   [0x5, 0x8].iter().cloned().collect());
+    }
+
+    #[test]
+    fn picture_geometry() {
+        let lores = (Cpu::DISPLAY_WIDTH as u32, Cpu::DISPLAY_HEIGHT as u32);
+
+        assert_eq!(Controller::picture_geometry((640, 320), lores, true), (0, 0, 640, 320));
+        assert_eq!(Controller::picture_geometry((700, 330), lores, false), (20, 0, 660, 330));
+        assert_eq!(Controller::picture_geometry((700, 330), lores, true), (30, 5, 640, 320));
+        assert_eq!(Controller::picture_geometry((32, 16), lores, true), (0, 0, 64, 32));
+    }
+
+    #[test]
+    fn try_new_reports_errors() {
+        let settings = Settings {
+            keymap: vec![("NotAKey".to_owned(), 0x1)],
+            ..Settings::default()
+        };
+        assert!(Controller::try_with_settings(None, settings).is_err());
     }
 }
@@ -1,4 +1,5 @@
-use std::sync::mpsc::{Sender, Receiver};
+use std::cell::Cell;
+use std::sync::mpsc::{Sender, Receiver, TryRecvError};
 
 use crate::core::driver::{Input, Sound, Display};
 use super::io;
@@ -7,30 +8,70 @@ pub struct SoundDriver {
     pub cido_tx: Sender<io::Command>,
 }
 
+/* Sends to a UI thread that has exited are simply dropped, here and in
+   DisplayDriver, since there is no one left to hear or see them. */
 impl Sound for SoundDriver {
     fn start_buzz(&self) {
-        self.cido_tx.send(io::Command::BuzzStart).unwrap();
+        let _ = self.cido_tx.send(io::Command::BuzzStart);
     }
 
     fn stop_buzz(&self) {
-        self.cido_tx.send(io::Command::BuzzStop).unwrap();
+        let _ = self.cido_tx.send(io::Command::BuzzStop);
     }
 }
 
 pub struct InputDriver {
     pub codi_rx: Receiver<io::Key>,
     pub cido_tx: Sender<io::Command>,
+    pub blocking: Cell<bool>,
 }
 
+/* The UI thread going away is a normal part of shutting down, so a closed
+   channel reads as no key held, and as key 0 for anything waiting on a
+   press, rather than panicking the CPU thread. */
 impl Input for InputDriver {
     fn poll(&self, key: u8) -> bool {
-        self.cido_tx.send(io::Command::KeyPoll(key)).unwrap();
-        self.codi_rx.recv() == Ok(Some(key))
+        self.cido_tx.send(io::Command::KeyPoll(key)).is_ok() &&
+            self.codi_rx.recv() == Ok(Some(key))
     }
 
     fn block(&self) -> u8 {
-        self.cido_tx.send(io::Command::KeyBlock).unwrap();
-        self.codi_rx.recv().unwrap_or(Some(0)).unwrap()
+        if self.cido_tx.send(io::Command::KeyBlock).is_err() {
+            return 0;
+        }
+        self.codi_rx.recv().ok().flatten().unwrap_or(0)
+    }
+
+    fn try_block(&self) -> Option<u8> {
+        if !self.blocking.get() {
+            if self.cido_tx.send(io::Command::KeyBlock).is_err() {
+                return Some(0);
+            }
+            self.blocking.set(true);
+        }
+
+        match self.codi_rx.try_recv() {
+            Ok(key) => {
+                self.blocking.set(false);
+                key
+            },
+            Err(TryRecvError::Empty) => None,
+            Err(TryRecvError::Disconnected) => {
+                self.blocking.set(false);
+                Some(0)
+            },
+        }
+    }
+
+    /* The UI thread answers with KEY_CANCELLED in place of a key, if it was
+       waiting on one. Every KeyBlock gets exactly one answer, so a pending
+       try_block's is taken here rather than left for a later poll or block
+       to mistake for its own. */
+    fn cancel_block(&self) {
+        if self.cido_tx.send(io::Command::KeyCancel).is_ok() && self.blocking.get() {
+            let _ = self.codi_rx.recv();
+        }
+        self.blocking.set(false);
     }
 }
 
@@ -40,9 +81,219 @@ pub struct DisplayDriver {
 
 impl Display for DisplayDriver {
     fn refresh(&mut self, vram: &[bool]) {
-        self.cido_tx.send(
-            io::Command::DisplayRefresh(
-                vram.to_owned()
-        )).unwrap();
+        let _ = self.cido_tx.send(io::Command::DisplayRefresh(io::plane_bits(vram)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::mpsc::channel;
+    use std::thread;
+
+    use crate::core::cpu::{Cpu, RunState};
+    use crate::core::driver::KEY_CANCELLED;
+    use super::*;
+
+    #[test]
+    fn poll_multiple_keys() {
+        let (cido_tx, cido_rx) = channel::<io::Command>();
+        let (codi_tx, codi_rx) = channel::<io::Key>();
+        let driver = InputDriver {
+            codi_rx,
+            cido_tx,
+            blocking: Cell::new(false),
+        };
+
+        /* Stand in for the SDL thread with 0xa and 0xb held. */
+        let ui = thread::spawn(move || {
+            let pressed_keys: HashSet<u8> = [0xa, 0xb].iter().cloned().collect();
+            while let Ok(io::Command::KeyPoll(k)) = cido_rx.recv() {
+                codi_tx.send(io::key_poll_reply(&pressed_keys, k)).unwrap();
+            }
+        });
+
+        assert!(driver.poll(0xa));
+        assert!(driver.poll(0xb));
+        assert!(!driver.poll(0xc));
+        assert!(driver.poll(0xa));
+
+        drop(driver);
+        ui.join().unwrap();
+    }
+
+    #[test]
+    fn block_needs_fresh_press() {
+        let (cido_tx, cido_rx) = channel::<io::Command>();
+        let (codi_tx, codi_rx) = channel::<io::Key>();
+        let driver = InputDriver {
+            codi_rx,
+            cido_tx,
+            blocking: Cell::new(false),
+        };
+        let mut key_wait = io::KeyWait::default();
+        let keys = |k: &[u8]| k.iter().cloned().collect::<HashSet<u8>>();
+
+        /* Play the SDL thread's part by hand, one frame per update. */
+        let mut frame = |pressed: &[u8]| {
+            if let Ok(io::Command::KeyBlock) = cido_rx.try_recv() {
+                key_wait.begin(&keys(pressed));
+            }
+            if let Some(k) = key_wait.update(&keys(pressed)) {
+                codi_tx.send(Some(k)).unwrap();
+            }
+        };
+
+        /* 0x5 is already down when the block starts, so it doesn't count. */
+        assert_eq!(driver.try_block(), None);
+        frame(&[0x5]);
+        assert_eq!(driver.try_block(), None);
+        frame(&[0x5]);
+        assert_eq!(driver.try_block(), None);
+        frame(&[0x5, 0x7]);
+        assert_eq!(driver.try_block(), Some(0x7));
+
+        /* Releasing and pressing it again does. */
+        assert_eq!(driver.try_block(), None);
+        frame(&[0x5]);
+        frame(&[]);
+        assert_eq!(driver.try_block(), None);
+        frame(&[0x5]);
+        assert_eq!(driver.try_block(), Some(0x5));
+    }
+
+    #[test]
+    fn cancel_block() {
+        let (cido_tx, cido_rx) = channel::<io::Command>();
+        let (codi_tx, codi_rx) = channel::<io::Key>();
+        let canceller = cido_tx.clone();
+        let driver = InputDriver {
+            codi_rx,
+            cido_tx,
+            blocking: Cell::new(false),
+        };
+        let (waiting_tx, waiting_rx) = channel::<()>();
+
+        /* The SDL thread, with no key ever pressed. */
+        let ui = thread::spawn(move || {
+            let mut key_wait = io::KeyWait::default();
+            while let Ok(cmd) = cido_rx.recv() {
+                match cmd {
+                    io::Command::KeyBlock => {
+                        key_wait.begin(&HashSet::new());
+                        let _ = waiting_tx.send(());
+                    },
+                    io::Command::KeyCancel if key_wait.cancel() => {
+                        codi_tx.send(Some(KEY_CANCELLED)).unwrap();
+                    },
+                    _ => (),
+                }
+            }
+        });
+
+        /* Another thread cancels a block() that would otherwise never
+           return, as Controller::cancel_key_wait does. */
+        let cancel = thread::spawn(move || {
+            waiting_rx.recv().unwrap();
+            canceller.send(io::Command::KeyCancel).unwrap();
+            waiting_rx
+        });
+        assert_eq!(driver.block(), KEY_CANCELLED);
+        let waiting_rx = cancel.join().unwrap();
+
+        /* Cancelling with nothing pending sends nothing back. */
+        driver.cancel_block();
+        assert_eq!(driver.try_block(), None);
+        waiting_rx.recv().unwrap();
+        driver.cancel_block();
+        assert!(!driver.blocking.get());
+        assert_eq!(driver.codi_rx.try_recv(), Err(TryRecvError::Empty));
+
+        drop(driver);
+        ui.join().unwrap();
+    }
+
+    #[test]
+    fn reset_cancels_key_wait() {
+        let (cido_tx, cido_rx) = channel::<io::Command>();
+        let (codi_tx, codi_rx) = channel::<io::Key>();
+        let mut cpu = Cpu::new_without_timer_thread();
+        cpu.set_input_driver(Some(Box::new(InputDriver {
+            codi_rx,
+            cido_tx,
+            blocking: Cell::new(false),
+        })));
+
+        /* The SDL thread, with 0x3 held throughout. It hands back whether
+           it was still waiting on a key once the CPU is gone. */
+        let ui = thread::spawn(move || {
+            let pressed_keys: HashSet<u8> = [0x3].iter().cloned().collect();
+            let mut key_wait = io::KeyWait::default();
+            while let Ok(cmd) = cido_rx.recv() {
+                match cmd {
+                    io::Command::KeyBlock => key_wait.begin(&pressed_keys),
+                    io::Command::KeyCancel if key_wait.cancel() => {
+                        codi_tx.send(Some(KEY_CANCELLED)).unwrap();
+                    },
+                    io::Command::KeyPoll(k) => {
+                        codi_tx.send(io::key_poll_reply(&pressed_keys, k)).unwrap();
+                    },
+                    _ => (),
+                }
+            }
+            key_wait.cancel()
+        });
+
+        /* ld v0, 0x3; ld v1, k */
+        cpu.load(&[0x60, 0x03, 0xf1, 0x0a]).unwrap();
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(cpu.state(), RunState::WaitingForKey);
+
+        /* The cancelled wait's answer mustn't be taken for the poll's. */
+        cpu.reset();
+        assert_eq!(cpu.state(), RunState::Running);
+        /* ld v0, 0x3; skp v0 */
+        cpu.load(&[0x60, 0x03, 0xe0, 0x9e]).unwrap();
+        cpu.tick().unwrap();
+        cpu.tick().unwrap();
+        assert_eq!(cpu.program_counter(), 0x206);
+
+        drop(cpu);
+        assert!(!ui.join().unwrap());
+    }
+
+    #[test]
+    fn input_after_ui_exits() {
+        let (cido_tx, cido_rx) = channel::<io::Command>();
+        let (codi_tx, codi_rx) = channel::<io::Key>();
+        let driver = InputDriver {
+            codi_rx,
+            cido_tx,
+            blocking: Cell::new(false),
+        };
+
+        /* Commands still go out, but nobody will ever answer. */
+        drop(codi_tx);
+        assert!(!driver.poll(0x1));
+        assert_eq!(driver.block(), 0);
+        assert_eq!(driver.try_block(), Some(0));
+
+        drop(cido_rx);
+        assert!(!driver.poll(0x1));
+        assert_eq!(driver.block(), 0);
+        assert_eq!(driver.try_block(), Some(0));
+    }
+
+    #[test]
+    fn output_after_ui_exits() {
+        let (cido_tx, cido_rx) = channel::<io::Command>();
+        let sound = SoundDriver { cido_tx: cido_tx.clone() };
+        let mut display = DisplayDriver { cido_tx };
+
+        drop(cido_rx);
+        sound.start_buzz();
+        sound.stop_buzz();
+        display.refresh(&[false; 64 * 32]);
     }
 }
@@ -0,0 +1,100 @@
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::WindowCanvas;
+
+use crate::core::cpu::Cpu;
+use super::io::CpuState;
+
+type Result<T> = std::result::Result<T, String>;
+
+const PIXEL: u32 = 2;
+const MARGIN: u32 = 4;
+const GLYPH_WIDTH: u32 = 4;
+const GLYPH_HEIGHT: u32 = 5;
+
+/* Letters the HUD needs on top of the hex digits in the CHIP-8 font. */
+const GLYPH_P: [u8; 5] = [0xe0, 0x90, 0xe0, 0x80, 0x80];
+const GLYPH_I: [u8; 5] = [0xe0, 0x40, 0x40, 0x40, 0xe0];
+const GLYPH_V: [u8; 5] = [0x90, 0x90, 0x90, 0x60, 0x60];
+const GLYPH_K: [u8; 5] = [0x90, 0xa0, 0xc0, 0xa0, 0x90];
+const GLYPH_SPACE: [u8; 5] = [0x00; 5];
+
+fn glyph(c: char) -> Option<&'static [u8]> {
+    match c {
+        'P' => Some(&GLYPH_P),
+        'I' => Some(&GLYPH_I),
+        'V' => Some(&GLYPH_V),
+        'K' => Some(&GLYPH_K),
+        ' ' => Some(&GLYPH_SPACE),
+        _ => c.to_digit(16).map(|d| Cpu::font_glyph(d as u8)),
+    }
+}
+
+/* keys is the held key mask from io::key_mask. */
+pub fn lines(state: &CpuState, keys: u16) -> Vec<String> {
+    let regs = state.v
+        .iter()
+        .map(|v| format!("{:02X}", v))
+        .collect::<Vec<String>>();
+
+    vec![
+        format!("PC {:04X} I {:04X}", state.pc, state.i),
+        format!("V {}", regs.join(" ")),
+        format!("K {:04X}", keys),
+    ]
+}
+
+/* Draws the registers and held keys in the top left corner of the window, over whatever
+   is already on the canvas. */
+pub fn draw(canvas: &mut WindowCanvas, state: &CpuState, keys: u16, fg: Color, bg: Color)
+        -> Result<()> {
+    let lines = lines(state, keys);
+    let cols = lines.iter().map(|l| l.len()).max().unwrap_or(0) as u32;
+    let advance_x = (GLYPH_WIDTH + 1) * PIXEL;
+    let advance_y = (GLYPH_HEIGHT + 2) * PIXEL;
+
+    canvas.set_draw_color(bg);
+    canvas.fill_rect(Rect::new(0, 0,
+                               cols * advance_x + MARGIN * 2,
+                               lines.len() as u32 * advance_y + MARGIN * 2))?;
+
+    canvas.set_draw_color(fg);
+    for (row, line) in lines.iter().enumerate() {
+        for (col, c) in line.chars().enumerate() {
+            let x0 = MARGIN + col as u32 * advance_x;
+            let y0 = MARGIN + row as u32 * advance_y;
+            for (y, bits) in glyph(c).unwrap_or(&GLYPH_SPACE).iter().enumerate() {
+                for x in 0..GLYPH_WIDTH {
+                    if bits & (0x80 >> x) != 0 {
+                        canvas.fill_rect(Rect::new((x0 + x * PIXEL) as i32,
+                                                   (y0 + y as u32 * PIXEL) as i32,
+                                                   PIXEL, PIXEL))?;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hud_text() {
+        let state = CpuState {
+            pc: 0x200,
+            i: 0xabc,
+            v: (0..0x10).collect(),
+        };
+
+        let lines = lines(&state, 0x8012);
+        assert_eq!(lines[0], "PC 0200 I 0ABC");
+        assert!(lines[1].starts_with("V 00 01 02"));
+        assert!(lines[1].ends_with("0E 0F"));
+        assert_eq!(lines[2], "K 8012");
+        assert!(lines.iter().all(|l| l.chars().all(|c| glyph(c).is_some())));
+    }
+}
@@ -1,6 +1,12 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 
 use sdl2::audio::{AudioCallback, AudioDevice};
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
 
 pub struct SquareWave {
     pub phase_inc: f32,
@@ -21,14 +27,396 @@ impl AudioCallback for SquareWave {
 
 pub type Buzzer = AudioDevice<SquareWave>;
 
+pub trait Buzz {
+    fn resume(&self);
+    fn pause(&self);
+    fn available(&self) -> bool {
+        true
+    }
+}
+
+impl Buzz for Buzzer {
+    fn resume(&self) {
+        AudioDevice::resume(self);
+    }
+
+    fn pause(&self) {
+        AudioDevice::pause(self);
+    }
+}
+
+/* Stands in when there is no audio device, so buzz commands do nothing. */
+pub struct NullBuzzer;
+
+impl Buzz for NullBuzzer {
+    fn resume(&self) {}
+
+    fn pause(&self) {}
+
+    fn available(&self) -> bool {
+        false
+    }
+}
+
+/* Takes the result of opening an audio device and falls back on a
+   NullBuzzer, with a warning, if that failed. */
+pub fn buzzer_or_null<B: Buzz + 'static>(opened: Result<B, String>) -> Box<dyn Buzz> {
+    match opened {
+        Ok(buzzer) => Box::new(buzzer),
+        Err(e) => {
+            eprintln!("warning: audio unavailable, running without sound: {}", e);
+            Box::new(NullBuzzer)
+        },
+    }
+}
+
 pub type Key = Option<u8>;
 
+#[derive(Debug, Clone, PartialEq)]
+pub struct CpuState {
+    pub pc: u16,
+    pub i: u16,
+    pub v: Vec<u8>,
+}
+
+/* Display colors by plane bits: bit 0 is the first plane and bit 1 the
+   second, so index 0 is the background and 3 is where both planes are lit.
+   Single plane frames only ever use 0 and 1. */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette(pub [Color; 4]);
+
+impl Palette {
+    /* bg and fg for the classic colors, plus the XO-CHIP Octo defaults for
+       the second plane and the blend of both. */
+    pub fn new(bg: Color, fg: Color) -> Self {
+        Palette([bg, fg, Color::RGB(0xff, 0x66, 0x00), Color::RGB(0x66, 0x22, 0x00)])
+    }
+
+    pub fn color(&self, planes: u8) -> Color {
+        self.0[(planes & 0x3) as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PixelShape {
+    Square,
+    /* Round dots, for a phosphor look. Ellipses if the scale isn't
+       integral and pixels come out a little wider than they are tall. */
+    Circle,
+}
+
+/* The rects that fill one pixel of a given shape and size, relative to its
+   top left corner. A circle takes a rect per row, and there are thousands
+   of pixels to draw per frame, so each size is only worked out once. */
+#[derive(Default)]
+pub struct PixelMasks {
+    masks: HashMap<(PixelShape, u32, u32), Vec<Rect>>,
+}
+
+impl PixelMasks {
+    /* Sizes only change with the window, so a handful is plenty. */
+    const MAX_CACHED: usize = 16;
+
+    pub fn get(&mut self, shape: PixelShape, w: u32, h: u32) -> &[Rect] {
+        if self.masks.len() >= Self::MAX_CACHED && !self.masks.contains_key(&(shape, w, h)) {
+            self.masks.clear();
+        }
+        self.masks.entry((shape, w, h)).or_insert_with(|| match shape {
+            PixelShape::Square => vec![Rect::new(0, 0, w, h)],
+            PixelShape::Circle => circle_mask(w, h),
+        })
+    }
+}
+
+fn circle_mask(w: u32, h: u32) -> Vec<Rect> {
+    (0..h)
+        .filter_map(|row| {
+            /* Each row is as wide as the ellipse is at its middle. */
+            let dy = (row as f32 + 0.5) / h as f32 * 2.0 - 1.0;
+            let half = (1.0 - dy * dy).sqrt() * w as f32 / 2.0;
+            let x0 = (w as f32 / 2.0 - half).round() as i32;
+            let x1 = (w as f32 / 2.0 + half).round() as i32;
+            if x1 > x0 {
+                Some(Rect::new(x0, row as i32, (x1 - x0) as u32, 1))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/* One byte of plane bits per pixel, as DisplayRefresh carries them. */
+pub fn plane_bits(vram: &[bool]) -> Vec<u8> {
+    vram.iter().map(|&px| px as u8).collect()
+}
+
 pub enum Command {
     BuzzStart,
     BuzzStop,
-    DisplayRefresh(Vec<bool>),
+    DisplayRefresh(Vec<u8>),
+    DumpScreen,
+    HudUpdate(CpuState),
     KeyBlock,
+    KeyCancel,
     KeyChanSet(Option<Sender<Key>>),
     KeyPoll(u8),
     Quit,
+    Reset,
+    SetCrtEffect(bool),
+    SetFps(u32),
+    SetPalette(Palette),
+    SetPixelShape(PixelShape),
+    SetTurbo(bool),
+    ToggleHud,
+    ToggleGrid,
+    ToggleFullscreen,
+}
+
+/* Answer to a KeyPoll for key given the full set of keys currently held.
+   Any number of other keys may be down at the same time. */
+pub fn key_poll_reply(pressed_keys: &HashSet<u8>, key: u8) -> Key {
+    if pressed_keys.contains(&key) {
+        Some(key)
+    } else {
+        None
+    }
+}
+
+/* The keys held as a bitmask, bit n set while key n is down. */
+pub fn key_mask(pressed_keys: &HashSet<u8>) -> u16 {
+    pressed_keys.iter().fold(0, |mask, &k| mask | 1 << (k & 0xf))
+}
+
+/* UI side of a KeyBlock. Only a fresh key-down satisfies a block: keys that
+   were already held when the block began have to be released and pressed
+   again before they count. */
+#[derive(Default)]
+pub struct KeyWait {
+    waiting: bool,
+    held: HashSet<u8>,
+}
+
+impl KeyWait {
+    pub fn begin(&mut self, pressed_keys: &HashSet<u8>) {
+        self.waiting = true;
+        self.held = pressed_keys.clone();
+    }
+
+    /* Called once per frame with the keys currently down. Returns the key
+       that ends the block, if any. */
+    pub fn update(&mut self, pressed_keys: &HashSet<u8>) -> Key {
+        if !self.waiting {
+            return None;
+        }
+
+        self.held.retain(|k| pressed_keys.contains(k));
+        let key = pressed_keys.difference(&self.held).next().cloned();
+        if key.is_some() {
+            self.waiting = false;
+        }
+        key
+    }
+
+    /* Returns whether there was a block to cancel. */
+    pub fn cancel(&mut self) -> bool {
+        std::mem::take(&mut self.waiting)
+    }
+}
+
+/* Presented frames per second over a sliding window. Timestamps are passed
+   in so the math can be tested without waiting on a clock. */
+#[derive(Debug, Default)]
+pub struct FpsCounter {
+    frames: VecDeque<Instant>,
+}
+
+impl FpsCounter {
+    const WINDOW: Duration = Duration::from_secs(1);
+
+    pub fn record(&mut self, now: Instant) {
+        self.frames.push_back(now);
+        while let Some(&first) = self.frames.front() {
+            if now.duration_since(first) <= Self::WINDOW {
+                break;
+            }
+            self.frames.pop_front();
+        }
+    }
+
+    pub fn fps(&self) -> f32 {
+        match (self.frames.front(), self.frames.back()) {
+            (Some(first), Some(last)) if last > first => {
+                (self.frames.len() - 1) as f32 / last.duration_since(*first).as_secs_f32()
+            },
+            _ => 0.0,
+        }
+    }
+}
+
+/* Press and release counts for each CHIP-8 key, for spotting phantom or
+   stuck keys. Counted from key events rather than the keys held each
+   frame, so a tap shorter than a frame still shows up. */
+#[derive(Debug, Default)]
+pub struct KeyStats {
+    counts: [(u32, u32); 16],
+}
+
+impl KeyStats {
+    /* keypad gives the CHIP-8 keys a scancode and keycode map to. Key
+       repeats and other events are ignored. */
+    pub fn record<F>(&mut self, event: &Event, keypad: F)
+            where F: Fn(Scancode, Option<Keycode>) -> HashSet<u8> {
+        match *event {
+            Event::KeyDown { scancode: Some(sc), keycode, repeat: false, .. } => {
+                for k in keypad(sc, keycode) {
+                    self.counts[k as usize & 0xf].0 += 1;
+                }
+            },
+            Event::KeyUp { scancode: Some(sc), keycode, .. } => {
+                for k in keypad(sc, keycode) {
+                    self.counts[k as usize & 0xf].1 += 1;
+                }
+            },
+            _ => (),
+        }
+    }
+
+    /* (presses, releases) indexed by key. */
+    pub fn counts(&self) -> [(u32, u32); 16] {
+        self.counts
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    struct FakeBuzzer {
+        on: Rc<Cell<bool>>,
+    }
+
+    impl Buzz for FakeBuzzer {
+        fn resume(&self) {
+            self.on.set(true);
+        }
+
+        fn pause(&self) {
+            self.on.set(false);
+        }
+    }
+
+    #[test]
+    fn buzzer_fallback() {
+        let buzzer = buzzer_or_null::<NullBuzzer>(Err("no audio device".to_owned()));
+        assert!(!buzzer.available());
+        buzzer.resume();
+        buzzer.pause();
+
+        let on = Rc::new(Cell::new(false));
+        let buzzer = buzzer_or_null(Ok(FakeBuzzer { on: Rc::clone(&on) }));
+        assert!(buzzer.available());
+        buzzer.resume();
+        assert!(on.get());
+        buzzer.pause();
+        assert!(!on.get());
+    }
+
+    #[test]
+    fn key_stats() {
+        let mut stats = KeyStats::default();
+        let keypad = |sc, _| match sc {
+            Scancode::Num1 => [0x1].iter().cloned().collect(),
+            Scancode::V => [0xf].iter().cloned().collect(),
+            _ => HashSet::new(),
+        };
+        let down = |scancode, repeat| Event::KeyDown {
+            timestamp: 0, window_id: 0, keycode: None, scancode: Some(scancode),
+            keymod: sdl2::keyboard::Mod::empty(), repeat,
+        };
+        let up = |scancode| Event::KeyUp {
+            timestamp: 0, window_id: 0, keycode: None, scancode: Some(scancode),
+            keymod: sdl2::keyboard::Mod::empty(), repeat: false,
+        };
+
+        /* One frame's worth of events. 1 is tapped and let go again before
+           the frame ends, which sampling the held keys would miss. */
+        for event in &[down(Scancode::Num1, false), down(Scancode::Num1, true),
+                       up(Scancode::Num1), down(Scancode::V, false), down(Scancode::Z, false)] {
+            stats.record(event, keypad);
+        }
+        let counts = stats.counts();
+        assert_eq!(counts[0x1], (1, 1));
+        assert_eq!(counts[0xf], (1, 0));
+        assert_eq!(counts[0x0], (0, 0));
+
+        stats.record(&up(Scancode::V), keypad);
+        stats.record(&Event::Quit { timestamp: 0 }, keypad);
+        assert_eq!(stats.counts()[0xf], (1, 1));
+    }
+
+    #[test]
+    fn mask() {
+        let keys = |ks: &[u8]| ks.iter().cloned().collect::<HashSet<u8>>();
+
+        assert_eq!(key_mask(&keys(&[])), 0);
+        assert_eq!(key_mask(&keys(&[0x0])), 0x0001);
+        assert_eq!(key_mask(&keys(&[0x1, 0x4, 0xf])), 0x8012);
+    }
+
+    #[test]
+    fn fps_counter() {
+        let start = Instant::now();
+        let mut counter = FpsCounter::default();
+        assert_eq!(counter.fps(), 0.0);
+
+        for n in 0..=30 {
+            counter.record(start + Duration::from_millis(n * 20));
+        }
+        assert!((counter.fps() - 50.0).abs() < 0.01);
+
+        /* Only the last second counts. */
+        for n in 0..=100 {
+            counter.record(start + Duration::from_millis(2000 + n * 10));
+        }
+        assert!((counter.fps() - 100.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn palette() {
+        let (bg, fg) = (Color::RGB(0, 0, 0), Color::RGB(0xff, 0xb0, 0x00));
+        let palette = Palette::new(bg, fg);
+
+        assert_eq!(plane_bits(&[false, true, true]), [0, 1, 1]);
+        assert_eq!(palette.color(0), bg);
+        assert_eq!(palette.color(1), fg);
+        assert_eq!(palette.color(0b10), palette.0[2]);
+        assert_eq!(palette.color(0b11), palette.0[3]);
+        assert_eq!(palette.color(0b111), palette.0[3]);
+    }
+
+    #[test]
+    fn pixel_masks() {
+        let mut masks = PixelMasks::default();
+        assert_eq!(masks.get(PixelShape::Square, 5, 4), [Rect::new(0, 0, 5, 4)]);
+
+        /* A circle eight pixels across: narrow at the top and bottom, full
+           width through the middle, and symmetric. */
+        let circle = masks.get(PixelShape::Circle, 8, 8).to_vec();
+        assert_eq!(circle.len(), 8);
+        assert_eq!(circle[0], Rect::new(2, 0, 4, 1));
+        assert_eq!((circle[3].x(), circle[3].width()), (0, 8));
+        for (top, bottom) in circle.iter().zip(circle.iter().rev()) {
+            assert_eq!((top.x(), top.width()), (bottom.x(), bottom.width()));
+        }
+        assert!(circle.iter().all(|r| r.right() <= 8));
+
+        assert_eq!(masks.get(PixelShape::Circle, 1, 1), [Rect::new(0, 0, 1, 1)]);
+        for size in 1..=PixelMasks::MAX_CACHED as u32 + 1 {
+            masks.get(PixelShape::Circle, size, size);
+        }
+        assert!(masks.masks.len() <= PixelMasks::MAX_CACHED);
+    }
 }
@@ -1,3 +1,4 @@
 pub mod io;
 pub mod driver;
 pub mod controller;
+pub mod hud;
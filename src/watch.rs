@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+/* Polls a ROM file's modification time so --watch can reload it after an
+   edit. Polling keeps this free of platform specific notification APIs;
+   a stat every POLL_INTERVAL is nothing next to running a frame. */
+pub struct RomWatcher {
+    path: PathBuf,
+    mtime: Option<SystemTime>,
+    last_poll: Instant,
+}
+
+impl RomWatcher {
+    pub const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+    pub fn new<P: Into<PathBuf>>(path: P, now: Instant) -> Self {
+        let path = path.into();
+        let mtime = mtime(&path);
+        RomWatcher { path, mtime, last_poll: now }
+    }
+
+    /* Whether the file changed since the last call that returned true. */
+    pub fn changed(&mut self, now: Instant) -> bool {
+        if now.saturating_duration_since(self.last_poll) < Self::POLL_INTERVAL {
+            return false;
+        }
+        self.last_poll = now;
+
+        let current = mtime(&self.path);
+        let reload = should_reload(self.mtime, current);
+        if reload {
+            self.mtime = current;
+        }
+        reload
+    }
+}
+
+fn mtime(path: &PathBuf) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/* A file that is missing, e.g. while an editor swaps in the new copy, is
+   not a change; the reload happens once it's back with a new time. */
+pub fn should_reload(last: Option<SystemTime>, current: Option<SystemTime>) -> bool {
+    current.is_some() && current != last
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mtime_changes() {
+        let t0 = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000);
+        let t1 = t0 + Duration::from_secs(1);
+
+        assert!(!should_reload(Some(t0), Some(t0)));
+        assert!(should_reload(Some(t0), Some(t1)));
+        assert!(!should_reload(Some(t0), None));
+        assert!(should_reload(None, Some(t0)));
+        assert!(!should_reload(None, None));
+    }
+
+    #[test]
+    fn poll_interval() {
+        let path = std::env::temp_dir().join(format!("chip8-watch-{}.ch8", std::process::id()));
+        fs::write(&path, [0x12, 0x00]).unwrap();
+
+        let start = Instant::now();
+        let mut watcher = RomWatcher::new(&path, start);
+        assert!(!watcher.changed(start + RomWatcher::POLL_INTERVAL));
+
+        let file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(10)).unwrap();
+        assert!(!watcher.changed(start + RomWatcher::POLL_INTERVAL));
+        assert!(watcher.changed(start + RomWatcher::POLL_INTERVAL * 2));
+        assert!(!watcher.changed(start + RomWatcher::POLL_INTERVAL * 3));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
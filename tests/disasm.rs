@@ -0,0 +1,26 @@
+use std::env;
+use std::fs;
+use std::process::Command;
+
+#[test]
+fn disasm_flag() {
+    let dir = env::temp_dir();
+    let rom = dir.join(format!("chip8-disasm-{}.ch8", std::process::id()));
+    let out = dir.join(format!("chip8-disasm-{}.txt", std::process::id()));
+
+    /* cls; ld v0, 0x05; jp 0x202 */
+    fs::write(&rom, [0x00, 0xe0, 0x60, 0x05, 0x12, 0x02]).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_main"))
+        .arg("--disasm")
+        .arg(&out)
+        .arg(&rom)
+        .status()
+        .unwrap();
+    let listing = fs::read_to_string(&out);
+    let _ = fs::remove_file(&rom);
+    let _ = fs::remove_file(&out);
+
+    assert!(status.success());
+    assert_eq!(listing.unwrap(), "0200  00e0  CLS\n0202  6005  LD V0, 0x05\n0204  1202  JP 0x202\n");
+}
@@ -0,0 +1,18 @@
+use chip8::core::cpu::Cpu;
+use chip8::core::op::Variant;
+use chip8::core::timer::Timer;
+
+/* Kept in its own test binary so no other test's CPUs are counted. */
+#[test]
+fn timer_threads_joined_on_drop() {
+    let baseline = Timer::live_threads();
+
+    let cpus: Vec<Cpu> = (0..100).map(|_| Cpu::new(Variant::Schip)).collect();
+    assert_eq!(Timer::live_threads(), baseline + 100);
+
+    drop(cpus);
+    assert_eq!(Timer::live_threads(), baseline);
+
+    let _manual = Cpu::new_without_timer_thread();
+    assert_eq!(Timer::live_threads(), baseline);
+}